@@ -0,0 +1,159 @@
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, LineWriter, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Local};
+use once_cell::sync::OnceCell;
+use hyper::{Body, Request, StatusCode, Version};
+use hyper::header::{REFERER, USER_AGENT};
+
+/// The stdout of the process, once `reserve_stdout` has kept it for the access log
+static RESERVED_STDOUT: OnceCell<File> = OnceCell::new();
+
+/// Keeps stdout for the access log alone, so `--access-log -` can be piped to a log processor. Everything else the
+/// process prints to stdout goes to stderr from then on.
+#[cfg(unix)]
+pub fn reserve_stdout() -> io::Result<()> {
+    use std::os::unix::io::FromRawFd;
+
+    let to_io = |e: nix::Error| io::Error::other(e.to_string());
+    io::stdout().flush()?;
+    let stdout = nix::unistd::dup(1).map_err(to_io)?;
+    nix::unistd::dup2(2, 1).map_err(to_io)?;
+    // The duplicate is owned by nothing else
+    let _ = RESERVED_STDOUT.set(unsafe { File::from_raw_fd(stdout) });
+    Ok(())
+}
+
+pub struct AccessLog {
+    output: Mutex<Box<dyn Write + Send>>,
+}
+
+impl AccessLog {
+    pub fn open(path: &Path) -> Result<AccessLog, io::Error> {
+        let output: Box<dyn Write + Send> = if path == Path::new("-") {
+            match RESERVED_STDOUT.get() {
+                Some(stdout) => Box::new(LineWriter::new(stdout.try_clone()?)),
+                None => Box::new(io::stdout()),
+            }
+        } else {
+            Box::new(OpenOptions::new().create(true).append(true).open(path)?)
+        };
+        Ok(AccessLog {
+            output: Mutex::new(output),
+        })
+    }
+
+    pub fn write(&self, entry: &AccessLogEntry) {
+        let mut output = self.output.lock().unwrap();
+        if let Err(e) = writeln!(output, "{}", entry).and_then(|_| output.flush()) {
+            println!("Failed to write access log entry: {}", e);
+        }
+    }
+}
+
+pub struct AccessLogEntry {
+    remote_addr: Option<SocketAddr>,
+    timestamp: DateTime<Local>,
+    method: String,
+    path: String,
+    version: Version,
+    status: Option<StatusCode>,
+    bytes: Option<u64>,
+    referer: Option<String>,
+    user_agent: Option<String>,
+}
+
+impl AccessLogEntry {
+    pub fn from_request(req: &Request<Body>, remote_addr: Option<SocketAddr>) -> AccessLogEntry {
+        let header = |name| req.headers().get(name)
+            .and_then(|v: &hyper::header::HeaderValue| v.to_str().ok())
+            .map(|v| v.to_owned());
+        AccessLogEntry {
+            remote_addr,
+            timestamp: Local::now(),
+            method: req.method().to_string(),
+            path: req.uri().path_and_query().map(|v| v.as_str()).unwrap_or("/").to_owned(),
+            version: req.version(),
+            status: None,
+            bytes: None,
+            referer: header(REFERER),
+            user_agent: header(USER_AGENT),
+        }
+    }
+
+    /// Records the status of the response and the number of body bytes sent to the client
+    pub fn complete(mut self, status: StatusCode, bytes: u64) -> AccessLogEntry {
+        self.status = Some(status);
+        self.bytes = Some(bytes);
+        self
+    }
+}
+
+fn quoted(value: &Option<String>) -> String {
+    match value {
+        Some(value) => value.replace('\\', "\\\\").replace('"', "\\\""),
+        None => "-".to_owned(),
+    }
+}
+
+impl fmt::Display for AccessLogEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.remote_addr {
+            Some(addr) => write!(f, "{}", addr.ip())?,
+            None => write!(f, "-")?,
+        }
+        write!(f, " - - [{}] \"{} {} {:?}\" ",
+               self.timestamp.format("%d/%b/%Y:%H:%M:%S %z"), self.method, self.path, self.version)?;
+        match self.status {
+            Some(status) => write!(f, "{} ", status.as_u16())?,
+            None => write!(f, "- ")?,
+        }
+        match self.bytes {
+            Some(bytes) => write!(f, "{} ", bytes)?,
+            None => write!(f, "- ")?,
+        }
+        write!(f, "\"{}\" \"{}\"", quoted(&self.referer), quoted(&self.user_agent))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn formats_combined_log_format() {
+        let req = Request::builder()
+            .method("GET")
+            .uri("/api/ping?verbose=true")
+            .header(REFERER, "https://speil.nais.preprod.local/")
+            .header(USER_AGENT, "curl/7.68.0 \"quoted\"")
+            .body(Body::empty())
+            .unwrap();
+        let mut entry = AccessLogEntry::from_request(&req, Some("127.0.0.1:51234".parse().unwrap()))
+            .complete(StatusCode::OK, 42);
+        entry.timestamp = Local.timestamp_opt(0, 0).unwrap();
+        let timestamp = entry.timestamp.format("%d/%b/%Y:%H:%M:%S %z").to_string();
+
+        assert_eq!(entry.to_string(), format!(
+            "127.0.0.1 - - [{}] \"GET /api/ping?verbose=true HTTP/1.1\" 200 42 \"https://speil.nais.preprod.local/\" \"curl/7.68.0 \\\"quoted\\\"\"",
+            timestamp));
+    }
+
+    #[test]
+    fn formats_missing_fields_as_dash() {
+        let req = Request::builder()
+            .uri("/")
+            .body(Body::empty())
+            .unwrap();
+        let entry = AccessLogEntry::from_request(&req, None);
+
+        assert!(entry.to_string().starts_with("- - - ["));
+        assert!(entry.to_string().ends_with("\"GET / HTTP/1.1\" - - \"-\" \"-\""));
+    }
+}
@@ -0,0 +1,171 @@
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use hyper::{Body, Method, Request, Response, StatusCode};
+use futures_util::stream::StreamExt;
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE};
+use tokio::sync::Mutex;
+
+use crate::forwarding::State;
+use crate::metrics::Metrics;
+use crate::responses::error_response;
+
+/// Requests with paths below this prefix are handled by the proxy itself when the admin endpoints are enabled
+pub const PATH_PREFIX: &str = "/_autoforward";
+
+/// Answers whether the proxy is ready, also when the other admin endpoints are disabled
+pub const READY_PATH: &str = "/_autoforward/ready";
+
+/// Serves the metrics in the Prometheus text format
+pub const METRICS_PATH: &str = "/_autoforward/metrics";
+
+pub fn is_admin_request(req: &Request<Body>) -> bool {
+    let path = req.uri().path();
+    path == PATH_PREFIX || path.starts_with(&format!("{}/", PATH_PREFIX))
+}
+
+pub async fn handle_admin(req: Request<Body>, state: Arc<Mutex<State>>, metrics: Arc<Metrics>) -> Response<Body> {
+    let path = &req.uri().path()[PATH_PREFIX.len()..];
+    match (req.method(), path) {
+        (&Method::GET, "/metrics") => Response::builder()
+            .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(Body::from(metrics.render()))
+            .unwrap(),
+        (&Method::GET, "/forwards") => {
+            let state = state.lock().await;
+            let forwards = state.port_forwards().collect::<Vec<_>>();
+            json_response(serde_json::to_string(&forwards).unwrap())
+        }
+        (&Method::GET, "/events") => {
+            // Subscribers falling behind miss the oldest events rather than holding up the proxy
+            let events = state.lock().await.subscribe()
+                .filter_map(|event| async move { event.ok().map(|event| Ok::<_, Infallible>(event.to_sse())) });
+            Response::builder()
+                .header(CONTENT_TYPE, "text/event-stream")
+                .header(CACHE_CONTROL, "no-cache")
+                .body(Body::wrap_stream(events))
+                .unwrap()
+        }
+        (&Method::POST, "/reload") => {
+            state.lock().await.request_reload();
+            Response::builder()
+                .status(StatusCode::ACCEPTED)
+                .body(Body::from("Reloading applications"))
+                .unwrap()
+        }
+        (&Method::DELETE, path) if path.starts_with("/forwards/") => {
+            let application = &path["/forwards/".len()..];
+            if state.lock().await.close_port_forwards(application).await > 0 {
+                Response::builder()
+                    .status(StatusCode::NO_CONTENT)
+                    .body(Body::empty())
+                    .unwrap()
+            } else {
+                error_response(StatusCode::NOT_FOUND, format!("No port-forward found for {}", application))
+            }
+        }
+        _ => error_response(StatusCode::NOT_FOUND, format!("Unknown admin endpoint {} {}", req.method(), req.uri().path())),
+    }
+}
+
+/// 200 once the applications are discovered and the hosts file is updated, 503 before that
+pub async fn ready_response(state: &Mutex<State>) -> Response<Body> {
+    if state.lock().await.is_ready() {
+        Response::new(Body::from("Ready"))
+    } else {
+        error_response(StatusCode::SERVICE_UNAVAILABLE, "Still discovering applications")
+    }
+}
+
+fn json_response(body: String) -> Response<Body> {
+    Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use structopt::StructOpt;
+
+    use crate::config::Config;
+
+    use super::*;
+
+    async fn empty_state() -> Arc<Mutex<State>> {
+        let mut config = Config::from_iter(&["autoforward"]);
+        config.contexts.clear();
+        Arc::new(Mutex::new(State::new(Arc::new(config)).await.unwrap()))
+    }
+
+    fn request(method: Method, path: &str) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(path)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn matches_only_reserved_prefix() {
+        assert!(is_admin_request(&request(Method::GET, "/_autoforward/forwards")));
+        assert!(is_admin_request(&request(Method::GET, "/_autoforward")));
+        assert!(!is_admin_request(&request(Method::GET, "/_autoforwarding")));
+        assert!(!is_admin_request(&request(Method::GET, "/api/_autoforward")));
+    }
+
+    #[tokio::test]
+    async fn lists_forwards_as_json() {
+        let response = handle_admin(request(Method::GET, "/_autoforward/forwards"), empty_state().await, Arc::default()).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"[]");
+    }
+
+    #[tokio::test]
+    async fn serves_metrics() {
+        let response = handle_admin(request(Method::GET, "/_autoforward/metrics"), empty_state().await, Arc::default()).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(String::from_utf8(body.to_vec()).unwrap().contains("autoforward_connections_active 0"));
+    }
+
+    #[tokio::test]
+    async fn streams_events() {
+        let response = handle_admin(request(Method::GET, "/_autoforward/events"), empty_state().await, Arc::default()).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "text/event-stream");
+    }
+
+    #[tokio::test]
+    async fn ready_once_discovery_is_done() {
+        let state = empty_state().await;
+
+        assert_eq!(ready_response(&state).await.status(), StatusCode::SERVICE_UNAVAILABLE);
+        state.lock().await.set_ready();
+        assert_eq!(ready_response(&state).await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn delete_unknown_forward_is_not_found() {
+        let response = handle_admin(request(Method::DELETE, "/_autoforward/forwards/speil"), empty_state().await, Arc::default()).await;
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn requests_reload() {
+        let state = empty_state().await;
+        let reload_requests = state.lock().await.reload_requests();
+
+        let response = handle_admin(request(Method::POST, "/_autoforward/reload"), state, Arc::default()).await;
+
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        tokio::time::timeout(std::time::Duration::from_secs(1), reload_requests.notified()).await
+            .expect("a reload should be requested");
+    }
+}
@@ -0,0 +1,98 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Writes to a temporary file next to the target and renames it into place, so a crash never leaves a
+/// truncated file behind and readers never see it half-written. The temporary file gets the permissions and owner
+/// of an existing target. A target that is hard linked, or can't be replaced, like a hosts file bind mounted into a
+/// container, is written in place instead.
+pub fn write(path: &Path, contents: &[u8]) -> Result<(), io::Error> {
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => Some(metadata),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+    if metadata.as_ref().is_some_and(has_hard_links) {
+        return write_in_place(path, contents);
+    }
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".autoforward.tmp");
+    let temp_path = Path::new(&temp_path);
+
+    let result = (|| {
+        let mut temp = File::create(temp_path)?;
+        if let Some(metadata) = &metadata {
+            temp.set_permissions(metadata.permissions())?;
+            copy_owner(&temp, metadata)?;
+        }
+        temp.write_all(contents)?;
+        temp.sync_all()?;
+        fs::rename(temp_path, path)
+    })();
+    if let Err(e) = result {
+        let _ = fs::remove_file(temp_path);
+        if metadata.is_none() {
+            return Err(e);
+        }
+        println!("Could not replace {} ({}), writing it in place", path.display(), e);
+        return write_in_place(path, contents);
+    }
+    Ok(())
+}
+
+fn write_in_place(path: &Path, contents: &[u8]) -> Result<(), io::Error> {
+    let mut file = fs::OpenOptions::new().write(true).truncate(true).open(path)?;
+    file.write_all(contents)?;
+    file.sync_all()
+}
+
+#[cfg(unix)]
+fn has_hard_links(metadata: &fs::Metadata) -> bool {
+    std::os::unix::fs::MetadataExt::nlink(metadata) > 1
+}
+
+#[cfg(not(unix))]
+fn has_hard_links(_metadata: &fs::Metadata) -> bool {
+    false
+}
+
+/// Gives the file the owner and group of the target. Failing to do so, the rename is skipped, so it doesn't hand
+/// the target over to whoever runs autoforward.
+#[cfg(unix)]
+fn copy_owner(file: &File, metadata: &fs::Metadata) -> Result<(), io::Error> {
+    use std::os::unix::fs::MetadataExt;
+    if file.metadata()?.uid() == metadata.uid() && file.metadata()?.gid() == metadata.gid() {
+        return Ok(());
+    }
+    std::os::unix::fs::fchown(file, Some(metadata.uid()), Some(metadata.gid()))
+}
+
+#[cfg(not(unix))]
+fn copy_owner(_file: &File, _metadata: &fs::Metadata) -> Result<(), io::Error> {
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_mode_and_hard_links_of_the_target() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let dir = tempfile::tempdir().unwrap();
+        let (path, link) = (dir.path().join("hosts"), dir.path().join("hosts.link"));
+        write(&path, b"127.0.0.1 localhost\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+
+        write(&path, b"127.0.0.1 speil.nais.preprod.local\n").unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o640);
+
+        fs::hard_link(&path, &link).unwrap();
+        let inode = fs::metadata(&path).unwrap().ino();
+        write(&path, b"127.0.0.1 spleis.nais.preprod.local\n").unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().ino(), inode);
+        assert_eq!(fs::read_to_string(&link).unwrap(), "127.0.0.1 spleis.nais.preprod.local\n");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+}
@@ -0,0 +1,58 @@
+use std::error::Error;
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use futures_util::stream::StreamExt;
+use hyper::{Body, Request, Response, StatusCode};
+use hyper::body::HttpBody;
+use hyper::header::CONTENT_LENGTH;
+
+use crate::responses::error_response;
+
+/// Enforces --max-body-size on a request body sent upstream
+pub struct BodyLimit {
+    limit: u64,
+    exceeded: Arc<AtomicBool>,
+}
+
+impl BodyLimit {
+    /// Applies the limit to the request, a request declaring a longer body has exceeded it right away. A body without
+    /// a declared length is cut off with an error once it grows past the limit, which fails the upstream request.
+    pub fn apply(req: &mut Request<Body>, limit: u64) -> BodyLimit {
+        let exceeded = Arc::new(AtomicBool::new(false));
+        let declared = req.headers().get(CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok())
+            .and_then(|length| length.parse::<u64>().ok());
+        match declared {
+            Some(length) if length > limit => exceeded.store(true, Ordering::SeqCst),
+            // hyper already holds the client to the length it declared
+            Some(_) => {}
+            None if req.body().is_end_stream() => {}
+            None => {
+                let flag = exceeded.clone();
+                let mut received = 0;
+                let body = std::mem::take(req.body_mut()).map(move |chunk| {
+                    let chunk = chunk?;
+                    received += chunk.len() as u64;
+                    if received > limit {
+                        flag.store(true, Ordering::SeqCst);
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, "Request body exceeds --max-body-size").into());
+                    }
+                    Ok::<_, Box<dyn Error + Send + Sync>>(chunk)
+                });
+                *req.body_mut() = Body::wrap_stream(body);
+            }
+        }
+        BodyLimit { limit, exceeded }
+    }
+
+    /// Whether the body is declared or turned out to be longer than the limit
+    pub fn exceeded(&self) -> bool {
+        self.exceeded.load(Ordering::SeqCst)
+    }
+
+    pub fn too_large_response(&self) -> Response<Body> {
+        error_response(StatusCode::PAYLOAD_TOO_LARGE, format!("The request body is larger than the limit of {} bytes.", self.limit))
+    }
+}
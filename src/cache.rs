@@ -0,0 +1,67 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+
+/// Discovered applications as written to disk. The key identifies the discovery settings that produced them, so a
+/// cache written with other contexts or namespaces is never used.
+#[derive(Serialize, Deserialize)]
+struct CacheFile<A> {
+    key: String,
+    written_at: u64,
+    applications: A,
+}
+
+/// Reads cached applications, or `None` if the cache is missing, unreadable, for another key or older than `ttl`
+pub fn load<T: DeserializeOwned>(path: &Path, key: &str, ttl: Duration) -> Option<Vec<T>> {
+    let cache: CacheFile<Vec<T>> = serde_json::from_slice(&fs::read(path).ok()?).ok()?;
+    let age = now().checked_sub(cache.written_at)?;
+    if cache.key != key || age > ttl.as_secs() {
+        return None;
+    }
+    Some(cache.applications)
+}
+
+pub fn store<T: Serialize>(path: &Path, key: &str, applications: &[T]) -> io::Result<()> {
+    let cache = CacheFile {
+        key: key.to_owned(),
+        written_at: now(),
+        applications,
+    };
+    fs::write(path, serde_json::to_vec(&cache)?)
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loads_what_was_stored() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        store(file.path(), "dev-fss", &["speil".to_owned()]).unwrap();
+
+        assert_eq!(load::<String>(file.path(), "dev-fss", Duration::from_secs(60)), Some(vec!["speil".to_owned()]));
+    }
+
+    #[test]
+    fn ignores_other_keys_and_stale_caches() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        fs::write(file.path(), format!(r#"{{"key":"dev-fss","written_at":{},"applications":["speil"]}}"#, now() - 120)).unwrap();
+
+        assert_eq!(load::<String>(file.path(), "prod-fss", Duration::from_secs(600)), None);
+        assert_eq!(load::<String>(file.path(), "dev-fss", Duration::from_secs(60)), None);
+        assert!(load::<String>(file.path(), "dev-fss", Duration::from_secs(600)).is_some());
+    }
+
+    #[test]
+    fn ignores_missing_cache() {
+        assert_eq!(load::<String>(Path::new("testdata/missing-cache.json"), "dev-fss", Duration::from_secs(60)), None);
+    }
+}
@@ -0,0 +1,119 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Stops sending requests to a port-forward after --circuit-breaker-failures failures in a row, until
+/// --circuit-breaker-cooldown has passed. A single request is then let through to see if the backend has recovered,
+/// closing the circuit if it succeeds and opening it again if it fails.
+pub struct CircuitBreaker {
+    threshold: Option<u32>,
+    cooldown: Duration,
+    circuit: Mutex<Circuit>,
+}
+
+#[derive(Default)]
+struct Circuit {
+    failures: u32,
+    open_until: Option<Instant>,
+    probing: bool,
+}
+
+impl CircuitBreaker {
+    /// A circuit breaker that never opens without a threshold
+    pub fn new(threshold: Option<u32>, cooldown: Duration) -> CircuitBreaker {
+        CircuitBreaker { threshold, cooldown, circuit: Mutex::default() }
+    }
+
+    /// Whether a request may be sent, or else how long until it is worth trying again
+    pub fn check(&self) -> Result<(), Duration> {
+        self.check_at(Instant::now())
+    }
+
+    /// Records how the request let through by `check` went
+    pub fn record(&self, success: bool) {
+        self.record_at(success, Instant::now())
+    }
+
+    fn check_at(&self, now: Instant) -> Result<(), Duration> {
+        if self.threshold.is_none() {
+            return Ok(());
+        }
+        let mut circuit = self.circuit.lock().unwrap();
+        match circuit.open_until {
+            Some(open_until) if now < open_until => Err(open_until - now),
+            // The other requests wait while this one finds out whether the backend has recovered. Should it never
+            // be recorded, e.g. because the client went away, the next one after the cooldown tries again.
+            Some(_) => {
+                circuit.open_until = Some(now + self.cooldown);
+                circuit.probing = true;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn record_at(&self, success: bool, now: Instant) {
+        let threshold = match self.threshold {
+            Some(threshold) => threshold,
+            None => return,
+        };
+        let mut circuit = self.circuit.lock().unwrap();
+        if success {
+            *circuit = Circuit::default();
+            return;
+        }
+        circuit.failures += 1;
+        if circuit.probing || circuit.failures >= threshold {
+            if !circuit.probing {
+                println!("Upstream failed {} times in a row, not sending it requests for {:?}", circuit.failures, self.cooldown);
+            }
+            circuit.open_until = Some(now + self.cooldown);
+            circuit.probing = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opens_after_consecutive_failures_and_closes_when_backend_recovers() {
+        let breaker = CircuitBreaker::new(Some(3), Duration::from_secs(10));
+        let start = Instant::now();
+
+        for _ in 0..2 {
+            assert_eq!(breaker.check_at(start), Ok(()));
+            breaker.record_at(false, start);
+        }
+        breaker.record_at(true, start);
+        for _ in 0..3 {
+            assert_eq!(breaker.check_at(start), Ok(()));
+            breaker.record_at(false, start);
+        }
+        assert_eq!(breaker.check_at(start + Duration::from_secs(4)), Err(Duration::from_secs(6)));
+
+        // Half-open, the probe fails and the circuit opens again
+        let reopened = start + Duration::from_secs(10);
+        assert_eq!(breaker.check_at(reopened), Ok(()));
+        assert_eq!(breaker.check_at(reopened), Err(Duration::from_secs(10)));
+        breaker.record_at(false, reopened);
+        assert_eq!(breaker.check_at(reopened + Duration::from_secs(1)), Err(Duration::from_secs(9)));
+
+        // The backend has recovered by the next probe
+        let recovered = reopened + Duration::from_secs(10);
+        assert_eq!(breaker.check_at(recovered), Ok(()));
+        breaker.record_at(true, recovered);
+        assert_eq!(breaker.check_at(recovered), Ok(()));
+        assert_eq!(breaker.check_at(recovered), Ok(()));
+    }
+
+    #[test]
+    fn never_opens_without_threshold() {
+        let breaker = CircuitBreaker::new(None, Duration::from_secs(10));
+
+        for _ in 0..10 {
+            breaker.record(false);
+        }
+        assert_eq!(breaker.check(), Ok(()));
+    }
+}
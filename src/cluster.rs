@@ -0,0 +1,191 @@
+use std::net::IpAddr;
+use std::process::Stdio;
+use std::str::FromStr;
+
+use tokio::process::Command;
+
+use crate::kubernetes::ResourceKind;
+
+/// The command line tool used to talk to the clusters. `oc` accepts the same flags as `kubectl` for the commands
+/// used here, but keeping the argument assembly per tool makes room for tools that don't.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClusterCli {
+    Kubectl,
+    Oc,
+}
+
+impl FromStr for ClusterCli {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "kubectl" => Ok(ClusterCli::Kubectl),
+            "oc" => Ok(ClusterCli::Oc),
+            _ => Err(format!("Expected kubectl or oc, got {}", s)),
+        }
+    }
+}
+
+impl ClusterCli {
+    pub fn program(self) -> &'static str {
+        match self {
+            ClusterCli::Kubectl => "kubectl",
+            ClusterCli::Oc => "oc",
+        }
+    }
+
+    pub fn command(self, args: Vec<String>) -> Command {
+        let mut command = Command::new(self.program());
+        command.args(args);
+        command
+    }
+
+    pub fn client_version_args(self) -> Vec<String> {
+        match self {
+            ClusterCli::Kubectl | ClusterCli::Oc => vec!["version".to_owned(), "--client".to_owned()],
+        }
+    }
+
+    /// Arguments for listing the names of the contexts in the kubeconfig, one per line
+    pub fn get_contexts_args(self) -> Vec<String> {
+        match self {
+            ClusterCli::Kubectl | ClusterCli::Oc => vec![
+                "config".to_owned(), "get-contexts".to_owned(), "-o".to_owned(), "name".to_owned(),
+            ],
+        }
+    }
+
+    /// The names of the contexts in the kubeconfig, for matching --context patterns against
+    pub async fn contexts(self) -> Result<Vec<String>, String> {
+        let output = self.command(self.get_contexts_args())
+            .stdin(Stdio::null())
+            .output()
+            .await
+            .map_err(|e| format!("Failed to run {}: {}", self.program(), e))?;
+        if !output.status.success() {
+            return Err(format!("{} config get-contexts failed\n{}", self.program(), String::from_utf8_lossy(&output.stderr).trim()));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(str::to_owned)
+            .collect())
+    }
+
+    /// Arguments for asking the cluster of a context for its version, which fails fast if it can't be reached
+    pub fn server_version_args(self, context: &str) -> Vec<String> {
+        match self {
+            ClusterCli::Kubectl | ClusterCli::Oc => vec![
+                "--context".to_owned(), context.to_owned(),
+                "--request-timeout".to_owned(), "5s".to_owned(),
+                "version".to_owned(),
+            ],
+        }
+    }
+
+    pub fn get_applications_args(self, context: &str, namespace: Option<&str>, kind: ResourceKind, selector: Option<&str>) -> Vec<String> {
+        match self {
+            ClusterCli::Kubectl | ClusterCli::Oc => {
+                let mut args = vec!["--context".to_owned(), context.to_owned()];
+                args.extend(namespace_args(namespace));
+                args.extend(vec![
+                    "get".to_owned(), kind.resource_name().to_owned(),
+                    "-o".to_owned(), "json".to_owned(),
+                ]);
+                if let Some(selector) = selector {
+                    args.push("-l".to_owned());
+                    args.push(selector.to_owned());
+                }
+                args
+            }
+        }
+    }
+
+    /// Arguments for forwarding a random local port to the service port, bound to `address` or localhost
+    /// Forwards from `local_port` when given, otherwise kubectl picks a free local port
+    pub fn port_forward_args(self, context: &str, namespace: &str, service: &str, service_port: &str, address: Option<IpAddr>, local_port: Option<u16>) -> Vec<String> {
+        match self {
+            ClusterCli::Kubectl | ClusterCli::Oc => {
+                let mut args = vec!["port-forward".to_owned(), "--context".to_owned(), context.to_owned()];
+                args.extend(namespace_args(Some(namespace)));
+                if let Some(address) = address {
+                    args.push("--address".to_owned());
+                    args.push(address.to_string());
+                }
+                args.push(format!("svc/{}", service));
+                match local_port {
+                    Some(local_port) => args.push(format!("{}:{}", local_port, service_port)),
+                    None => args.push(format!(":{}", service_port)),
+                }
+                args
+            }
+        }
+    }
+}
+
+/// Selects the namespace, or leaves it to the context without one as with --kubeconfig-namespace
+fn namespace_args(namespace: Option<&str>) -> Vec<String> {
+    match namespace {
+        Some(namespace) => vec!["--namespace".to_owned(), namespace.to_owned()],
+        None => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_cli_names() {
+        assert_eq!("kubectl".parse(), Ok(ClusterCli::Kubectl));
+        assert_eq!("oc".parse(), Ok(ClusterCli::Oc));
+        assert!("helm".parse::<ClusterCli>().is_err());
+    }
+
+    #[test]
+    fn get_contexts_args_list_names() {
+        assert_eq!(ClusterCli::Kubectl.get_contexts_args(), vec!["config", "get-contexts", "-o", "name"]);
+    }
+
+    #[test]
+    fn server_version_args_use_context() {
+        assert_eq!(ClusterCli::Kubectl.server_version_args("dev-fss"),
+                   vec!["--context", "dev-fss", "--request-timeout", "5s", "version"]);
+    }
+
+    #[test]
+    fn get_applications_args_include_selector() {
+        assert_eq!(ClusterCli::Kubectl.get_applications_args("dev-fss", Some("tbd"), ResourceKind::Application, None),
+                   vec!["--context", "dev-fss", "--namespace", "tbd", "get", "application", "-o", "json"]);
+        assert_eq!(ClusterCli::Oc.get_applications_args("dev-fss", Some("tbd"), ResourceKind::Application, Some("team=tbd")),
+                   vec!["--context", "dev-fss", "--namespace", "tbd", "get", "application", "-o", "json", "-l", "team=tbd"]);
+        assert_eq!(ClusterCli::Kubectl.get_applications_args("dev-fss", Some("tbd"), ResourceKind::Ingress, None)[5],
+                   "ingresses.networking.k8s.io");
+    }
+
+    #[test]
+    fn leaves_out_missing_namespace() {
+        assert_eq!(ClusterCli::Kubectl.get_applications_args("dev-fss", None, ResourceKind::Application, None),
+                   vec!["--context", "dev-fss", "get", "application", "-o", "json"]);
+    }
+
+    #[test]
+    fn port_forward_args_target_service_port() {
+        let expected = vec!["port-forward", "--context", "dev-fss", "--namespace", "default", "svc/speil", ":metrics"];
+
+        assert_eq!(ClusterCli::Kubectl.port_forward_args("dev-fss", "default", "speil", "metrics", None, None), expected);
+        assert_eq!(ClusterCli::Oc.port_forward_args("dev-fss", "default", "speil", "metrics", None, None), expected);
+    }
+
+    #[test]
+    fn port_forward_args_include_address() {
+        assert_eq!(ClusterCli::Kubectl.port_forward_args("dev-fss", "default", "speil", "80", Some("::1".parse().unwrap()), None),
+                   vec!["port-forward", "--context", "dev-fss", "--namespace", "default", "--address", "::1", "svc/speil", ":80"]);
+    }
+
+    #[test]
+    fn port_forward_args_include_local_port() {
+        assert_eq!(ClusterCli::Kubectl.port_forward_args("dev-fss", "default", "speil", "metrics", None, Some(8080)),
+                   vec!["port-forward", "--context", "dev-fss", "--namespace", "default", "svc/speil", "8080:metrics"]);
+    }
+}
@@ -0,0 +1,81 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Lets at most --max-concurrent-requests requests through to a port-forward at once. Up to --request-queue more
+/// wait for their turn, any beyond that are turned away.
+pub struct ConcurrencyLimit {
+    permits: Option<Arc<Semaphore>>,
+    queue: usize,
+    waiting: AtomicUsize,
+}
+
+/// The request can't even wait for its turn
+#[derive(Debug, PartialEq, Eq)]
+pub struct QueueFull;
+
+/// Counts a request as waiting until it has its turn or gives up
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl ConcurrencyLimit {
+    /// A limit letting every request through without a maximum
+    pub fn new(max: Option<usize>, queue: usize) -> ConcurrencyLimit {
+        ConcurrencyLimit { permits: max.map(|max| Arc::new(Semaphore::new(max))), queue, waiting: AtomicUsize::new(0) }
+    }
+
+    /// Waits for the request's turn, which lasts until the permit is dropped
+    pub async fn acquire(&self) -> Result<Option<OwnedSemaphorePermit>, QueueFull> {
+        let permits = match &self.permits {
+            Some(permits) => permits,
+            None => return Ok(None),
+        };
+        if let Ok(permit) = permits.clone().try_acquire_owned() {
+            return Ok(Some(permit));
+        }
+        if self.waiting.fetch_add(1, Ordering::SeqCst) >= self.queue {
+            self.waiting.fetch_sub(1, Ordering::SeqCst);
+            return Err(QueueFull);
+        }
+        let _waiting = Waiting(&self.waiting);
+        Ok(Some(permits.clone().acquire_owned().await))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::FutureExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn queues_requests_beyond_the_limit_and_turns_away_those_beyond_the_queue() {
+        let limit = ConcurrencyLimit::new(Some(2), 1);
+
+        let first = limit.acquire().await.unwrap();
+        let _second = limit.acquire().await.unwrap();
+        let mut third = Box::pin(limit.acquire());
+        assert!((&mut third).now_or_never().is_none());
+        assert_eq!(limit.acquire().await.err(), Some(QueueFull));
+
+        drop(first);
+        let third = third.await.unwrap();
+        assert!(third.is_some());
+        assert!(limit.acquire().now_or_never().is_none());
+    }
+
+    #[tokio::test]
+    async fn lets_everything_through_without_a_limit() {
+        let limit = ConcurrencyLimit::new(None, 0);
+
+        for _ in 0..100 {
+            assert!(limit.acquire().await.unwrap().is_none());
+        }
+    }
+}
@@ -0,0 +1,1030 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use hyper::{StatusCode, Uri};
+use hyper::http::uri::Authority;
+use regex::Regex;
+use hyper::header::{HeaderName, HeaderValue};
+use structopt::StructOpt;
+
+use crate::basic_auth::BasicAuth;
+use crate::cluster::ClusterCli;
+use crate::connections::OverLimit;
+use crate::hosts::HostsFormat;
+use crate::kubernetes::ResourceKind;
+use crate::tls::{Alpn, TlsVersion};
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "autoforward", about = "Automagically routes ingresses to Kubernetes via kubectl or oc port-forward")]
+pub struct Config {
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+
+    /// Write an access log in Combined Log Format to the given file, use `-` for stdout. Everything else printed goes
+    /// to stderr then, so stdout only has the access log.
+    #[structopt(long, parse(from_os_str))]
+    pub access_log: Option<PathBuf>,
+
+    /// Serve admin endpoints for listing and closing port-forwards under /_autoforward
+    #[structopt(long)]
+    pub admin: bool,
+
+    /// Command line tool used to talk to the clusters, either `kubectl` or `oc`
+    #[structopt(long, default_value = "kubectl")]
+    pub cli: ClusterCli,
+
+    /// Leave the hosts file alone, for when clients reach the proxy directly and only route by the Host header
+    #[structopt(long)]
+    pub no_hosts: bool,
+
+    /// Remove entries outside the autoforward block of the hosts file for hosts autoforward routes
+    #[structopt(long)]
+    pub force: bool,
+
+    /// Comment out the hosts entries when shutting down, and back in when starting, instead of leaving them active
+    #[structopt(long)]
+    pub disable_hosts_on_exit: bool,
+
+    /// Skip checking that the cluster tool is installed and the first context is reachable before starting
+    #[structopt(long)]
+    pub no_preflight: bool,
+
+    /// Print the subject, DNS names and validity of the server certificate, warn about discovered hosts it doesn't
+    /// cover and exit
+    #[structopt(long)]
+    pub print_cert_info: bool,
+
+    /// Cache discovered applications in this file and start from it while discovering them again
+    #[structopt(long, parse(from_os_str))]
+    pub cache: Option<PathBuf>,
+
+    /// Write the hosts entries to this file instead of the system hosts file, for resolving the hosts with e.g.
+    /// dnsmasq or HOSTALIASES without running as root
+    #[structopt(long, parse(from_os_str))]
+    pub hosts_file: Option<PathBuf>,
+
+    /// Format of the entries: `etc-hosts` lines, or `dnsmasq` `address=/<host>/<address>` lines for a snippet
+    /// included by a local dnsmasq, which takes --hosts-file
+    #[structopt(long, default_value = "etc-hosts")]
+    pub hosts_format: HostsFormat,
+
+    /// Keep a JSON list of the open port-forwards and their local addresses in this file. Their local ports are
+    /// remembered in `<file>.ports`, so the next start opens the port-forwards on the same ports when they are free
+    #[structopt(long, parse(from_os_str))]
+    pub state_file: Option<PathBuf>,
+
+    /// Seconds a cache is used for after it was written
+    #[structopt(long, default_value = "86400")]
+    pub cache_ttl: u64,
+
+    /// Kubernetes contexts to discover applications in. A pattern like `dev-*` is matched against the contexts in
+    /// the kubeconfig at startup
+    #[structopt(long = "context", default_value = "dev-fss,prod-fss", use_delimiter = true)]
+    pub contexts: Vec<String>,
+
+    /// Contexts in the order they win when applications in several contexts share an ingress, e.g.
+    /// `dev-fss,prod-fss`. Contexts not listed come last
+    #[structopt(long, use_delimiter = true)]
+    pub context_priority: Vec<String>,
+
+    /// Namespaces to discover applications in, for every context not listed in --namespace-file
+    #[structopt(long = "namespace", default_value = "default,tbd", use_delimiter = true)]
+    pub namespaces: Vec<String>,
+
+    /// JSON file mapping contexts to the namespaces to discover applications in, e.g.
+    /// `{"prod-fss": ["default"]}`. Contexts missing from the file use --namespace
+    #[structopt(long, parse(try_from_str = NamespaceMap::from_file))]
+    pub namespace_file: Option<NamespaceMap>,
+
+    /// Discover applications in the namespace each context is set to in the kubeconfig, leaving out `--namespace`
+    /// when calling kubectl, instead of those of --namespace. Can't be combined with --namespace-file
+    #[structopt(long)]
+    pub kubeconfig_namespace: bool,
+
+    /// Kind of resource to discover ingresses from: the nais `application`, or `ingress` or `httproute` for clusters
+    /// routing with standard resources, where every service routed to counts as an application
+    #[structopt(long, default_value = "application")]
+    pub resource_kind: ResourceKind,
+
+    /// Times to try discovering the applications of a namespace before leaving them out
+    #[structopt(long, default_value = "3")]
+    pub discovery_attempts: u32,
+
+    /// Milliseconds to wait before retrying discovery, doubled for every failed attempt
+    #[structopt(long, default_value = "500")]
+    pub discovery_backoff_ms: u64,
+
+    /// Seconds to wait for the applications of a namespace before counting the attempt as failed
+    #[structopt(long, default_value = "30")]
+    pub discovery_timeout: u64,
+
+    /// Only manage hosts matching one of these globs, e.g. `*.dev-fss.*`. All hosts are managed if unset
+    #[structopt(long = "allow-host", number_of_values = 1)]
+    pub allow_hosts: Vec<HostPattern>,
+
+    /// Never manage hosts matching this glob, even if they match --allow-host
+    #[structopt(long = "deny-host", number_of_values = 1)]
+    pub deny_hosts: Vec<HostPattern>,
+
+    /// Only discover applications matching this label selector, e.g. `team=tbd`. Applications without ingresses
+    /// are skipped regardless of the selector
+    #[structopt(long)]
+    pub selector: Option<String>,
+
+    /// Route an ingress to a named or numbered service port instead of port 80, given as <ingress>=<port>.
+    /// The ingress has to share its host with an ingress of the application
+    #[structopt(long = "service-port", number_of_values = 1)]
+    pub service_ports: Vec<ServicePortRule>,
+
+    /// Open the port-forwards of an application through another context than it was discovered in, given as
+    /// <discovered context>/<application>=<context>[/<namespace>]. The namespace stays the one it was discovered in
+    /// when left out
+    #[structopt(long = "forward-via", number_of_values = 1)]
+    pub forward_via: Vec<ForwardVia>,
+
+    /// Route requests with one Host header as if they had another, given as <from>=<to>, e.g.
+    /// `localhost=speil.nais.preprod.local`. A <from> without a port matches the host on any port
+    #[structopt(long = "host-rewrite", number_of_values = 1)]
+    pub host_rewrites: Vec<HostRewrite>,
+
+    /// Forward requests for a host to an address of your choosing instead of a port-forward, given as
+    /// <host>=<address>:<port>, e.g. `speil.nais.preprod.local=localhost:3000` for a local dev server
+    #[structopt(long = "static-route", number_of_values = 1)]
+    pub static_routes: Vec<StaticRoute>,
+
+    /// Hosts to open port-forwards for as soon as the applications are discovered, e.g.
+    /// `speil.nais.preprod.local,spleis.nais.preprod.local`, so the first request doesn't wait on kubectl
+    #[structopt(long, use_delimiter = true)]
+    pub warmup: Vec<String>,
+
+    /// Header added to every request forwarded to a backend, given as `Name: value`, e.g. `X-Tenant: tbd`. Replaces
+    /// a header of the same name sent by the client
+    #[structopt(long = "request-header", number_of_values = 1)]
+    pub request_headers: Vec<RequestHeader>,
+
+    /// Path prepended to the path of requests forwarded to a backend, e.g. `/api`. Given as <ingress>=<prefix> it
+    /// only applies to that ingress and takes precedence over a prefix for every ingress
+    #[structopt(long = "upstream-prefix", number_of_values = 1)]
+    pub upstream_prefixes: Vec<UpstreamPrefix>,
+
+    /// Local address port-forwards bind to, localhost if unset
+    #[structopt(long)]
+    pub forward_address: Option<IpAddr>,
+
+    /// Local ports port-forwards are opened on, either a single port or a range like `8000-8099`. kubectl picks a
+    /// free port if unset
+    #[structopt(long)]
+    pub local_ports: Option<LocalPorts>,
+
+    /// Allow a --forward-address that isn't a loopback address, exposing the port-forwards to the network
+    #[structopt(long)]
+    pub allow_remote_forwards: bool,
+
+    /// Wait for a new port-forward to pass its readiness or liveness check before forwarding requests to it
+    #[structopt(long)]
+    pub wait_for_ready: bool,
+
+    /// Seconds to wait for a new port-forward to become ready before failing the request
+    #[structopt(long, default_value = "10")]
+    pub ready_timeout: u64,
+
+    /// Seconds a client connection may go without sending or receiving anything before it is closed, while the
+    /// proxy isn't working on one of its requests. Unlimited if unset
+    #[structopt(long)]
+    pub idle_timeout: Option<u64>,
+
+    /// Seconds to wait for a connection to a port-forward before answering with 502
+    #[structopt(long, default_value = "5")]
+    pub connect_timeout: u64,
+
+    /// Port-forwards opened per second at most, so a page load hitting many hosts doesn't spawn kubectl for all of
+    /// them at once. Requests beyond the limit wait their turn. Unlimited if unset
+    #[structopt(long)]
+    pub forward_rate: Option<f64>,
+
+    /// Port-forwards that may be opened at once before --forward-rate kicks in
+    #[structopt(long, default_value = "5")]
+    pub forward_burst: u32,
+
+    /// Answer requests for a port-forward with 503 right away once this many requests to it have failed in a row,
+    /// instead of trying it again. Off if unset
+    #[structopt(long)]
+    pub circuit_breaker_failures: Option<u32>,
+
+    /// Seconds to wait after --circuit-breaker-failures before letting a request through to check on the backend
+    #[structopt(long, default_value = "10")]
+    pub circuit_breaker_cooldown: u64,
+
+    /// Requests sent to a port-forward at once, others wait for their turn. Unlimited if unset
+    #[structopt(long)]
+    pub max_concurrent_requests: Option<usize>,
+
+    /// Requests that may wait for their turn at a port-forward because of --max-concurrent-requests, any more are
+    /// answered with 503
+    #[structopt(long, default_value = "20")]
+    pub request_queue: usize,
+
+    /// Seconds a port-forward is kept open before it is replaced by a new one, even when in use. Unlimited if unset
+    #[structopt(long)]
+    pub forward_max_lifetime: Option<u64>,
+
+    /// Seconds shutting down may take before autoforward kills the port-forwards, removes its hosts entries, or
+    /// disables them with --disable-hosts-on-exit, and exits
+    #[structopt(long, default_value = "15")]
+    pub shutdown_timeout: u64,
+
+    /// Seconds between checking the health and ttl of open port-forwards, a small random jitter is added
+    #[structopt(long, default_value = "10")]
+    pub tick_interval: u64,
+
+    /// Statuses from the liveness and readiness paths that count as healthy, e.g. `200,204,401`. Any 2xx status
+    /// if unset
+    #[structopt(long = "selftest-status", use_delimiter = true)]
+    pub selftest_statuses: Vec<StatusCode>,
+
+    /// Follow one redirect from the liveness and readiness paths, only its path is used
+    #[structopt(long)]
+    pub selftest_follow_redirect: bool,
+
+    /// Seconds a liveness or readiness probe may take before it counts as failed
+    #[structopt(long, default_value = "5")]
+    pub selftest_timeout: u64,
+
+    /// Include the last lines kubectl wrote to stderr when a request to a port-forward fails. This exposes details
+    /// about the cluster to clients
+    #[structopt(long)]
+    pub debug_upstream: bool,
+
+    /// Print every attempt to match a request against an ingress, for finding out why a request is routed as it is
+    #[structopt(long)]
+    pub verbose_matching: bool,
+
+    /// Print how long each forwarded request spent getting its port-forward and waiting for the upstream to answer
+    #[structopt(long)]
+    pub log_timing: bool,
+
+    /// Host header sent to the backend: `preserve` the one the client sent, use the `loopback` address of the
+    /// port-forward, or any other value to send that host
+    #[structopt(long, default_value = "preserve")]
+    pub upstream_host: UpstreamHost,
+
+    /// Largest request body in bytes forwarded to a backend, larger requests are answered with 413. Unlimited if
+    /// unset
+    #[structopt(long)]
+    pub max_body_size: Option<u64>,
+
+    /// Answer HTTP/2 requests for another host than the TLS connection was opened for with 421, making clients that
+    /// reuse one connection for several hosts connect again
+    #[structopt(long)]
+    pub strict_sni: bool,
+
+    /// Speak HTTP/2 to the backends without negotiating it, for backends that only serve HTTP/2 over plain text
+    #[structopt(long)]
+    pub upstream_http2: bool,
+
+    /// List the known hosts when no service is found for a request. This exposes the routing table to clients
+    #[structopt(long)]
+    pub list_hosts: bool,
+
+    /// Directory with HTML pages to answer with instead of plain text, named by status like `404.html` or
+    /// `502.html`. `{{status}}`, `{{message}}`, `{{host}}` and `{{upstream}}` in a page are replaced
+    #[structopt(long, parse(from_os_str))]
+    pub error_pages: Option<PathBuf>,
+
+    /// Maximum number of client connections served at the same time, unlimited if unset
+    #[structopt(long)]
+    pub max_connections: Option<usize>,
+
+    /// What to do with connections over the limit, either `queue` them or `reject` them with 503
+    #[structopt(long, default_value = "queue")]
+    pub over_limit: OverLimit,
+
+    /// PEM file with the server certificate and any intermediates, possibly followed by its private key
+    #[structopt(long, parse(from_os_str), default_value = ".keys/server.crt")]
+    pub cert: PathBuf,
+
+    /// PEM file with the private key of --cert. If unset, the key in --cert is used, or else the `.key` file next
+    /// to it
+    #[structopt(long, parse(from_os_str))]
+    pub key: Option<PathBuf>,
+
+    /// Require clients to log in with this user and password, given as `<user>:<password>`. The ready endpoint is
+    /// always open
+    #[structopt(long)]
+    pub basic_auth: Option<BasicAuth>,
+
+    /// Like --basic-auth, but reads `<user>:<password>` from this file to keep the password out of the process list
+    #[structopt(long, parse(try_from_str = BasicAuth::from_file))]
+    pub basic_auth_file: Option<BasicAuth>,
+
+    /// Let the metrics endpoint be scraped without the --basic-auth user and password
+    #[structopt(long)]
+    pub basic_auth_exempt_metrics: bool,
+
+    /// Require clients to present a certificate signed by one of the CA certificates in this PEM file
+    #[structopt(long, parse(from_os_str))]
+    pub client_ca: Option<PathBuf>,
+
+    /// Serve TLS on this Unix domain socket instead of port 443 or 8443, limiting access by file permissions
+    #[structopt(long, parse(from_os_str))]
+    pub unix_socket: Option<PathBuf>,
+
+    /// Give every host its own address from a loopback range like `127.1.0.0/16`, listening on all of them and
+    /// routing by the address a connection arrived on
+    #[structopt(long)]
+    pub loopback_range: Option<LoopbackRange>,
+
+    /// Oldest TLS version accepted from clients, either `1.2` or `1.3`
+    #[structopt(long, default_value = "1.2")]
+    pub tls_min_version: TlsVersion,
+
+    /// Protocols offered to clients over ALPN and served, either `http1`, `h2` or `both`
+    #[structopt(long, default_value = "both")]
+    pub alpn: Alpn,
+}
+
+impl Config {
+    /// Checks the options that depend on each other, returning a message explaining what is wrong
+    pub fn validate(&self) -> Result<(), String> {
+        if self.circuit_breaker_failures == Some(0) {
+            return Err("--circuit-breaker-failures has to be at least 1".to_owned());
+        }
+        if self.hosts_format == HostsFormat::Dnsmasq && self.hosts_file.is_none() {
+            return Err("--hosts-format dnsmasq needs --hosts-file pointing at the dnsmasq snippet".to_owned());
+        }
+        if self.kubeconfig_namespace && self.namespace_file.is_some() {
+            return Err("--kubeconfig-namespace can't be combined with --namespace-file".to_owned());
+        }
+        if self.basic_auth.is_some() && self.basic_auth_file.is_some() {
+            return Err("--basic-auth can't be combined with --basic-auth-file".to_owned());
+        }
+        #[cfg(not(unix))]
+        if self.access_log.as_deref() == Some(std::path::Path::new("-")) {
+            return Err("--access-log - is only supported on unix, give a file instead".to_owned());
+        }
+        if self.shutdown_timeout == 0 {
+            return Err("--shutdown-timeout has to be at least 1".to_owned());
+        }
+        if self.tick_interval == 0 {
+            return Err("--tick-interval has to be at least 1".to_owned());
+        }
+        if self.idle_timeout == Some(0) {
+            return Err("--idle-timeout has to be at least 1".to_owned());
+        }
+        if self.max_concurrent_requests == Some(0) {
+            return Err("--max-concurrent-requests has to be at least 1".to_owned());
+        }
+        if self.forward_rate.is_some_and(|rate| !(rate > 0.0 && rate.is_finite())) {
+            return Err("--forward-rate has to be a positive number".to_owned());
+        }
+        if self.loopback_range.is_some() && self.unix_socket.is_some() {
+            return Err("--loopback-range can't be combined with --unix-socket".to_owned());
+        }
+        match self.forward_address {
+            Some(address) if !address.is_loopback() && !self.allow_remote_forwards => Err(format!(
+                "--forward-address {} is not a loopback address, add --allow-remote-forwards to expose port-forwards to the network",
+                address)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the host is allowed by --allow-host and --deny-host, where deny takes precedence
+    pub fn manages_host(&self, host: &str) -> bool {
+        let allowed = self.allow_hosts.is_empty() || self.allow_hosts.iter().any(|pattern| pattern.matches(host));
+        allowed && !self.deny_hosts.iter().any(|pattern| pattern.matches(host))
+    }
+
+    /// The namespaces to discover applications in for a context
+    pub fn namespaces_for(&self, context: &str) -> &[String] {
+        self.namespace_file.as_ref()
+            .and_then(|map| map.0.get(context))
+            .unwrap_or(&self.namespaces)
+    }
+
+    /// The user and password clients have to log in with, from --basic-auth or --basic-auth-file
+    pub fn basic_auth(&self) -> Option<&BasicAuth> {
+        self.basic_auth.as_ref().or(self.basic_auth_file.as_ref())
+    }
+
+    /// Whether any --context is a pattern like `dev-*` to be matched against the contexts in the kubeconfig
+    pub fn has_context_patterns(&self) -> bool {
+        self.contexts.iter().any(|context| context.contains('*'))
+    }
+
+    /// Replaces each --context pattern with the available contexts it matches, in the order they are available.
+    /// Returns a warning for each pattern matching none of them, or an error when no context is left at all.
+    pub fn expand_contexts(&mut self, available: &[String]) -> Result<Vec<String>, String> {
+        let mut contexts = Vec::new();
+        let mut warnings = Vec::new();
+        let requested = self.contexts.join(",");
+        for context in std::mem::take(&mut self.contexts) {
+            if !context.contains('*') {
+                if !contexts.contains(&context) {
+                    contexts.push(context);
+                }
+                continue;
+            }
+            let pattern = glob(&context).unwrap();
+            let matching = available.iter().filter(|available| pattern.is_match(available)).collect::<Vec<_>>();
+            if matching.is_empty() {
+                warnings.push(format!("--context {} matches none of the contexts in the kubeconfig", context));
+            }
+            for available in matching {
+                if !contexts.contains(available) {
+                    contexts.push(available.clone());
+                }
+            }
+        }
+        if contexts.is_empty() {
+            return Err(format!("--context {} matches none of the contexts in the kubeconfig, there is nothing to discover",
+                               requested));
+        }
+        self.contexts = contexts;
+        Ok(warnings)
+    }
+
+    /// Every context and namespace combination to discover applications in, without a namespace for the one the
+    /// context is set to with --kubeconfig-namespace
+    pub fn discovery_targets(&self) -> Vec<(String, Option<String>)> {
+        if self.kubeconfig_namespace {
+            return self.contexts.iter().map(|context| (context.clone(), None)).collect();
+        }
+        self.contexts.iter()
+            .flat_map(|context| self.namespaces_for(context).iter().map(move |namespace| (context.clone(), Some(namespace.clone()))))
+            .collect()
+    }
+}
+
+/// Namespaces to discover applications in per context
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NamespaceMap(pub BTreeMap<String, Vec<String>>);
+
+impl NamespaceMap {
+    fn from_file(path: &str) -> Result<NamespaceMap, String> {
+        let content = fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+        serde_json::from_str(&content)
+            .map(NamespaceMap)
+            .map_err(|e| format!("Invalid namespace file {}: {}", path, e))
+    }
+}
+
+/// An inclusive range of local ports
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LocalPorts {
+    pub first: u16,
+    pub last: u16,
+}
+
+impl FromStr for LocalPorts {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (first, last) = s.split_once('-').unwrap_or((s, s));
+        let parse = |port: &str| port.trim().parse::<u16>().ok().filter(|&port| port != 0);
+        match (parse(first), parse(last)) {
+            (Some(first), Some(last)) if first <= last => Ok(LocalPorts { first, last }),
+            _ => Err(format!("Expected a port or a range of ports like 8000-8099, got {}", s)),
+        }
+    }
+}
+
+/// An IPv4 network inside 127.0.0.0/8 that hosts are given addresses from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LoopbackRange {
+    pub network: Ipv4Addr,
+    pub prefix_len: u8,
+}
+
+impl LoopbackRange {
+    /// The number of addresses in the range, not counting the network and broadcast addresses
+    pub fn usable_addresses(&self) -> u32 {
+        (1 << (32 - self.prefix_len)) - 2
+    }
+
+    /// The usable address `offset` addresses into the range, counting from 0
+    pub fn address(&self, offset: u32) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(self.network) + 1 + offset)
+    }
+
+    /// The offset of a usable address in the range
+    pub fn offset_of(&self, address: Ipv4Addr) -> Option<u32> {
+        u32::from(address).checked_sub(u32::from(self.network) + 1).filter(|&offset| offset < self.usable_addresses())
+    }
+}
+
+impl FromStr for LoopbackRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || format!("Expected a loopback range like 127.1.0.0/16 with a prefix between /8 and /30, got {}", s);
+        let (address, prefix_len) = s.split_once('/').ok_or_else(error)?;
+        let address = address.parse::<Ipv4Addr>().map_err(|_| error())?;
+        let prefix_len = prefix_len.parse::<u8>().ok().filter(|len| (8..=30).contains(len)).ok_or_else(error)?;
+        if !address.is_loopback() {
+            return Err(error());
+        }
+        let mask = u32::MAX << (32 - prefix_len);
+        Ok(LoopbackRange { network: Ipv4Addr::from(u32::from(address) & mask), prefix_len })
+    }
+}
+
+/// A hostname glob where `*` matches any number of characters, including dots
+#[derive(Clone, Debug)]
+pub struct HostPattern(Regex);
+
+impl HostPattern {
+    pub fn matches(&self, host: &str) -> bool {
+        self.0.is_match(&host.to_ascii_lowercase())
+    }
+}
+
+impl FromStr for HostPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err("Host pattern can't be empty".to_owned());
+        }
+        glob(&s.to_ascii_lowercase())
+            .map(HostPattern)
+            .map_err(|e| format!("Invalid host pattern {}: {}", s, e))
+    }
+}
+
+/// Matches the whole text, where `*` in the pattern matches any number of characters
+fn glob(pattern: &str) -> Result<Regex, regex::Error> {
+    let pattern = pattern.split('*').map(regex::escape).collect::<Vec<_>>().join(".*");
+    Regex::new(&format!("^{}$", pattern))
+}
+
+#[derive(Debug, StructOpt)]
+pub enum Command {
+    /// Remove the entries autoforward added to the hosts file, e.g. after it was killed, and exit
+    Clean,
+    /// Discover applications and print every ingress with where it is routed as JSON, then exit. No port-forwards
+    /// are opened and the hosts file is left alone
+    DumpRoutes,
+    /// Check that autoforward can run: discover applications, load the certificate and key, and check that the
+    /// hosts file is writable and every host is covered by the certificate. Exits with an error if anything is wrong
+    Check,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum UpstreamHost {
+    Preserve,
+    Loopback,
+    Fixed(HeaderValue),
+}
+
+impl FromStr for UpstreamHost {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "preserve" => Ok(UpstreamHost::Preserve),
+            "loopback" => Ok(UpstreamHost::Loopback),
+            _ => HeaderValue::from_str(s)
+                .map(UpstreamHost::Fixed)
+                .map_err(|_| format!("Invalid upstream host {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HostRewrite {
+    pub from: String,
+    pub to: HeaderValue,
+}
+
+impl HostRewrite {
+    /// Whether the rule applies to a Host header, ignoring case and, unless the rule names one, the port
+    pub fn matches(&self, host: &str) -> bool {
+        let names_port = self.from.parse::<Authority>().is_ok_and(|from| from.port().is_some());
+        match host.parse::<Authority>() {
+            Ok(authority) if !names_port => authority.host().eq_ignore_ascii_case(&self.from),
+            _ => host.eq_ignore_ascii_case(&self.from),
+        }
+    }
+}
+
+impl FromStr for HostRewrite {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((from, to)) if !from.is_empty() && !to.is_empty() => Ok(HostRewrite {
+                from: from.to_owned(),
+                to: HeaderValue::from_str(to).map_err(|_| format!("Invalid host {}", to))?,
+            }),
+            _ => Err(format!("Expected <from>=<to>, got {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StaticRoute {
+    pub host: String,
+    pub target_host: String,
+    pub target_port: u16,
+}
+
+impl StaticRoute {
+    /// The route for the host, ignoring case
+    pub fn find<'a>(routes: &'a [StaticRoute], host: &str) -> Option<&'a StaticRoute> {
+        routes.iter().find(|route| route.host.eq_ignore_ascii_case(host))
+    }
+}
+
+impl FromStr for StaticRoute {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || format!("Expected <host>=<address>:<port>, got {}", s);
+        let (host, target) = s.split_once('=').ok_or_else(error)?;
+        let (target_host, target_port) = target.rsplit_once(':').ok_or_else(error)?;
+        // IPv6 addresses are given in brackets, like in a URI
+        let target_host = target_host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(target_host);
+        let target_port = target_port.parse::<u16>().ok().filter(|&port| port != 0).ok_or_else(error)?;
+        // Both have to be valid in a URI, e.g. without spaces, or requests for them can't be forwarded
+        let valid = |authority: &str| authority.parse::<Authority>().is_ok();
+        if host.is_empty() || host.contains(':') || !valid(host) || target_host.is_empty() || !valid(target) {
+            return Err(error());
+        }
+        Ok(StaticRoute { host: host.to_ascii_lowercase(), target_host: target_host.to_owned(), target_port })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RequestHeader {
+    pub name: HeaderName,
+    pub value: HeaderValue,
+}
+
+impl FromStr for RequestHeader {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, value) = s.split_once(':').ok_or_else(|| format!("Expected <name>: <value>, got {}", s))?;
+        Ok(RequestHeader {
+            name: HeaderName::from_str(name.trim()).map_err(|_| format!("Invalid header name {}", name.trim()))?,
+            value: HeaderValue::from_str(value.trim()).map_err(|_| format!("Invalid value for header {}", name.trim()))?,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UpstreamPrefix {
+    /// The ingress the prefix applies to, every ingress without one
+    pub ingress: Option<String>,
+    pub prefix: String,
+}
+
+impl UpstreamPrefix {
+    /// The prefix for the ingress, preferring one given for the ingress itself
+    pub fn find<'a>(prefixes: &'a [UpstreamPrefix], ingress: &str) -> Option<&'a str> {
+        prefixes.iter().find(|p| p.ingress.as_deref() == Some(ingress))
+            .or_else(|| prefixes.iter().find(|p| p.ingress.is_none()))
+            .map(|p| p.prefix.as_str())
+    }
+}
+
+impl FromStr for UpstreamPrefix {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (ingress, prefix) = if s.starts_with('/') {
+            (None, s)
+        } else {
+            let (ingress, prefix) = s.rsplit_once('=')
+                .ok_or_else(|| format!("Expected <prefix> or <ingress>=<prefix>, got {}", s))?;
+            let uri = Uri::from_str(ingress).map_err(|e| format!("Invalid ingress {}: {}", ingress, e))?;
+            if uri.host().is_none() {
+                return Err(format!("Ingress {} has no host", ingress));
+            }
+            (Some(ingress.to_owned()), prefix)
+        };
+        if !prefix.starts_with('/') || prefix.contains(|c: char| c == '?' || c == '#' || c.is_whitespace()) {
+            return Err(format!("Invalid upstream prefix {}, expected a path like /api", prefix));
+        }
+        let segments = prefix.split('/').filter(|segment| !segment.is_empty()).collect::<Vec<_>>();
+        Ok(UpstreamPrefix { ingress, prefix: format!("/{}", segments.join("/")) })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServicePortRule {
+    pub ingress: String,
+    pub port: String,
+}
+
+impl FromStr for ServicePortRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (ingress, port) = s.rsplit_once('=')
+            .ok_or_else(|| format!("Expected <ingress>=<port>, got {}", s))?;
+        let uri = Uri::from_str(ingress)
+            .map_err(|e| format!("Invalid ingress {}: {}", ingress, e))?;
+        if uri.host().is_none() {
+            return Err(format!("Ingress {} has no host", ingress));
+        }
+        let valid_name = !port.is_empty() && port.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+        if port.parse::<u16>().is_err() && !valid_name {
+            return Err(format!("Invalid service port {}, expected a port number or name", port));
+        }
+        Ok(ServicePortRule {
+            ingress: ingress.to_owned(),
+            port: port.to_owned(),
+        })
+    }
+}
+
+/// Where the port-forwards of an application are opened, when not where it was discovered
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ForwardVia {
+    /// The context the application is discovered in
+    pub discovered_in: String,
+    pub application: String,
+    pub context: String,
+    pub namespace: Option<String>,
+}
+
+impl FromStr for ForwardVia {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Expected <discovered context>/<application>=<context>[/<namespace>], got {}", s);
+        let (source, target) = s.split_once('=').ok_or_else(invalid)?;
+        let (discovered_in, application) = source.split_once('/').ok_or_else(invalid)?;
+        let (context, namespace) = match target.split_once('/') {
+            Some((context, namespace)) => (context, Some(namespace)),
+            None => (target, None),
+        };
+        if discovered_in.is_empty() || application.is_empty() || context.is_empty() || namespace == Some("") {
+            return Err(invalid());
+        }
+        Ok(ForwardVia {
+            discovered_in: discovered_in.to_owned(),
+            application: application.to_owned(),
+            context: context.to_owned(),
+            namespace: namespace.map(str::to_owned),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    #[test]
+    fn parses_service_port_rules() {
+        assert_eq!("https://speil.nais.preprod.local/metrics=9090".parse(), Ok(ServicePortRule {
+            ingress: "https://speil.nais.preprod.local/metrics".to_owned(),
+            port: "9090".to_owned(),
+        }));
+        assert_eq!("https://speil.nais.preprod.local/metrics=http-metrics".parse::<ServicePortRule>().map(|r| r.port),
+                   Ok("http-metrics".to_owned()));
+        assert!("https://speil.nais.preprod.local/metrics".parse::<ServicePortRule>().is_err());
+        assert!("https://speil.nais.preprod.local/metrics=".parse::<ServicePortRule>().is_err());
+        assert!("/metrics=9090".parse::<ServicePortRule>().is_err());
+    }
+
+    #[test]
+    fn expands_context_patterns_against_available_contexts() {
+        let mut config = Config::from_iter(&["autoforward", "--context", "dev-*,prod-fss,test-*,dev-fss"]);
+        let available = ["prod-fss", "dev-gcp", "dev-fss", "prod-gcp"].iter().map(|&context| context.to_owned()).collect::<Vec<_>>();
+
+        assert!(config.has_context_patterns());
+        let warnings = config.expand_contexts(&available);
+
+        assert_eq!(config.contexts, vec!["dev-gcp", "dev-fss", "prod-fss"]);
+        assert!(!config.has_context_patterns());
+        assert_eq!(warnings, Ok(vec!["--context test-* matches none of the contexts in the kubeconfig".to_owned()]));
+    }
+
+    #[test]
+    fn rejects_context_patterns_matching_nothing() {
+        let mut config = Config::from_iter(&["autoforward", "--context", "test-*,qa-*"]);
+        let available = vec!["dev-fss".to_owned()];
+
+        assert_eq!(config.expand_contexts(&available),
+                   Err("--context test-*,qa-* matches none of the contexts in the kubeconfig, there is nothing to discover".to_owned()));
+    }
+
+    #[test]
+    fn parses_forward_via() {
+        assert_eq!("dev-fss/speil=jump-fss".parse(), Ok(ForwardVia {
+            discovered_in: "dev-fss".to_owned(),
+            application: "speil".to_owned(),
+            context: "jump-fss".to_owned(),
+            namespace: None,
+        }));
+        assert_eq!("dev-fss/speil=jump-fss/tbd".parse::<ForwardVia>().map(|via| via.namespace), Ok(Some("tbd".to_owned())));
+        assert!("dev-fss/speil".parse::<ForwardVia>().is_err());
+        assert!("speil=jump-fss".parse::<ForwardVia>().is_err());
+        assert!("/speil=jump-fss".parse::<ForwardVia>().is_err());
+        assert!("dev-fss/=jump-fss".parse::<ForwardVia>().is_err());
+        assert!("dev-fss/speil=".parse::<ForwardVia>().is_err());
+        assert!("dev-fss/speil=jump-fss/".parse::<ForwardVia>().is_err());
+    }
+
+    #[test]
+    fn parses_upstream_prefixes() {
+        assert_eq!("/api//v1/".parse(), Ok(UpstreamPrefix { ingress: None, prefix: "/api/v1".to_owned() }));
+        assert_eq!("https://speil.nais.preprod.local/speil=/api".parse(), Ok(UpstreamPrefix {
+            ingress: Some("https://speil.nais.preprod.local/speil".to_owned()),
+            prefix: "/api".to_owned(),
+        }));
+        assert!("api".parse::<UpstreamPrefix>().is_err());
+        assert!("https://speil.nais.preprod.local=api".parse::<UpstreamPrefix>().is_err());
+        assert!("/api?v=1".parse::<UpstreamPrefix>().is_err());
+    }
+
+    #[test]
+    fn prefers_upstream_prefix_of_the_ingress() {
+        let prefixes = vec!["/global".parse().unwrap(), "https://speil.nais.preprod.local/=/speil".parse().unwrap()];
+
+        assert_eq!(UpstreamPrefix::find(&prefixes, "https://speil.nais.preprod.local/"), Some("/speil"));
+        assert_eq!(UpstreamPrefix::find(&prefixes, "https://spleis.nais.preprod.local/"), Some("/global"));
+        assert_eq!(UpstreamPrefix::find(&prefixes[1..], "https://spleis.nais.preprod.local/"), None);
+    }
+
+    #[test]
+    fn parses_static_routes() {
+        assert_eq!("Speil.nais.preprod.local=localhost:3000".parse(), Ok(StaticRoute {
+            host: "speil.nais.preprod.local".to_owned(),
+            target_host: "localhost".to_owned(),
+            target_port: 3000,
+        }));
+        assert_eq!("speil.nais.preprod.local=[::1]:3000".parse::<StaticRoute>().map(|r| r.target_host), Ok("::1".to_owned()));
+        assert!("speil.nais.preprod.local=localhost".parse::<StaticRoute>().is_err());
+        assert!("speil.nais.preprod.local=localhost:0".parse::<StaticRoute>().is_err());
+        assert!("speil.nais.preprod.local:443=localhost:3000".parse::<StaticRoute>().is_err());
+        assert!("=localhost:3000".parse::<StaticRoute>().is_err());
+        assert!("speil.nais.preprod.local=speil local:3000".parse::<StaticRoute>().is_err());
+        assert!("speil nais.preprod.local=localhost:3000".parse::<StaticRoute>().is_err());
+    }
+
+    #[test]
+    fn parses_request_headers() {
+        let header = "X-Tenant: tbd".parse::<RequestHeader>().unwrap();
+
+        assert_eq!(header.name, "x-tenant");
+        assert_eq!(header.value, "tbd");
+        assert_eq!("Authorization:Bearer a:b".parse::<RequestHeader>().unwrap().value, "Bearer a:b");
+        assert!("X-Tenant".parse::<RequestHeader>().is_err());
+        assert!("X Tenant: tbd".parse::<RequestHeader>().is_err());
+        assert!("X-Tenant: t\nbd".parse::<RequestHeader>().is_err());
+    }
+
+    #[test]
+    fn parses_host_rewrites() {
+        let rewrite = "localhost=speil.nais.preprod.local".parse::<HostRewrite>().unwrap();
+
+        assert_eq!(rewrite.to, "speil.nais.preprod.local");
+        assert!(rewrite.matches("localhost"));
+        assert!(rewrite.matches("LocalHost:8443"));
+        assert!(!rewrite.matches("localhost.localdomain"));
+        assert!(!"localhost:8443=speil.nais.preprod.local".parse::<HostRewrite>().unwrap().matches("localhost:443"));
+        assert!("[::1]=speil.nais.preprod.local".parse::<HostRewrite>().unwrap().matches("[::1]:8443"));
+        assert!("localhost".parse::<HostRewrite>().is_err());
+        assert!("=speil.nais.preprod.local".parse::<HostRewrite>().is_err());
+    }
+
+    #[test]
+    fn defaults_to_dev_and_prod_contexts() {
+        let config = Config::from_iter(&["autoforward"]);
+
+        assert_eq!(config.contexts, vec!["dev-fss", "prod-fss"]);
+        assert_eq!(config.namespaces, vec!["default", "tbd"]);
+        assert!(config.command.is_none());
+    }
+
+    #[test]
+    fn remote_forward_address_must_be_allowed() {
+        assert!(Config::from_iter(&["autoforward", "--forward-address", "127.0.0.2"]).validate().is_ok());
+        assert!(Config::from_iter(&["autoforward", "--forward-address", "10.0.0.1"]).validate().is_err());
+        assert!(Config::from_iter(&["autoforward", "--forward-address", "10.0.0.1", "--allow-remote-forwards"]).validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_zero_timeouts() {
+        assert!(Config::from_iter(&["autoforward", "--shutdown-timeout", "0"]).validate().is_err());
+        assert!(Config::from_iter(&["autoforward", "--shutdown-timeout", "1"]).validate().is_ok());
+        assert!(Config::from_iter(&["autoforward", "--tick-interval", "0"]).validate().is_err());
+        assert!(Config::from_iter(&["autoforward", "--idle-timeout", "0"]).validate().is_err());
+    }
+
+    #[test]
+    fn expands_namespaces_per_context() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(file, r#"{{"prod-fss": ["teamsykefravr"], "labs-gcp": ["tbd"]}}"#).unwrap();
+        let config = Config::from_iter(&["autoforward", "--namespace-file", file.path().to_str().unwrap()]);
+
+        assert_eq!(config.discovery_targets(), vec![
+            ("dev-fss".to_owned(), Some("default".to_owned())),
+            ("dev-fss".to_owned(), Some("tbd".to_owned())),
+            ("prod-fss".to_owned(), Some("teamsykefravr".to_owned())),
+        ]);
+    }
+
+    #[test]
+    fn kubeconfig_namespace_discovers_each_context_once() {
+        let config = Config::from_iter(&["autoforward", "--kubeconfig-namespace", "--namespace", "tbd"]);
+
+        assert_eq!(config.discovery_targets(), vec![
+            ("dev-fss".to_owned(), None),
+            ("prod-fss".to_owned(), None),
+        ]);
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(file, "{{}}").unwrap();
+        assert!(Config::from_iter(&["autoforward", "--kubeconfig-namespace", "--namespace-file", file.path().to_str().unwrap()])
+            .validate().is_err());
+    }
+
+    #[test]
+    fn reads_basic_auth_from_file() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, "speil:hemmelig").unwrap();
+        let path = file.path().to_str().unwrap();
+
+        let config = Config::from_iter(&["autoforward", "--basic-auth-file", path]);
+        assert_eq!(config.basic_auth(), Some(&"speil:hemmelig".parse().unwrap()));
+        assert!(Config::from_iter(&["autoforward", "--basic-auth", "speil:annet", "--basic-auth-file", path])
+            .validate().is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_namespace_file() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(file, r#"{{"prod-fss": "default"}}"#).unwrap();
+
+        assert!(Config::from_iter_safe(&["autoforward", "--namespace-file", file.path().to_str().unwrap()]).is_err());
+        assert!(Config::from_iter_safe(&["autoforward", "--namespace-file", "/autoforward/missing.json"]).is_err());
+    }
+
+    #[test]
+    fn deny_host_takes_precedence() {
+        let config = Config::from_iter(&["autoforward", "--allow-host", "*.dev-fss.*", "--deny-host", "spleis.*"]);
+
+        assert!(config.manages_host("speil.dev-fss.nais.io"));
+        assert!(!config.manages_host("spleis.dev-fss.nais.io"));
+        assert!(!config.manages_host("speil.nais.preprod.local"));
+    }
+
+    #[test]
+    fn manages_every_host_by_default() {
+        let config = Config::from_iter(&["autoforward"]);
+
+        assert!(config.manages_host("speil.nais.preprod.local"));
+        assert!(Config::from_iter(&["autoforward", "--deny-host", "spleis.*"]).manages_host("Speil.nais.preprod.local"));
+    }
+
+    #[test]
+    fn host_patterns_match_literally_except_for_stars() {
+        let pattern = "speil.nais.*".parse::<HostPattern>().unwrap();
+
+        assert!(pattern.matches("speil.nais.preprod.local"));
+        assert!(!pattern.matches("speilxnais.preprod.local"));
+        assert!(!pattern.matches("ny.speil.nais.preprod.local"));
+        assert!("".parse::<HostPattern>().is_err());
+    }
+
+    #[test]
+    fn parses_local_ports() {
+        assert_eq!("8000".parse(), Ok(LocalPorts { first: 8000, last: 8000 }));
+        assert_eq!("8000-8099".parse(), Ok(LocalPorts { first: 8000, last: 8099 }));
+        assert!("8099-8000".parse::<LocalPorts>().is_err());
+        assert!("0".parse::<LocalPorts>().is_err());
+        assert!("http".parse::<LocalPorts>().is_err());
+    }
+
+    #[test]
+    fn parses_loopback_range() {
+        let range = "127.1.2.3/16".parse::<LoopbackRange>().unwrap();
+        assert_eq!(range, LoopbackRange { network: Ipv4Addr::new(127, 1, 0, 0), prefix_len: 16 });
+        assert_eq!(range.usable_addresses(), 65534);
+        assert_eq!(range.address(0), Ipv4Addr::new(127, 1, 0, 1));
+        assert_eq!(range.offset_of(Ipv4Addr::new(127, 1, 0, 3)), Some(2));
+        assert_eq!(range.offset_of(Ipv4Addr::new(127, 1, 255, 255)), None);
+        assert_eq!(range.offset_of(Ipv4Addr::LOCALHOST), None);
+        assert!("10.0.0.0/8".parse::<LoopbackRange>().is_err());
+        assert!("127.0.0.0/31".parse::<LoopbackRange>().is_err());
+        assert!("127.0.0.1".parse::<LoopbackRange>().is_err());
+        assert!(Config::from_iter(&["autoforward", "--loopback-range", "127.1.0.0/16", "--unix-socket", "a.sock"])
+            .validate().is_err());
+    }
+
+    #[test]
+    fn parses_clean_subcommand() {
+        let config = Config::from_iter(&["autoforward", "clean"]);
+
+        assert!(matches!(config.command, Some(Command::Clean)));
+        assert!(matches!(Config::from_iter(&["autoforward", "check"]).command, Some(Command::Check)));
+    }
+}
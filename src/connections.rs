@@ -0,0 +1,130 @@
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::metrics::Metrics;
+
+/// What to do with a connection accepted while the connection limit is reached
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverLimit {
+    Queue,
+    Reject,
+}
+
+impl FromStr for OverLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "queue" => Ok(OverLimit::Queue),
+            "reject" => Ok(OverLimit::Reject),
+            _ => Err(format!("Expected queue or reject, got {}", s)),
+        }
+    }
+}
+
+pub struct ConnectionLimit {
+    semaphore: Option<Arc<Semaphore>>,
+    over_limit: OverLimit,
+    metrics: Arc<Metrics>,
+}
+
+/// Holds a connection slot until the connection is closed
+pub struct ConnectionGuard {
+    _permit: Option<OwnedSemaphorePermit>,
+    metrics: Arc<Metrics>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.metrics.connections_active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl ConnectionLimit {
+    pub fn new(max_connections: Option<usize>, over_limit: OverLimit, metrics: Arc<Metrics>) -> ConnectionLimit {
+        ConnectionLimit {
+            semaphore: max_connections.map(|max| Arc::new(Semaphore::new(max))),
+            over_limit,
+            metrics,
+        }
+    }
+
+    /// Waits for a free connection slot, or returns `None` if the connection should be rejected
+    pub async fn acquire(&self) -> Option<ConnectionGuard> {
+        let permit = match &self.semaphore {
+            None => None,
+            Some(semaphore) => match semaphore.clone().try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(_) if self.over_limit == OverLimit::Reject => {
+                    self.metrics.connections_rejected.fetch_add(1, Ordering::Relaxed);
+                    return None;
+                }
+                Err(_) => {
+                    self.metrics.connections_queued.fetch_add(1, Ordering::Relaxed);
+                    let permit = semaphore.clone().acquire_owned().await;
+                    self.metrics.connections_queued.fetch_sub(1, Ordering::Relaxed);
+                    Some(permit)
+                }
+            },
+        };
+        self.metrics.connections_active.fetch_add(1, Ordering::Relaxed);
+        Some(ConnectionGuard {
+            _permit: permit,
+            metrics: self.metrics.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::timeout;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn rejects_over_limit() {
+        let metrics = Arc::new(Metrics::default());
+        let limit = ConnectionLimit::new(Some(1), OverLimit::Reject, metrics.clone());
+
+        let first = limit.acquire().await;
+        assert!(first.is_some());
+        assert!(limit.acquire().await.is_none());
+        assert_eq!(metrics.connections_active.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.connections_rejected.load(Ordering::Relaxed), 1);
+
+        drop(first);
+        assert!(limit.acquire().await.is_some());
+        assert_eq!(metrics.connections_active.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn queues_over_limit() {
+        let metrics = Arc::new(Metrics::default());
+        let limit = Arc::new(ConnectionLimit::new(Some(1), OverLimit::Queue, metrics.clone()));
+
+        let first = limit.acquire().await.unwrap();
+        let queued = tokio::spawn({
+            let limit = limit.clone();
+            async move { limit.acquire().await.is_some() }
+        });
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+        assert_eq!(metrics.connections_queued.load(Ordering::Relaxed), 1);
+
+        drop(first);
+        assert!(timeout(Duration::from_secs(1), queued).await.unwrap().unwrap());
+        assert_eq!(metrics.connections_queued.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn unlimited_by_default() {
+        let limit = ConnectionLimit::new(None, OverLimit::Reject, Arc::new(Metrics::default()));
+
+        let guards = [limit.acquire().await, limit.acquire().await];
+        assert!(guards.iter().all(Option::is_some));
+    }
+}
@@ -0,0 +1,91 @@
+use std::path::Path;
+
+use hyper::{Body, Response, StatusCode};
+use hyper::header::{CONTENT_LENGTH, CONTENT_TYPE, HeaderValue};
+
+use crate::responses::error_response;
+
+/// Answers with the page for the status from the --error-pages directory, like `502.html`, or the plain text message
+/// when there is no such page. `{{status}}`, `{{message}}`, `{{host}}` and `{{upstream}}` in the page are replaced.
+pub async fn error_page(dir: Option<&Path>, status: StatusCode, message: String, host: &str, upstream: Option<&str>) -> Response<Body> {
+    with_error_page(dir, error_response(status, message), host, upstream).await
+}
+
+/// Replaces the plain text message of an error answered by the proxy with the page for its status, like
+/// `error_page`. Headers like Retry-After are kept.
+pub async fn with_error_page(dir: Option<&Path>, response: Response<Body>, host: &str, upstream: Option<&str>) -> Response<Body> {
+    let page = match dir {
+        Some(dir) => tokio::fs::read_to_string(dir.join(format!("{}.html", response.status().as_u16()))).await.ok(),
+        None => None,
+    };
+    let page = match page {
+        Some(page) => page,
+        None => return response,
+    };
+    let (mut parts, body) = response.into_parts();
+    // The message was built in memory, reading it can't fail
+    let message = hyper::body::to_bytes(body).await.map(|message| String::from_utf8_lossy(&message).into_owned()).unwrap_or_default();
+    let page = page
+        .replace("{{status}}", parts.status.as_str())
+        .replace("{{message}}", &escape(&message))
+        .replace("{{host}}", &escape(host))
+        .replace("{{upstream}}", &escape(upstream.unwrap_or_default()));
+    parts.headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/html; charset=utf-8"));
+    parts.headers.remove(CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(page))
+}
+
+/// Escapes text for HTML, the host comes straight from the client
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn serves_page_for_status_with_placeholders_replaced() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("502.html"), "<h1>{{status}}</h1><p>{{host}} via {{upstream}}: {{message}}</p>").unwrap();
+
+        let response = error_page(Some(dir.path()), StatusCode::BAD_GATEWAY, "connection refused".to_owned(),
+                                  "<speil>.nais.preprod.local", Some("127.0.0.1:54321")).await;
+
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(response.headers()[CONTENT_TYPE], "text/html; charset=utf-8");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], &b"<h1>502</h1><p>&lt;speil&gt;.nais.preprod.local via 127.0.0.1:54321: connection refused</p>"[..]);
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_plain_text_without_a_page() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("502.html"), "<h1>Bad gateway</h1>").unwrap();
+
+        let response = error_page(Some(dir.path()), StatusCode::NOT_FOUND, "No service found for speil.nais.preprod.local".to_owned(),
+                                  "speil.nais.preprod.local", None).await;
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers()[CONTENT_TYPE], "text/plain; charset=utf-8");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"No service found for speil.nais.preprod.local");
+    }
+
+    #[tokio::test]
+    async fn keeps_headers_of_the_replaced_response() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("503.html"), "<p>{{message}}</p>").unwrap();
+
+        let response = with_error_page(Some(dir.path()), crate::responses::queue_full_response(), "speil.nais.preprod.local", None).await;
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[hyper::header::RETRY_AFTER], "1");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], &b"<p>Too many requests are waiting for the upstream, try again.</p>"[..]);
+    }
+}
@@ -0,0 +1,51 @@
+use serde::Serialize;
+
+/// How many events are kept for subscribers that fall behind, older events are dropped for them
+pub const EVENT_BUFFER: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Opened,
+    Closed,
+    SelftestFailed,
+}
+
+/// A change to a port-forward, published to the event stream of the admin endpoints
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Event {
+    pub event: EventKind,
+    pub application: String,
+    pub ingresses: Vec<String>,
+    pub local_port: usize,
+    pub reason: String,
+}
+
+impl Event {
+    /// Formats the event as a Server-Sent Events message
+    pub fn to_sse(&self) -> String {
+        format!("event: {}\ndata: {}\n\n",
+                serde_json::to_value(self.event).unwrap().as_str().unwrap(),
+                serde_json::to_string(self).unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_as_server_sent_event() {
+        let event = Event {
+            event: EventKind::SelftestFailed,
+            application: "speil".to_owned(),
+            ingresses: vec!["https://speil.nais.preprod.local".to_owned()],
+            local_port: 4242,
+            reason: "Liveness check failed".to_owned(),
+        };
+
+        assert_eq!(event.to_sse(), "event: selftest_failed\n\
+            data: {\"event\":\"selftest_failed\",\"application\":\"speil\",\"ingresses\":[\"https://speil.nais.preprod.local\"],\
+            \"local_port\":4242,\"reason\":\"Liveness check failed\"}\n\n");
+    }
+}
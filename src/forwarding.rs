@@ -1,35 +1,66 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::error::Error;
 use std::fmt;
 use std::io;
-use std::process::Stdio;
+use std::net::IpAddr;
+use std::ops::Deref;
 use std::str::FromStr;
-use std::time::{Duration, SystemTime};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime};
 
-use hyper::{Client, Uri};
+use hyper::{Body, Client, Method, Request, Response, StatusCode, Uri};
+use hyper::header::{HOST, LOCATION};
 use hyper::client::HttpConnector;
 #[cfg(unix)]
+use nix::errno::Errno;
+#[cfg(unix)]
+use nix::sys::signal::Signal;
+#[cfg(unix)]
 use nix::unistd::Pid;
-use pin_utils::pin_mut;
+use once_cell::sync::Lazy;
+use rand::Rng;
 use regex::Regex;
-use tokio::{io::{AsyncBufReadExt, BufReader}};
-use tokio::process::{Child, Command};
+use serde::{Deserialize, Serialize};
+use tokio::{io::{AsyncBufRead, AsyncBufReadExt, BufReader, Lines}};
+use tokio::net::TcpStream;
+use tokio::process::Child;
+use tokio::sync::{broadcast, mpsc, Mutex, Notify, OwnedMutexGuard, Semaphore};
 use tokio::task::JoinHandle;
 use tokio::time::timeout;
+use tokio_rustls::TlsConnector;
+use tokio_rustls::webpki::DNSNameRef;
 
-use futures_util::stream::FuturesOrdered;
+use futures_util::future::{AbortHandle, Aborted, FutureExt, abortable, join, join_all};
+use futures_util::stream::{FuturesOrdered, FuturesUnordered};
 
-use super::kubernetes::{ApplicationResource, KubernetesResponse};
+use super::{cache, hosts, state_file, tls};
+use super::circuit_breaker::CircuitBreaker;
+use super::concurrency_limit::ConcurrencyLimit;
+use super::config::{Config, ForwardVia, ServicePortRule, StaticRoute};
+use super::events::{Event, EventKind, EVENT_BUFFER};
+use super::kubernetes::{ApplicationResource, HealthCheck, HealthScheme, DEFAULT_APPLICATION_PORT};
+use super::provider::{CliProvider, ResourceProvider};
+use super::rate_limit::TokenBucket;
 use futures_util::StreamExt;
 
 #[derive(Debug)]
 pub struct ForwardError {
-    message: &'static str,
-    original: io::Error,
+    pub(crate) message: Cow<'static, str>,
+    pub(crate) original: io::Error,
+    /// The ingress and application the error happened for, if it happened for a request
+    pub(crate) route: Option<String>,
+    /// Set when the request hit a port-forward that is being reconnected
+    pub(crate) reconnecting: bool,
 }
 
 impl fmt::Display for ForwardError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.message)
+        match &self.route {
+            Some(route) => write!(f, "{}: {}", route, self.message),
+            None => write!(f, "{}", self.message),
+        }
     }
 }
 
@@ -40,43 +71,356 @@ impl Error for ForwardError {
 }
 
 pub trait ToForwardError<A> {
-    fn context(self, context: &'static str) -> Result<A, ForwardError>;
+    fn context(self, context: impl Into<Cow<'static, str>>) -> Result<A, ForwardError>;
+}
+
+impl ForwardError {
+    /// Whether the request hit a port-forward that is being reconnected, and can be retried shortly
+    pub fn is_reconnecting(&self) -> bool {
+        self.reconnecting
+    }
+
+    fn for_route(self, route: &str) -> ForwardError {
+        ForwardError { route: Some(route.to_owned()), ..self }
+    }
 }
 
 impl<A> ToForwardError<A> for Result<A, io::Error> {
-    fn context(self, context: &'static str) -> Result<A, ForwardError> {
+    fn context(self, context: impl Into<Cow<'static, str>>) -> Result<A, ForwardError> {
         match self {
             Ok(v) => Ok(v),
             Err(e) => Err(ForwardError {
-                message: context,
+                message: context.into(),
                 original: e,
+                route: None,
+                reconnecting: false,
             }),
         }
     }
 }
 
-#[derive(PartialEq, Eq)]
-struct ApplicationDescriptor {
+static FORWARDING_LINE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"Forwarding from (?:\[([^\]]+)\]|([^\s\[\]]+)):(\d{1,5}) -> \d{1,5}").unwrap()
+});
+
+static INGRESS_HOST: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?i)^https?://([^/:?#]+)").unwrap());
+
+/// The pids of the kubectl processes still running, so they can be killed when shutting down gets stuck and the
+/// state holding them can't be reached
+static KUBECTL_PIDS: Lazy<std::sync::Mutex<HashSet<u32>>> = Lazy::new(Default::default);
+
+/// How many lines of stderr are kept for each port-forward
+const STDERR_LINES: usize = 5;
+
+/// How many selftests a tick runs at the same time
+const CONCURRENT_SELFTESTS: usize = 8;
+
+/// How long closing a port-forward waits for the requests still using it
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The service port forwarded to for ingresses without a service port rule
+const DEFAULT_SERVICE_PORT: &str = "80";
+
+/// How many times a port-forward whose kubectl exited is opened again before giving up on it
+const RECONNECT_ATTEMPTS: u32 = 5;
+
+/// The pause after the first failed reconnect, doubled after each failure
+const RECONNECT_BACKOFF: Duration = Duration::from_millis(500);
+
+
+/// Where a request is forwarded to, by a --static-route or to a discovered application
+#[derive(Debug, PartialEq, Eq)]
+pub enum Resolved<'a> {
+    Static(&'a StaticRoute),
+    Application(&'a ApplicationDescriptor),
+}
+
+impl<'a> Resolved<'a> {
+    /// The application forwarded to, unless it is a static route
+    pub fn application(self) -> Option<&'a ApplicationDescriptor> {
+        match self {
+            Resolved::Static(_) => None,
+            Resolved::Application(app) => Some(app),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ApplicationDescriptor {
     application_name: String,
     ingresses: Vec<String>,
-    liveness: Option<String>,
+    /// Ingresses routed to another service port than the default, with the port name or number
+    service_ports: Vec<(String, String)>,
+    liveness: Option<HealthCheck>,
+    readiness: Option<HealthCheck>,
     context: String,
     namespace: String,
+    /// The context and namespace to open port-forwards through instead, set by --forward-via
+    #[serde(default)]
+    forward_context: Option<String>,
+    #[serde(default)]
+    forward_namespace: Option<String>,
 }
 
-#[derive(Clone, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
 pub struct Portforward {
     pub host: String,
     pub port: usize,
 }
 
+impl Portforward {
+    /// The host and port as used in a URI, with IPv6 addresses in brackets
+    pub fn authority(&self) -> String {
+        if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+}
+
+/// A port-forward handed out to a request. It counts as in flight until dropped, so the port-forward isn't closed
+/// underneath it.
+pub struct ForwardLease {
+    portforward: Portforward,
+    ingress: String,
+    in_flight: Arc<AtomicUsize>,
+    circuit: Arc<CircuitBreaker>,
+    concurrency: Arc<ConcurrencyLimit>,
+    ready: Arc<Mutex<bool>>,
+    /// Set for the request that opened the port-forward with --wait-for-ready, which probes it
+    pending: Option<PendingReadiness>,
+}
+
+/// A readiness check still to be passed by a port-forward opened with --wait-for-ready
+struct PendingReadiness {
+    ready: OwnedMutexGuard<bool>,
+    probe: HealthProbe,
+    check: HealthCheck,
+    timeout: Duration,
+    route: String,
+}
+
+impl ForwardLease {
+    fn new(portforward: Portforward, ingress: &str, in_flight: &Arc<AtomicUsize>, circuit: &Arc<CircuitBreaker>, concurrency: &Arc<ConcurrencyLimit>, ready: &Arc<Mutex<bool>>) -> ForwardLease {
+        in_flight.fetch_add(1, Ordering::SeqCst);
+        ForwardLease {
+            portforward,
+            ingress: ingress.to_owned(),
+            in_flight: in_flight.clone(),
+            circuit: circuit.clone(),
+            concurrency: concurrency.clone(),
+            ready: ready.clone(),
+            pending: None,
+        }
+    }
+
+    /// Waits until the port-forward passed its readiness check. The request that opened it probes, the others
+    /// wait for its outcome. Returns the route of a port-forward failing the check when this request probed it.
+    async fn wait_until_ready(&mut self) -> Result<(), Option<String>> {
+        match self.pending.take() {
+            Some(mut pending) => {
+                *pending.ready = pending.probe.wait_until_ready(&pending.check, pending.timeout).await;
+                if *pending.ready { Ok(()) } else { Err(Some(pending.route)) }
+            }
+            None if *self.ready.lock().await => Ok(()),
+            None => Err(None),
+        }
+    }
+
+    /// The ingress the request was matched to
+    pub fn ingress(&self) -> &str {
+        &self.ingress
+    }
+
+    /// The host of the ingress the request was matched to
+    pub fn ingress_host(&self) -> String {
+        ingress_host(&self.ingress).unwrap_or_else(|| self.ingress.clone())
+    }
+
+    /// The circuit breaker of the port-forward, shared by every request to it
+    pub fn circuit(&self) -> &CircuitBreaker {
+        &self.circuit
+    }
+
+    /// The limit on requests sent to the port-forward at once
+    pub fn concurrency(&self) -> &ConcurrencyLimit {
+        &self.concurrency
+    }
+}
+
+impl Deref for ForwardLease {
+    type Target = Portforward;
+
+    fn deref(&self) -> &Portforward {
+        &self.portforward
+    }
+}
+
+impl Drop for ForwardLease {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Parses the local address from a line like `Forwarding from 127.0.0.1:54321 -> 80` or
+/// `Forwarding from [::1]:54321 -> 80`
+fn parse_forwarding_line(line: &str) -> Option<Portforward> {
+    let captures = FORWARDING_LINE.captures(line)?;
+    let host = captures.get(1).or_else(|| captures.get(2))?.as_str().to_owned();
+    let port = captures[3].parse::<u16>().ok()? as usize;
+    Some(Portforward { host, port })
+}
+
+/// Which responses from the liveness and readiness paths count as healthy
+#[derive(Clone, Debug)]
+struct SelftestPolicy {
+    /// Any 2xx status is accepted when empty
+    statuses: Vec<StatusCode>,
+    follow_redirect: bool,
+    /// How long a probe, including a followed redirect, may take before it counts as failed
+    timeout: Duration,
+}
+
+impl Default for SelftestPolicy {
+    fn default() -> SelftestPolicy {
+        SelftestPolicy { statuses: vec![], follow_redirect: false, timeout: Duration::from_secs(5) }
+    }
+}
+
+impl SelftestPolicy {
+    fn new(config: &Config) -> SelftestPolicy {
+        SelftestPolicy {
+            statuses: config.selftest_statuses.clone(),
+            follow_redirect: config.selftest_follow_redirect,
+            timeout: Duration::from_secs(config.selftest_timeout),
+        }
+    }
+
+    fn accepts(&self, status: StatusCode) -> bool {
+        if self.statuses.is_empty() {
+            status.is_success()
+        } else {
+            self.statuses.contains(&status)
+        }
+    }
+}
+
+/// Sends the liveness and readiness checks of a port-forward. It is cloned out of the port-forward to probe without
+/// holding the state.
+#[derive(Clone)]
+struct HealthProbe {
+    client: Client<HttpConnector>,
+    portforward: Portforward,
+    selftest: SelftestPolicy,
+}
+
+impl HealthProbe {
+    /// Probes the check until it succeeds or the timeout expires, even while a single probe hangs
+    async fn wait_until_ready(&self, check: &HealthCheck, ready_timeout: Duration) -> bool {
+        let probing = async {
+            let mut backoff = Duration::from_millis(100);
+            while !self.probe(check).await {
+                tokio::time::delay_for(backoff).await;
+                backoff = (backoff * 2).min(Duration::from_secs(1));
+            }
+        };
+        timeout(ready_timeout, probing).await.is_ok()
+    }
+
+    /// Probes the check, failing it when the backend doesn't answer in time instead of holding up the tick
+    async fn probe(&self, check: &HealthCheck) -> bool {
+        match timeout(self.selftest.timeout, self.probe_without_timeout(check)).await {
+            Ok(healthy) => healthy,
+            Err(_) => {
+                println!("Self-test towards {} timed out after {:?}", &check.path, self.selftest.timeout);
+                false
+            }
+        }
+    }
+
+    async fn probe_without_timeout(&self, check: &HealthCheck) -> bool {
+        let scheme = check.scheme.unwrap_or(HealthScheme::Http);
+        let response = match self.probe_response(scheme, &check.path).await {
+            Some(response) => response,
+            None => return false,
+        };
+        let location = response.headers().get(LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .and_then(|location| Uri::from_str(location).ok());
+        match location {
+            Some(location) if self.selftest.follow_redirect && response.status().is_redirection() => {
+                // Only the path is followed, the application can't be reached other than through the port-forward
+                let followed = self.probe_response(scheme, location.path()).await;
+                followed.is_some_and(|response| self.selftest.accepts(response.status()))
+            }
+            _ => self.selftest.accepts(response.status()),
+        }
+    }
+
+    async fn probe_response(&self, scheme: HealthScheme, path: &str) -> Option<Response<Body>> {
+        let path = path.strip_prefix('/').unwrap_or(path);
+        let uri = match Uri::from_str(format!("{}://{}/{}", scheme.as_str(), self.portforward.authority(), path).as_str()) {
+            Ok(uri) => uri,
+            Err(e) => {
+                println!("Can't run self-test towards {}: {}", path, e);
+                return None;
+            }
+        };
+        println!("Running self-test towards {}", &uri);
+        // HEAD spares downloading the body. Applications only routing GET on the path answer HEAD with a client
+        // error like 404 or 405, so only a server error is taken at its word.
+        let response = self.send_probe(Method::HEAD, scheme, uri.clone()).await?;
+        if !response.status().is_client_error() {
+            return Some(response);
+        }
+        self.send_probe(Method::GET, scheme, uri).await
+    }
+
+    async fn send_probe(&self, method: Method, scheme: HealthScheme, uri: Uri) -> Option<Response<Body>> {
+        match scheme {
+            HealthScheme::Http => {
+                let req = Request::builder().method(method).uri(uri).body(Body::empty()).ok()?;
+                self.client.request(req).await.ok()
+            }
+            HealthScheme::Https => self.probe_https(method, uri).await.ok(),
+        }
+    }
+
+    /// Sends a health check over HTTPS on a connection of its own, the checks are too rare to pool connections for
+    async fn probe_https(&self, method: Method, uri: Uri) -> Result<Response<Body>, Box<dyn Error + Send + Sync>> {
+        let tcp = TcpStream::connect(self.portforward.authority()).await?;
+        let name = DNSNameRef::try_from_ascii_str("localhost").map_err(|_| io::Error::other("invalid server name"))?;
+        let tls = TlsConnector::from(Arc::new(tls::backend_client_config())).connect(name, tcp).await?;
+        let (mut sender, connection) = hyper::client::conn::handshake(tls).await?;
+        tokio::spawn(connection);
+        let req = Request::builder().method(method).uri(uri.path()).header(HOST, uri.authority().map(|a| a.as_str()).unwrap_or_default()).body(Body::empty())?;
+        Ok(sender.send_request(req).await?)
+    }
+}
+
 struct PortforwardDescriptor {
+    application_name: String,
     hosts: Vec<String>,
+    service_port: String,
     ttl: SystemTime,
+    opened_at: Instant,
     port_forward_command: Child,
-    client: Client<HttpConnector>,
-    liveness: Option<String>,
-    stdout: JoinHandle<()>,
+    probe: HealthProbe,
+    liveness: Option<HealthCheck>,
+    readiness: Option<HealthCheck>,
+    /// Whether the port-forward passed its readiness check. The request that opened it holds the lock while
+    /// probing with --wait-for-ready, so requests finding the port-forward meanwhile wait for the outcome.
+    ready: Arc<Mutex<bool>>,
+    last_selftest: Option<bool>,
+    /// Requests holding a lease on the port-forward
+    in_flight: Arc<AtomicUsize>,
+    circuit: Arc<CircuitBreaker>,
+    concurrency: Arc<ConcurrencyLimit>,
+    /// The last lines kubectl wrote to stderr, usually explaining why it stopped forwarding
+    stderr_lines: Arc<std::sync::Mutex<VecDeque<String>>>,
+    output: JoinHandle<Result<(), Aborted>>,
+    output_abort: AbortHandle,
     portforward: Portforward,
 }
 
@@ -85,137 +429,480 @@ impl PortforwardDescriptor {
         SystemTime::now() + Duration::from_secs(60)
     }
 
-    async fn from_app(application: &ApplicationDescriptor) -> Result<PortforwardDescriptor, io::Error> {
-        let regex = Regex::new(r"Forwarding from (.+):(\d{2,5}) -> \d{2,5}").unwrap();
+    async fn from_app(provider: &dyn ResourceProvider, application: &ApplicationDescriptor, service_port: &str, local_port: Option<u16>, selftest: SelftestPolicy, circuit: Arc<CircuitBreaker>, concurrency: Arc<ConcurrencyLimit>) -> Result<PortforwardDescriptor, io::Error> {
+        let context = application.forward_context.as_ref().unwrap_or(&application.context);
+        let namespace = application.forward_namespace.as_ref().unwrap_or(&application.namespace);
+        let cmd = provider.port_forward(context, namespace, &application.application_name, service_port, local_port)?;
 
-        let mut cmd = Command::new("kubectl")
-            .args(&["port-forward",
-                "--context", application.context.as_str(),
-                "--namespace", application.namespace.as_str(),
-                format!("svc/{}", application.application_name.as_str()).as_str(), ":80"])
-            .stdout(Stdio::piped())
-            .spawn()
-            .unwrap();
+        Self::from_process(application, service_port, selftest, circuit, concurrency, cmd).await
+    }
 
-        let mut lines = BufReader::new(cmd.stdout.take().unwrap()).lines();
-        let line = lines.next_line().await?.unwrap();
-        let captures = regex.captures(line.as_str()).unwrap();
-        let host = captures[1].to_owned();
-        let port: usize = captures[2].parse().unwrap();
+    async fn from_process(application: &ApplicationDescriptor, service_port: &str, selftest: SelftestPolicy, circuit: Arc<CircuitBreaker>, concurrency: Arc<ConcurrencyLimit>, mut cmd: Child) -> Result<PortforwardDescriptor, io::Error> {
+        let stderr_lines = Arc::new(std::sync::Mutex::new(VecDeque::with_capacity(STDERR_LINES)));
+        let mut stdout = cmd.stdout.take().map(|stdout| BufReader::new(stdout).lines());
+        let mut stderr = cmd.stderr.take().map(|stderr| BufReader::new(stderr).lines());
+        // Some kubectl builds announce the forward on stderr, so both are read until one of them does
+        let (portforward, line) = loop {
+            if stdout.is_none() && stderr.is_none() {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Port-forward exited without forwarding"));
+            }
+            tokio::select! {
+                line = next_line(&mut stdout) => match line? {
+                    Some(line) => match parse_forwarding_line(&line) {
+                        Some(portforward) => break (portforward, line),
+                        None => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unexpected port-forward output: {}", line))),
+                    },
+                    None => stdout = None,
+                },
+                line = next_line(&mut stderr) => match line? {
+                    Some(line) => match parse_forwarding_line(&line) {
+                        Some(portforward) => break (portforward, line),
+                        None => record_stderr(&stderr_lines, line),
+                    },
+                    None => stderr = None,
+                },
+            }
+        };
 
-        println!("Opened a connection for {}:{} from {}", &host, &port, &line);
+        println!("Opened a connection for {} from {}", portforward.authority(), &line);
 
-        Ok(PortforwardDescriptor {
-            hosts: application.ingresses.clone(),
-            ttl: PortforwardDescriptor::create_ttl(),
-            port_forward_command: cmd,
-            client: Client::new(),
-            liveness: (&application).liveness.to_owned(),
-            stdout: tokio::spawn(async move {
+        let stderr = {
+            let stderr_lines = stderr_lines.clone();
+            async move {
+                if let Some(mut lines) = stderr {
+                    while let Ok(Some(line)) = lines.next_line().await {
+                        record_stderr(&stderr_lines, line);
+                    }
+                }
+            }
+        };
+        let stdout = async move {
+            if let Some(mut lines) = stdout {
                 while let Ok(Some(line)) = lines.next_line().await {
                     if !line.starts_with("Handling connection") {
                         println!("{}", line);
                     }
                 }
-            }),
-            portforward: Portforward {
-                host,
-                port,
-            },
+            }
+        };
+        let (output, output_abort) = abortable(async move {
+            join(stdout, stderr).await;
+        });
+
+        KUBECTL_PIDS.lock().unwrap().insert(cmd.id());
+        Ok(PortforwardDescriptor {
+            application_name: application.application_name.clone(),
+            hosts: application.ingresses_on_port(service_port),
+            service_port: service_port.to_owned(),
+            ttl: PortforwardDescriptor::create_ttl(),
+            opened_at: Instant::now(),
+            port_forward_command: cmd,
+            probe: HealthProbe { client: Client::new(), portforward: portforward.clone(), selftest },
+            // The liveness path belongs to the default port, other ports are only kept alive by their ttl
+            liveness: application.liveness.to_owned().filter(|_| service_port == DEFAULT_SERVICE_PORT),
+            readiness: application.readiness.to_owned().filter(|_| service_port == DEFAULT_SERVICE_PORT),
+            ready: Arc::new(Mutex::new(true)),
+            last_selftest: None,
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            circuit,
+            concurrency,
+            stderr_lines,
+            output: tokio::spawn(output),
+            output_abort,
+            portforward,
         })
     }
 
     async fn tick(&mut self) -> bool {
-        if !self.check_selftest().await {
+        let selftest = self.check_selftest().await;
+        self.last_selftest = Some(selftest);
+        if !selftest {
             println!("Failed selftest, marking connection for {:?} as dead", &self.hosts);
             return false;
         }
-        return self.ttl > SystemTime::now();
+        self.ttl > SystemTime::now()
+    }
+
+    /// Whether kubectl has exited on its own, reaping it if so
+    fn has_exited(&mut self) -> bool {
+        let exited = (&mut self.port_forward_command).now_or_never().is_some();
+        if exited {
+            // It has been reaped, so its pid may soon belong to an unrelated process
+            KUBECTL_PIDS.lock().unwrap().remove(&self.port_forward_command.id());
+        }
+        exited
     }
 
     async fn close(self) {
+        self.drain().await;
         println!("Closing port-forward for {:?}", self.hosts);
 
         PortforwardDescriptor::kill(self.port_forward_command).await;
-        self.stdout.await.unwrap();
+
+        // A hard killed kubectl can leave the pipe half-open, so don't wait for the end of its output forever
+        let mut output = self.output;
+        if timeout(Duration::from_secs(1), &mut output).await.is_err() {
+            println!("Output from port-forward did not close, aborting");
+            self.output_abort.abort();
+            let _ = output.await;
+        }
     }
 
-    #[cfg(unix)]
-    async fn kill(process: Child) {
-        let process_id = process.id();
-        let output = process.wait_with_output();
-        pin_mut!(output);
-        nix::sys::signal::kill(Pid::from_raw(process_id as _), nix::sys::signal::SIGINT);
-        if let Err(_) = timeout(Duration::from_secs(3), &mut output).await {
-            println!("Failed to sigint kubectl, killing");
-            nix::sys::signal::kill(Pid::from_raw(process_id as _), nix::sys::signal::SIGKILL);
+    /// Waits for the requests using the port-forward to finish, giving up after `DRAIN_TIMEOUT`
+    async fn drain(&self) {
+        let deadline = Instant::now() + DRAIN_TIMEOUT;
+        while self.in_flight.load(Ordering::SeqCst) > 0 {
+            if Instant::now() >= deadline {
+                println!("Closing port-forward for {:?} with {} requests still in flight", self.hosts,
+                         self.in_flight.load(Ordering::SeqCst));
+                return;
+            }
+            tokio::time::delay_for(Duration::from_millis(20)).await;
+        }
+    }
+
+    /// Closes the port-forward, in the background while requests are still using it so the state isn't locked
+    /// until they finish
+    async fn retire(self) {
+        if self.in_flight.load(Ordering::SeqCst) > 0 {
+            tokio::spawn(self.close());
+        } else {
+            self.close().await;
+        }
+    }
 
-            output.await.unwrap();
+    #[cfg(unix)]
+    async fn kill(mut process: Child) {
+        KUBECTL_PIDS.lock().unwrap().remove(&process.id());
+        // Polling the child reaps it if it already exited, after that its pid might belong to an unrelated process
+        if let Some(status) = (&mut process).now_or_never() {
+            println!("Port-forward had already exited with {:?}", status);
+            return;
+        }
+        let pid = Pid::from_raw(process.id() as _);
+        if PortforwardDescriptor::signal(pid, Signal::SIGINT)
+            && timeout(Duration::from_secs(3), &mut process).await.is_ok() {
+            println!("Closed port-forward.");
+            return;
+        }
+        println!("Failed to sigint the port-forward, killing it");
+        if !PortforwardDescriptor::signal(pid, Signal::SIGKILL) {
+            println!("Unable to kill the port-forward with pid {}, leaving it behind", pid);
+            return;
+        }
+        if let Err(e) = process.await {
+            println!("Failed to wait for the port-forward with pid {} to exit: {}", pid, e);
         }
         println!("Closed port-forward.");
     }
 
+    /// Sends a signal to kubectl, a process that no longer exists counts as a success
+    #[cfg(unix)]
+    fn signal(pid: Pid, signal: Signal) -> bool {
+        match nix::sys::signal::kill(pid, signal) {
+            Ok(()) => true,
+            Err(nix::Error::Sys(Errno::ESRCH)) => {
+                println!("The port-forward with pid {} has already exited", pid);
+                true
+            }
+            Err(e) => {
+                println!("Failed to send {:?} to the port-forward with pid {}: {}", signal, pid, e);
+                false
+            }
+        }
+    }
+
     #[cfg(not(unix))]
     async fn kill(mut process: Child) {
+        KUBECTL_PIDS.lock().unwrap().remove(&process.id());
         process.kill().unwrap();
         process.wait_with_output().await.unwrap();
     }
 
     async fn check_selftest(&self) -> bool {
-        if let Some(liveness) = &self.liveness {
-            let path = if liveness.starts_with("/") {
-                &liveness[1..]
-            } else {
-                liveness.as_str()
-            };
-            let uri = Uri::from_str(format!("http://{}:{}/{}", self.portforward.host, self.portforward.port, path).as_str());
-            println!("Running self-test towards {:?}", &uri);
-            let response = self.client.get(uri.unwrap()).await;
-            return match response {
-                Ok(response) => response.status().is_success(),
-                _ => false,
-            };
+        // Without a liveness check there is nothing to test, the port-forward is kept alive by its ttl
+        match &self.liveness {
+            Some(liveness) => self.probe.probe(liveness).await,
+            None => true,
         }
-        false
     }
 
-    fn contains_ingress(&self, ingress: &String) -> bool {
-        self.hosts.contains(ingress)
+    /// What tells whether the port-forward is ready: its readiness check, or else its liveness check
+    fn ready_check(&self) -> Option<HealthCheck> {
+        self.readiness.clone().or_else(|| self.liveness.clone())
+    }
+
+    fn contains_ingress(&self, ingress: &str) -> bool {
+        self.hosts.iter().any(|host| host == ingress)
     }
 
     fn update_ttl(&mut self) {
         self.ttl = Self::create_ttl();
     }
+
+    fn event(&self, event: EventKind, reason: impl Into<String>) -> Event {
+        Event {
+            event,
+            application: self.application_name.clone(),
+            ingresses: self.hosts.clone(),
+            local_port: self.portforward.port,
+            reason: reason.into(),
+        }
+    }
+
+    fn summary(&self) -> PortforwardSummary<'_> {
+        PortforwardSummary {
+            application: &self.application_name,
+            ingresses: &self.hosts,
+            local: &self.portforward,
+            ttl_seconds: self.ttl.duration_since(SystemTime::now()).map(|ttl| ttl.as_secs()).unwrap_or(0),
+            last_selftest: self.last_selftest,
+        }
+    }
+}
+
+/// A port-forward whose kubectl exited, being opened again in the background
+struct Reconnecting {
+    application_name: String,
+    hosts: Vec<String>,
+    abort: AbortHandle,
+}
+
+/// The outcome of reconnecting a port-forward, without a port-forward if every attempt failed
+struct Reconnected {
+    application_name: String,
+    hosts: Vec<String>,
+    portforward: Option<PortforwardDescriptor>,
+}
+
+#[derive(Serialize)]
+pub struct PortforwardSummary<'a> {
+    pub application: &'a str,
+    pub ingresses: &'a [String],
+    pub local: &'a Portforward,
+    pub ttl_seconds: u64,
+    pub last_selftest: Option<bool>,
 }
 
 pub struct State {
+    config: Arc<Config>,
+    provider: Arc<dyn ResourceProvider>,
     next_update: SystemTime,
     hosts: Vec<ApplicationDescriptor>,
+    /// The host given each address with --loopback-range, kept up to date with `hosts`
+    loopback_hosts: HashMap<IpAddr, String>,
     port_forwards: Vec<PortforwardDescriptor>,
+    reconnecting: Vec<Reconnecting>,
+    /// The local port each ingress was last forwarded on, reused when it is forwarded again
+    recorded_ports: HashMap<String, u16>,
+    reconnected_sender: mpsc::UnboundedSender<Reconnected>,
+    forward_rate: Option<TokenBucket>,
+    /// When the --forward-rate token reserved for opening a port-forward for each ingress is due
+    throttled: HashMap<String, Instant>,
+    warnings: Vec<String>,
+    reload: Arc<Notify>,
+    reconnected: mpsc::UnboundedReceiver<Reconnected>,
+    events: broadcast::Sender<Event>,
+    ready: bool,
 }
 
 impl ApplicationDescriptor {
-    fn create(resource: ApplicationResource, context: String, namespace: String) -> Self {
-        ApplicationDescriptor {
-            application_name: resource.metadata.name,
-            ingresses: resource.spec.ingresses.unwrap().clone(),
-            liveness: resource.spec.liveness.map(|v| v.path),
+    pub fn application_name(&self) -> &str {
+        &self.application_name
+    }
+
+    pub fn context(&self) -> &str {
+        &self.context
+    }
+
+    pub fn namespace(&self) -> &str {
+        &self.namespace
+    }
+
+    /// The context and namespace port-forwards are opened through when --forward-via points them elsewhere
+    fn forwarded_via(&self) -> Option<String> {
+        let context = self.forward_context.as_ref()?;
+        Some(format!("{}/{}", context, self.forward_namespace.as_ref().unwrap_or(&self.namespace)))
+    }
+
+    /// Creates a descriptor for an application, or `None` if it has no ingresses to route
+    fn create(resource: ApplicationResource, context: String, namespace: String) -> Option<Self> {
+        let (name, mut spec) = (resource.metadata.name, resource.spec);
+        let port = spec.port;
+        let reachable = |check: &HealthCheck| probed_on_application_port(&name, check, port);
+        let liveness = spec.liveness.filter(reachable);
+        let readiness = spec.readiness.filter(reachable);
+        let ingresses = spec.ingresses?.iter()
+            .map(|ingress| normalize_ingress(ingress))
+            .filter(|ingress| {
+                let valid = ingress_host(ingress).is_some();
+                if !valid {
+                    println!("Warning: Skipping ingress {:?} of {} in {}/{}, it has no host", ingress, name, context, namespace);
+                }
+                valid
+            })
+            .collect();
+        Some(ApplicationDescriptor {
+            ingresses,
+            application_name: name,
+            service_ports: std::mem::take(&mut spec.service_ports),
+            liveness,
+            readiness,
             context,
             namespace,
-        }
+            forward_context: None,
+            forward_namespace: None,
+        })
+    }
+    fn service_port(&self, ingress: &str) -> &str {
+        self.service_ports.iter()
+            .find(|(service_ingress, _)| service_ingress == ingress)
+            .map(|(_, port)| port.as_str())
+            .unwrap_or(DEFAULT_SERVICE_PORT)
+    }
+
+    fn ingresses_on_port(&self, service_port: &str) -> Vec<String> {
+        self.ingresses.iter()
+            .filter(|ingress| self.service_port(ingress) == service_port)
+            .cloned()
+            .collect()
     }
-    fn best_ingress(&self, host: &str, path: &str) -> Option<String> {
-        (&self.ingresses).into_iter()
+
+    /// Finds the most specific ingress matching the request, printing every attempt when `verbose` is set
+    fn best_ingress(&self, host: &str, path: &str, verbose: bool) -> Option<IngressMatch> {
+        self.ingresses.iter()
             .map(|pf| (Uri::from_str(pf.as_str()), pf))
             .filter(|(uri, _)| uri.is_ok())
             .map(|(uri, ingress)| (uri.unwrap(), ingress))
-            .filter(|(uri, _)| uri.host() == Some(host))
-            .filter(|(uri, _)| {
-                println!("matching {} with {}, outcome {}", uri.path(), path, uri.path().len());
-                path.starts_with(uri.path())
+            .filter_map(|(uri, ingress)| uri.host()
+                .and_then(|pattern| match_host(pattern, host))
+                .map(|exact_host| (uri, ingress, exact_host)))
+            .filter_map(|(uri, ingress, exact_host)| {
+                let outcome = match_path(uri.path(), path);
+                if verbose {
+                    println!("matching {} with {}, outcome {:?}", uri.path(), path, outcome);
+                }
+                outcome.map(|(path_length, exact_path)| IngressMatch {
+                    path_length,
+                    exact_path,
+                    exact_host,
+                    ingress: ingress.to_owned(),
+                })
             })
-            .map(|(_, ingress)| ingress.to_owned())
-            .max_by(|a, b| a.len().cmp(&b.len()))
+            .max()
+    }
+}
+
+/// An ingress matching a request, ordered so the most specific match is the greatest. Longer paths win, for
+/// equally long paths an exact path match wins over a prefix match, and then an exact host over a wildcard host.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct IngressMatch {
+    path_length: usize,
+    exact_path: bool,
+    exact_host: bool,
+    ingress: String,
+}
+
+/// Matches a request path against an ingress path on whole segments, ignoring trailing slashes so `/api` and
+/// `/api/` are equally specific. Returns the length of the normalized ingress path and whether the path matched
+/// exactly, or `None` if the path isn't below the ingress. The root ingress `/` matches every path with length 0, so
+/// it only catches what no other ingress on the host matches.
+fn match_path(ingress_path: &str, path: &str) -> Option<(usize, bool)> {
+    let ingress_normalized = ingress_path.trim_end_matches('/');
+    let normalized = path.trim_end_matches('/');
+    let below = normalized.strip_prefix(ingress_normalized)
+        .map(|rest| rest.is_empty() || rest.starts_with('/'))
+        .unwrap_or(false);
+    if below {
+        Some((ingress_normalized.len(), ingress_path == path))
+    } else {
+        None
+    }
+}
+
+/// Ingresses can be declared as just `host` or `host/path`, those are given the https scheme nais uses so they are
+/// parsed like any other ingress
+fn normalize_ingress(ingress: &str) -> String {
+    if ingress.contains("://") {
+        ingress.to_owned()
+    } else {
+        format!("https://{}", ingress)
+    }
+}
+
+/// Whether a health check is served on the application port. The service only forwards that port, so a check on
+/// another port can't be reached through the port-forward and is left out.
+fn probed_on_application_port(application: &str, check: &HealthCheck, port: Option<u16>) -> bool {
+    let port = port.unwrap_or(DEFAULT_APPLICATION_PORT);
+    let reachable = check.port.is_none_or(|check_port| check_port == port);
+    if !reachable {
+        println!("Not probing {} of {} on port {}, only port {} is reachable through the service", check.path, application,
+                 check.port.unwrap_or_default(), port);
+    }
+    reachable
+}
+
+fn is_wildcard(host: &str) -> bool {
+    host.starts_with("*.")
+}
+
+/// Names a context and namespace applications are discovered in, for messages
+fn discovery_target(context: &str, namespace: Option<&str>) -> String {
+    match namespace {
+        Some(namespace) => format!("{}/{}", context, namespace),
+        None => format!("{} (namespace from the kubeconfig)", context),
+    }
+}
+
+/// The host of an ingress, leaving out any port or query it was declared with, or `None` if it has no host
+fn ingress_host(ingress: &str) -> Option<String> {
+    INGRESS_HOST.captures(ingress).map(|captures| captures[1].to_owned())
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct Route<'a> {
+    pub ingress: &'a str,
+    pub host: String,
+    pub path: String,
+    pub application: &'a str,
+    pub namespace: &'a str,
+    pub context: &'a str,
+    pub service_port: &'a str,
+    /// The context and namespace the port-forwards are opened through, when not where the application was discovered
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub via: Option<String>,
+    pub liveness: Option<&'a str>,
+    pub readiness: Option<&'a str>,
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct KnownHost {
+    pub host: String,
+    pub context: String,
+    pub namespace: String,
+    pub via: Option<String>,
+}
+
+impl fmt::Display for KnownHost {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({}/{}", self.host, self.context, self.namespace)?;
+        if let Some(via) = &self.via {
+            write!(f, " via {}", via)?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// Matches a host against an ingress host, where a leading `*` label matches exactly one label.
+/// Returns whether the match was exact, or `None` if the host doesn't match.
+fn match_host(pattern: &str, host: &str) -> Option<bool> {
+    if pattern == host {
+        return Some(true);
+    }
+    let suffix = pattern.strip_prefix("*.")?;
+    match host.split_once('.') {
+        Some((label, rest)) if !label.is_empty() && rest == suffix => Some(false),
+        _ => None,
     }
 }
 
@@ -224,98 +911,2433 @@ impl State {
         SystemTime::now() + Duration::from_secs(120)
     }
 
-    pub async fn new(contexts: Vec<String>, namespaces: Vec<String>) -> Result<State, ForwardError> {
-        let descriptors = contexts.into_iter()
-            .flat_map(|context| (&namespaces).into_iter().map(move |namespace| (context.clone(), namespace.clone())))
-            .map(|(context, namespace)| Self::fetch_descriptors(context.clone(), namespace.clone()))
-            .collect::<FuturesOrdered<_>>()
-            .collect::<Vec<_>>().await
-            .into_iter()
-            .flatten()
-            .flatten()
-            .collect::<Vec<_>>();
-        Ok(State {
-            next_update: State::next_update(),
-            hosts: descriptors,
-            port_forwards: vec![],
-        })
+    pub async fn new(config: Arc<Config>) -> Result<State, ForwardError> {
+        let provider = Arc::new(CliProvider::new(&config));
+        Self::with_provider(config, provider).await
     }
 
-    async fn fetch_descriptors(context: String, namespace: String) -> Result<Vec<ApplicationDescriptor>, ForwardError> {
-        let cmd = Command::new("kubectl")
-            .args(&["--context", context.as_str(), "--namespace", namespace.as_str(), "get", "application", "-o", "json"])
-            .output()
-            .await
-            .context("Failed to execute kubectl get application")?;
-        if !cmd.status.success() {
-            let input = String::from_utf8(cmd.stderr).unwrap();
-            return Err(ForwardError {
-                message: "Failed to execute kubectl get application, got invalid exit code. Is navtunnel running?",
-                original: io::Error::new(io::ErrorKind::Other, input),
-            });
+    pub async fn with_provider(config: Arc<Config>, provider: Arc<dyn ResourceProvider>) -> Result<State, ForwardError> {
+        let (descriptors, complete) = Self::discover(&config, provider.as_ref()).await;
+        if complete {
+            Self::store_cache(&config, &descriptors);
         }
-        let resource = serde_json::from_slice::<KubernetesResponse>(&cmd.stdout)
-            .unwrap();
-        Ok(resource.items
-            .into_iter()
-            .filter(|application| application.spec.ingresses.is_some())
-            .map(|application| ApplicationDescriptor::create(application, context.clone(), namespace.clone()))
-            .collect())
+        Ok(Self::from_descriptors(config, provider, descriptors))
     }
 
-    pub fn hostnames(&self) -> Vec<String> {
-        let regex = Regex::new(r"https?://(.[^/]+)(:?/.*)?").unwrap();
-        let mut hosts: Vec<String> = (&self.hosts)
-            .into_iter()
-            .flat_map(|v| (&v.ingresses))
-            .map(|ingress| {
-                let captures = regex.captures(ingress.as_str()).unwrap();
-                captures[1].to_owned()
-            })
-            .collect();
-        hosts.sort();
-        hosts.dedup();
-        hosts
+    /// Discovers the applications like `new` without storing them in the cache, for commands only looking at them
+    pub async fn without_caching(config: Arc<Config>) -> State {
+        let provider = Arc::new(CliProvider::new(&config));
+        let (descriptors, _) = Self::discover(&config, provider.as_ref()).await;
+        Self::from_descriptors(config, provider, descriptors)
     }
 
-    pub async fn tick(&mut self) {
-        if self.next_update < SystemTime::now() {
-            self.next_update = State::next_update();
-        }
-        let mut new_portforwards = Vec::with_capacity(self.port_forwards.len());
-        while !self.port_forwards.is_empty() {
-            let mut pf = self.port_forwards.remove(self.port_forwards.len() - 1);
-            if pf.tick().await {
-                new_portforwards.push(pf);
-            } else {
-                pf.close().await;
-            }
-        }
-        self.port_forwards = new_portforwards;
+    /// Creates the state from the cache given by `--cache`, if there is a fresh one. The applications should be
+    /// refreshed with `refresh` afterwards.
+    pub fn from_cache(config: Arc<Config>) -> Option<State> {
+        let path = config.cache.as_ref()?;
+        let descriptors = cache::load(path, &Self::cache_key(&config), Duration::from_secs(config.cache_ttl))?;
+        println!("Loaded {} applications from {}", descriptors.len(), path.display());
+        let provider = Arc::new(CliProvider::new(&config));
+        Some(Self::from_descriptors(config, provider, descriptors))
     }
 
-    pub async fn fetch_address(&mut self, host: &String, path: &str) -> Result<Option<Portforward>, ForwardError> {
-        let info = (&self.hosts).into_iter()
-            .filter_map(|desc| (desc.best_ingress(host, path).map(|v| (v, desc))))
-            .max_by(|(a, _), (b, _)| a.len().cmp(&b.len()));
+    /// Creates the state without any applications, they are filled in by `refresh`
+    pub fn undiscovered(config: Arc<Config>) -> State {
+        let provider = Arc::new(CliProvider::new(&config));
+        Self::from_descriptors(config, provider, vec![])
+    }
 
-        let (ingress, app) = if let Some(info) = info {
-            info
-        } else {
-            return Ok(None);
+    /// Discovers the applications again without holding the lock meanwhile. Each context and namespace is merged in
+    /// as soon as it is done, so a slow or unreachable one doesn't hold up the others, and keeps its applications
+    /// from before until then. One that fails keeps them until the next refresh. `merged` is called with the state
+    /// after every merge.
+    pub async fn refresh(state: &Mutex<State>, mut merged: impl FnMut(&State)) {
+        let (config, provider, previous) = {
+            let state = state.lock().await;
+            (state.config.clone(), state.provider.clone(), state.hosts.clone())
         };
-        let mut desc = (&mut self.port_forwards).into_iter()
-            .find(|v| v.contains_ingress(&ingress));
-        if let Some(desc) = &mut desc {
-            desc.update_ttl();
-            Ok(Some((&desc.portforward).clone()))
-        } else {
-            let portforward_desc: PortforwardDescriptor = PortforwardDescriptor::from_app(&app)
+        let mut pending = config.discovery_targets()
+            .into_iter()
+            .map(|(context, namespace)| {
+                let (config, provider) = (&config, provider.as_ref());
+                async move {
+                    let result = Self::fetch_with_retry(config, provider, context.clone(), namespace.clone()).await;
+                    (context, namespace, result)
+                }
+            })
+            .collect::<FuturesUnordered<_>>();
+        let total = pending.len();
+        let mut finished = 0;
+        let mut failed = false;
+        let mut done = HashSet::new();
+        let mut discovered = vec![];
+        while let Some((context, namespace, result)) = pending.next().await {
+            finished += 1;
+            let target = discovery_target(&context, namespace.as_deref());
+            let found = match result {
+                Ok(found) => found,
+                // Not counted as done, so its applications from before stay and their port-forwards aren't closed
+                Err(_) => {
+                    failed = true;
+                    println!("Keeping the applications from before in {}, {} of {} namespaces done", target, finished, total);
+                    continue;
+                }
+            };
+            println!("Discovered {} applications in {}, {} of {} namespaces done", found.len(), target, finished, total);
+            discovered.extend(found);
+            done.insert((context, namespace));
+            let mut descriptors = previous.iter()
+                .filter(|app| !done.contains(&(app.context.clone(), Some(app.namespace.clone()))) && !done.contains(&(app.context.clone(), None)))
+                .cloned()
+                .chain(discovered.iter().cloned())
+                .collect::<Vec<_>>();
+            let warnings = Self::prepare_hosts(&mut descriptors, &config);
+            let mut state = state.lock().await;
+            // Every merged namespace finds the warnings of those before it again
+            for warning in warnings.iter().filter(|warning| !state.warnings.contains(warning)) {
+                println!("Warning: {}", warning);
+            }
+            state.hosts = descriptors;
+            state.assign_loopback_hosts();
+            state.warnings = warnings;
+            merged(&state);
+        }
+        if !failed {
+            Self::store_cache(&config, &discovered);
+        }
+        state.lock().await.close_vanished().await;
+    }
+
+    /// Asks for the applications to be discovered again right away. Requests made while a reload is running are
+    /// coalesced into one reload after it.
+    pub fn request_reload(&self) {
+        self.reload.notify();
+    }
+
+    /// Notified when a reload is requested, for the task running the reloads to wait on
+    pub fn reload_requests(&self) -> Arc<Notify> {
+        self.reload.clone()
+    }
+
+    /// Closes the port-forwards of ingresses no longer belonging to their application after a refresh
+    async fn close_vanished(&mut self) {
+        let hosts = &self.hosts;
+        let exists = |application: &str, ingresses: &[String]| hosts.iter().any(|app| app.application_name == application
+            && ingresses.iter().any(|ingress| app.ingresses.contains(ingress)));
+        self.reconnecting.retain(|reconnecting| {
+            let keep = exists(&reconnecting.application_name, &reconnecting.hosts);
+            if !keep {
+                reconnecting.abort.abort();
+            }
+            keep
+        });
+        let (vanished, kept): (Vec<_>, Vec<_>) = self.port_forwards.drain(..)
+            .partition(|pf| !exists(&pf.application_name, &pf.hosts));
+        self.port_forwards = kept;
+        if vanished.is_empty() {
+            return;
+        }
+        for pf in vanished {
+            self.publish(pf.event(EventKind::Closed, "The application or ingress is gone"));
+            pf.retire().await;
+        }
+        self.save_state_file();
+    }
+
+    fn from_descriptors(config: Arc<Config>, provider: Arc<dyn ResourceProvider>, mut descriptors: Vec<ApplicationDescriptor>) -> State {
+        let warnings = Self::prepare_hosts(&mut descriptors, &config);
+        for warning in &warnings {
+            println!("Warning: {}", warning);
+        }
+        let (reconnected_sender, reconnected) = mpsc::unbounded_channel();
+        let recorded_ports = config.state_file.as_deref().map(state_file::recorded_ports).unwrap_or_default();
+        let forward_rate = config.forward_rate.map(|rate| TokenBucket::new(rate, config.forward_burst));
+        let mut state = State {
+            config,
+            provider,
+            next_update: State::next_update(),
+            hosts: descriptors,
+            loopback_hosts: HashMap::new(),
+            port_forwards: vec![],
+            reconnecting: vec![],
+            recorded_ports,
+            reconnected_sender,
+            reconnected,
+            forward_rate,
+            throttled: HashMap::new(),
+            warnings,
+            reload: Arc::new(Notify::new()),
+            events: broadcast::channel(EVENT_BUFFER).0,
+            ready: false,
+        };
+        state.assign_loopback_hosts();
+        state
+    }
+
+    /// Whether the applications are discovered and written to the hosts file, so requests can be routed
+    pub fn is_ready(&self) -> bool {
+        self.ready
+    }
+
+    pub fn set_ready(&mut self) {
+        self.ready = true;
+    }
+
+    /// The problems found with the discovered applications, like ingresses claimed by several of them
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    fn cache_key(config: &Config) -> String {
+        format!("{:?} {:?} {:?}", config.discovery_targets(), config.resource_kind, config.selector)
+    }
+
+    /// Fetches the applications of every context and namespace, and tells whether all of them could be listed
+    async fn discover(config: &Config, provider: &dyn ResourceProvider) -> (Vec<ApplicationDescriptor>, bool) {
+        let results = config.discovery_targets()
+            .into_iter()
+            .map(|(context, namespace)| Self::fetch_with_retry(config, provider, context, namespace))
+            .collect::<FuturesOrdered<_>>()
+            .collect::<Vec<_>>().await;
+        let complete = results.iter().all(Result::is_ok);
+        (results.into_iter().flatten().flatten().collect(), complete)
+    }
+
+    /// Stores the applications of a discovery every context and namespace answered, a partial one would replace a good
+    /// cache with only the applications of those that did
+    fn store_cache(config: &Config, descriptors: &[ApplicationDescriptor]) {
+        // Nothing found usually means the clusters couldn't be reached, which shouldn't replace a good cache
+        if let (Some(path), false) = (&config.cache, descriptors.is_empty()) {
+            if let Err(e) = cache::store(path, &Self::cache_key(config), descriptors) {
+                println!("Failed to write cache {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    /// Returns the warnings about the discovered applications
+    fn prepare_hosts(hosts: &mut Vec<ApplicationDescriptor>, config: &Config) -> Vec<String> {
+        let warnings = Self::remove_duplicate_ingresses(hosts, &config.context_priority);
+        Self::assign_service_ports(hosts, &config.service_ports);
+        Self::assign_forward_via(hosts, &config.forward_via);
+        Self::filter_hosts(hosts, config);
+        warnings
+    }
+
+    /// Keeps an ingress claimed by several applications only on the first of them by --context-priority, namespace
+    /// and name, returning a warning for every ingress removed
+    fn remove_duplicate_ingresses(hosts: &mut Vec<ApplicationDescriptor>, context_priority: &[String]) -> Vec<String> {
+        // Contexts without a priority come after those with one
+        let rank = |app: &ApplicationDescriptor| context_priority.iter().position(|context| context == &app.context)
+            .unwrap_or(context_priority.len());
+        let mut order = (0..hosts.len()).collect::<Vec<_>>();
+        order.sort_by(|&a, &b| (rank(&hosts[a]), &hosts[a].namespace, &hosts[a].application_name, &hosts[a].context)
+            .cmp(&(rank(&hosts[b]), &hosts[b].namespace, &hosts[b].application_name, &hosts[b].context)));
+        let mut claimed: HashMap<String, usize> = HashMap::new();
+        let mut warnings = vec![];
+        for index in order {
+            let mut ingresses = std::mem::take(&mut hosts[index].ingresses);
+            ingresses.retain(|ingress| match claimed.get(ingress.trim_end_matches('/')) {
+                Some(&owner) if owner == index => false,
+                Some(&owner) if hosts[owner].context != hosts[index].context => {
+                    let (owner, other) = (&hosts[owner], &hosts[index]);
+                    warnings.push(format!("{} is claimed by both {} in {}/{} and {} in {}/{}, routing it to {}",
+                                          ingress, owner.application_name, owner.context, owner.namespace,
+                                          other.application_name, other.context, other.namespace, owner.context));
+                    false
+                }
+                Some(&owner) => {
+                    warnings.push(format!("{} is claimed by both {} in {} and {} in {}, routing it to {}",
+                                          ingress, hosts[owner].application_name, hosts[owner].namespace,
+                                          hosts[index].application_name, hosts[index].namespace, hosts[owner].application_name));
+                    false
+                }
+                None => {
+                    claimed.insert(ingress.trim_end_matches('/').to_owned(), index);
+                    true
+                }
+            });
+            hosts[index].ingresses = ingresses;
+        }
+        hosts.retain(|app| !app.ingresses.is_empty());
+        warnings
+    }
+
+    /// Points the port-forwards of applications named in --forward-via at the given context and namespace
+    fn assign_forward_via(hosts: &mut [ApplicationDescriptor], rules: &[ForwardVia]) {
+        for app in hosts.iter_mut() {
+            let rule = rules.iter().find(|rule| rule.discovered_in == app.context && rule.application == app.application_name);
+            app.forward_context = rule.map(|rule| rule.context.clone());
+            app.forward_namespace = rule.and_then(|rule| rule.namespace.clone());
+        }
+    }
+
+    /// Adds the ingress of each service port rule to the application that would otherwise serve it
+    fn assign_service_ports(hosts: &mut [ApplicationDescriptor], rules: &[ServicePortRule]) {
+        for rule in rules {
+            let uri = Uri::from_str(&rule.ingress).unwrap();
+            let host = uri.host().unwrap_or_default();
+            let best = hosts.iter()
+                .enumerate()
+                .filter_map(|(index, app)| app.best_ingress(host, uri.path(), false).map(|m| (m, index)))
+                .max()
+                .map(|(_, index)| index);
+            if let Some(index) = best {
+                let app = &mut hosts[index];
+                println!("Routing {} to port {} of {}", &rule.ingress, &rule.port, &app.application_name);
+                if !app.ingresses.contains(&rule.ingress) {
+                    app.ingresses.push(rule.ingress.clone());
+                }
+                // Applications prepared before, e.g. kept while refreshing, already have the rule
+                if !app.service_ports.iter().any(|(ingress, _)| ingress == &rule.ingress) {
+                    app.service_ports.push((rule.ingress.clone(), rule.port.clone()));
+                }
+            } else {
+                println!("No application found for service port rule {}", &rule.ingress);
+            }
+        }
+    }
+
+    /// Drops the ingresses with hosts excluded by --allow-host and --deny-host, and applications left without any
+    fn filter_hosts(hosts: &mut Vec<ApplicationDescriptor>, config: &Config) {
+        for app in hosts.iter_mut() {
+            app.ingresses.retain(|ingress| ingress_host(ingress).is_some_and(|host| config.manages_host(&host)));
+        }
+        hosts.retain(|app| !app.ingresses.is_empty());
+    }
+
+    /// Fetches the applications in a namespace, retrying with exponential backoff so a brief failure doesn't leave
+    /// out a whole context until the next refresh. An attempt that hangs, e.g. on an auth prompt, fails after
+    /// --discovery-timeout.
+    async fn fetch_with_retry(config: &Config, provider: &dyn ResourceProvider, context: String, namespace: Option<String>) -> Result<Vec<ApplicationDescriptor>, ForwardError> {
+        let target = discovery_target(&context, namespace.as_deref());
+        let mut delay = Duration::from_millis(config.discovery_backoff_ms);
+        let mut attempt = 1;
+        loop {
+            let fetch = Self::fetch_descriptors(provider, context.clone(), namespace.clone(), config.selector.as_deref());
+            let result = match timeout(Duration::from_secs(config.discovery_timeout), fetch).await {
+                Ok(result) => result,
+                Err(_) => Err(ForwardError {
+                    message: "Timed out discovering applications".into(),
+                    original: io::Error::new(io::ErrorKind::TimedOut, format!(
+                        "Listing applications in {} took more than {} seconds", target, config.discovery_timeout)),
+                    route: None,
+                    reconnecting: false,
+                }),
+            };
+            match result {
+                Err(e) if attempt < config.discovery_attempts => {
+                    println!("Discovering applications in {} failed ({}/{}): {}, retrying in {:?}",
+                             target, attempt, config.discovery_attempts, e, delay);
+                    tokio::time::delay_for(delay).await;
+                    delay *= 2;
+                    attempt += 1;
+                }
+                Err(e) => {
+                    println!("Giving up discovering applications in {}: {}", target, e);
+                    return Err(e);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Fetches the applications with ingresses in a namespace, limited to those matching the label selector if given
+    async fn fetch_descriptors(provider: &dyn ResourceProvider, context: String, namespace: Option<String>, selector: Option<&str>) -> Result<Vec<ApplicationDescriptor>, ForwardError> {
+        let applications = provider.applications(&context, namespace.as_deref(), selector).await?;
+        Ok(applications
+            .into_iter()
+            .filter_map(|application| {
+                // Listed in the namespace of the context, the applications tell which one that is. Contexts without one
+                // use the default namespace.
+                let namespace = namespace.clone()
+                    .or_else(|| application.metadata.namespace.clone())
+                    .unwrap_or_else(|| "default".to_owned());
+                ApplicationDescriptor::create(application, context.clone(), namespace)
+            })
+            .collect())
+    }
+
+    pub fn hostnames(&self) -> Vec<String> {
+        // There is no way to express a wildcard in the hosts file, those entries have to be added manually
+        for host in self.ingress_hosts().filter(|host| is_wildcard(host)) {
+            println!("Skipping hosts entry for wildcard ingress {}", host);
+        }
+        self.hosts_file_names()
+    }
+
+    /// The hosts written to the hosts file, together with the loopback address each of them is given
+    pub fn host_addresses(&self) -> Vec<(IpAddr, String)> {
+        hosts::assign_addresses(&self.hostnames(), self.config.loopback_range)
+    }
+
+    /// The host given the address with --loopback-range, if any
+    pub fn host_for_address(&self, address: IpAddr) -> Option<String> {
+        self.loopback_hosts.get(&address).cloned()
+    }
+
+    /// Works out the address of every host with --loopback-range once the hosts change, instead of on every request
+    fn assign_loopback_hosts(&mut self) {
+        self.loopback_hosts = match self.config.loopback_range {
+            Some(range) => hosts::assign_addresses(&self.hosts_file_names(), Some(range))
+                .into_iter()
+                // Hosts that didn't fit in the range share 127.0.0.1
+                .filter(|(address, _)| *address != IpAddr::from([127, 0, 0, 1]))
+                .collect(),
+            None => HashMap::new(),
+        };
+    }
+
+    fn ingress_hosts(&self) -> impl Iterator<Item = String> + '_ {
+        self.hosts.iter().flat_map(|v| &v.ingresses).filter_map(|ingress| ingress_host(ingress))
+    }
+
+    fn hosts_file_names(&self) -> Vec<String> {
+        let mut hosts: Vec<String> = self.ingress_hosts().filter(|host| !is_wildcard(host))
+            .chain(self.config.static_routes.iter().map(|route| route.host.clone()))
+            .collect();
+        hosts.sort();
+        hosts.dedup();
+        hosts
+    }
+
+    /// Lists the hosts of every ingress together with where its application lives, sorted and deduplicated
+    pub fn known_hosts(&self) -> Vec<KnownHost> {
+        let mut hosts: Vec<KnownHost> = self.hosts
+            .iter()
+            .flat_map(|app| app.ingresses.iter().filter_map(move |ingress| Some(KnownHost {
+                host: ingress_host(ingress)?,
+                context: app.context.clone(),
+                namespace: app.namespace.clone(),
+                via: app.forwarded_via(),
+            })))
+            .collect();
+        hosts.sort();
+        hosts.dedup();
+        hosts
+    }
+
+    /// Lists every ingress with where requests for it are routed
+    pub fn routes(&self) -> Vec<Route<'_>> {
+        self.hosts.iter()
+            .flat_map(|app| app.ingresses.iter().map(move |ingress| {
+                let uri = Uri::from_str(ingress).ok();
+                Route {
+                    ingress,
+                    host: uri.as_ref().and_then(Uri::host).unwrap_or_default().to_owned(),
+                    path: uri.as_ref().map(Uri::path).unwrap_or("/").to_owned(),
+                    application: &app.application_name,
+                    namespace: &app.namespace,
+                    context: &app.context,
+                    service_port: app.service_port(ingress),
+                    via: app.forwarded_via(),
+                    liveness: app.liveness.as_ref().map(|check| check.path.as_str()),
+                    readiness: app.readiness.as_ref().map(|check| check.path.as_str()),
+                }
+            }))
+            .collect()
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    fn publish(&self, event: Event) {
+        // Sending only fails when nobody is subscribed
+        let _ = self.events.send(event);
+    }
+
+    /// The last lines the port-forward wrote to stderr, or nothing if it has been closed
+    pub fn stderr_lines(&self, portforward: &Portforward) -> Vec<String> {
+        self.port_forwards.iter()
+            .find(|pf| &pf.portforward == portforward)
+            .map(|pf| pf.stderr_lines.lock().unwrap().iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn port_forwards(&self) -> impl Iterator<Item = PortforwardSummary<'_>> {
+        self.port_forwards.iter().map(PortforwardDescriptor::summary)
+    }
+
+    /// Writes the open port-forwards to --state-file, if set
+    fn save_state_file(&self) {
+        if let Some(path) = &self.config.state_file {
+            if let Err(e) = state_file::store(path, self.port_forwards(), &self.recorded_ports) {
+                println!("Failed to write state file {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    /// Closes all port-forwards for the given application, returning how many were closed
+    pub async fn close_port_forwards(&mut self, application: &str) -> usize {
+        let (closing, open): (Vec<_>, Vec<_>) = self.port_forwards.drain(..)
+            .partition(|pf| pf.application_name == application);
+        self.port_forwards = open;
+        self.reconnecting.retain(|reconnecting| {
+            let keep = reconnecting.application_name != application;
+            if !keep {
+                reconnecting.abort.abort();
+            }
+            keep
+        });
+        let closed = closing.len();
+        for pf in closing {
+            self.publish(pf.event(EventKind::Closed, "Closed through the admin endpoint"));
+            pf.close().await;
+        }
+        if closed > 0 {
+            self.save_state_file();
+        }
+        closed
+    }
+
+    /// Closes every port-forward at the same time, giving up on those that haven't closed within the limit. Returns
+    /// whether they all closed in time.
+    pub async fn close_all(&mut self, limit: Duration) -> bool {
+        for reconnecting in self.reconnecting.drain(..) {
+            reconnecting.abort.abort();
+        }
+        let closing = self.port_forwards.drain(..).collect::<Vec<_>>();
+        for pf in &closing {
+            self.publish(pf.event(EventKind::Closed, "Shutting down"));
+        }
+        // Their local ports stay remembered next to the state file, so the next start can open them on the same ones
+        self.save_state_file();
+        let count = closing.len();
+        if timeout(limit, join_all(closing.into_iter().map(PortforwardDescriptor::close))).await.is_err() {
+            println!("Not all of {} port-forwards closed within {:?}, some {} processes may be left behind", count, limit, self.config.cli.program());
+            return false;
+        }
+        true
+    }
+
+    pub async fn tick(&mut self) {
+        if self.next_update < SystemTime::now() {
+            self.next_update = State::next_update();
+        }
+        self.collect_reconnected();
+        let open = self.port_forwards.len();
+        let mut running = Vec::with_capacity(open);
+        for mut pf in std::mem::take(&mut self.port_forwards) {
+            if pf.has_exited() {
+                self.reconnect(pf).await;
+            } else {
+                running.push(pf);
+            }
+        }
+        // Selftests run concurrently so one slow backend doesn't hold up the others, each check owns its port-forward
+        let selftests = Semaphore::new(CONCURRENT_SELFTESTS);
+        let checked = join_all(running.into_iter().map(|mut pf| {
+            let expired = self.past_max_lifetime(&pf);
+            let selftests = &selftests;
+            async move {
+                if expired {
+                    return (pf, None);
+                }
+                let _permit = selftests.acquire().await;
+                let keep = pf.tick().await;
+                (pf, Some(keep))
+            }
+        })).await;
+        let mut new_portforwards = Vec::with_capacity(open);
+        for (pf, keep) in checked {
+            match keep {
+                Some(true) => new_portforwards.push(pf),
+                None => {
+                    self.publish(pf.event(EventKind::Closed, "Reached its maximum lifetime"));
+                    pf.retire().await;
+                }
+                Some(false) => {
+                    if pf.last_selftest == Some(false) {
+                        self.publish(pf.event(EventKind::SelftestFailed, "Liveness check failed"));
+                        self.publish(pf.event(EventKind::Closed, "Liveness check failed"));
+                    } else {
+                        self.publish(pf.event(EventKind::Closed, "Unused until its ttl expired"));
+                    }
+                    pf.retire().await;
+                }
+            }
+        }
+        self.port_forwards = new_portforwards;
+        if self.port_forwards.len() != open {
+            self.save_state_file();
+        }
+    }
+
+    /// Opens the port-forward of an exited kubectl again in the background, on the same local port. Requests for its
+    /// ingresses are turned away until it is back.
+    async fn reconnect(&mut self, pf: PortforwardDescriptor) {
+        println!("Port-forward for {:?} exited, reconnecting", pf.hosts);
+        self.publish(pf.event(EventKind::Closed, format!("{} exited, reconnecting", self.config.cli.program())));
+        let application = self.hosts.iter()
+            .find(|app| app.application_name == pf.application_name && pf.hosts.iter().any(|host| app.ingresses.contains(host)))
+            .cloned();
+        let (application_name, hosts, service_port) = (pf.application_name.clone(), pf.hosts.clone(), pf.service_port.clone());
+        let local_port = Some(pf.portforward.port as u16);
+        // The reconnected port-forward keeps counting failures where this one left off, and requests still waiting for
+        // their turn keep their place
+        let (circuit, concurrency) = (pf.circuit.clone(), pf.concurrency.clone());
+        pf.retire().await;
+        // The application is gone if it disappeared when the applications were refreshed
+        let application = match application {
+            Some(application) => application,
+            None => return,
+        };
+        let (provider, selftest, sender) = (self.provider.clone(), SelftestPolicy::new(&self.config), self.reconnected_sender.clone());
+        let (reopen, abort) = abortable(Self::reopen(provider, application, service_port, local_port, selftest, circuit, concurrency));
+        let (name, ingresses) = (application_name.clone(), hosts.clone());
+        tokio::spawn(async move {
+            if let Ok(portforward) = reopen.await {
+                // The receiver only goes away with the state
+                let _ = sender.send(Reconnected { application_name: name, hosts: ingresses, portforward });
+            }
+        });
+        self.reconnecting.push(Reconnecting { application_name, hosts, abort });
+    }
+
+    /// Tries opening the port-forward `RECONNECT_ATTEMPTS` times, backing off between attempts
+    async fn reopen(provider: Arc<dyn ResourceProvider>, application: ApplicationDescriptor, service_port: String, local_port: Option<u16>, selftest: SelftestPolicy, circuit: Arc<CircuitBreaker>, concurrency: Arc<ConcurrencyLimit>) -> Option<PortforwardDescriptor> {
+        let mut backoff = RECONNECT_BACKOFF;
+        for attempt in 1..=RECONNECT_ATTEMPTS {
+            match PortforwardDescriptor::from_app(provider.as_ref(), &application, &service_port, local_port, selftest.clone(), circuit.clone(), concurrency.clone()).await {
+                Ok(portforward) => return Some(portforward),
+                Err(e) => println!("Reconnecting {} failed, attempt {} of {}: {}", application.application_name, attempt, RECONNECT_ATTEMPTS, e),
+            }
+            if attempt < RECONNECT_ATTEMPTS {
+                tokio::time::delay_for(backoff).await;
+                backoff *= 2;
+            }
+        }
+        None
+    }
+
+    /// Takes in the port-forwards reconnected in the background since last time
+    fn collect_reconnected(&mut self) {
+        while let Ok(reconnected) = self.reconnected.try_recv() {
+            let position = self.reconnecting.iter()
+                .position(|r| r.application_name == reconnected.application_name && r.hosts == reconnected.hosts);
+            match (position, reconnected.portforward) {
+                (Some(position), Some(portforward)) => {
+                    self.reconnecting.remove(position);
+                    self.publish(portforward.event(EventKind::Opened, format!("Reconnected after {} exited", self.config.cli.program())));
+                    self.port_forwards.push(portforward);
+                    self.save_state_file();
+                }
+                (Some(position), None) => {
+                    self.reconnecting.remove(position);
+                    println!("Gave up reconnecting {:?}, the next request opens a new port-forward", reconnected.hosts);
+                }
+                // Closed while it was reconnecting
+                (None, Some(portforward)) => {
+                    tokio::spawn(portforward.close());
+                }
+                (None, None) => {}
+            }
+        }
+    }
+
+    fn circuit_breaker(&self) -> Arc<CircuitBreaker> {
+        Arc::new(CircuitBreaker::new(self.config.circuit_breaker_failures, Duration::from_secs(self.config.circuit_breaker_cooldown)))
+    }
+
+    fn concurrency_limit(&self) -> Arc<ConcurrencyLimit> {
+        Arc::new(ConcurrencyLimit::new(self.config.max_concurrent_requests, self.config.request_queue))
+    }
+
+    /// Picks the port the ingress was last forwarded on if it is free, or else the first free port of --local-ports,
+    /// or `None` to let kubectl pick one
+    fn allocate_local_port(&self, ingress: &str) -> Result<Option<u16>, ForwardError> {
+        if let Some(port) = self.recorded_ports.get(ingress).copied().filter(|&port| self.is_free(port)) {
+            return Ok(Some(port));
+        }
+        let local_ports = match self.config.local_ports {
+            Some(local_ports) => local_ports,
+            None => return Ok(None),
+        };
+        (local_ports.first..=local_ports.last)
+            .find(|&port| self.is_free(port))
+            .map(Some)
+            .ok_or_else(|| ForwardError {
+                message: "No free local port for the port-forward, all of --local-ports are in use".into(),
+                original: io::Error::new(io::ErrorKind::AddrInUse,
+                                         format!("ports {}-{} are in use", local_ports.first, local_ports.last)),
+                route: None,
+                reconnecting: false,
+            })
+    }
+
+    /// Whether no port-forward uses the local port and it can be bound
+    fn is_free(&self, port: u16) -> bool {
+        let address = self.config.forward_address.unwrap_or_else(|| IpAddr::from([127, 0, 0, 1]));
+        !self.port_forwards.iter().any(|pf| pf.portforward.port == port as usize)
+            && std::net::TcpListener::bind((address, port)).is_ok()
+    }
+
+    /// Whether the port-forward has been open longer than --forward-max-lifetime, however much it is used
+    fn past_max_lifetime(&self, pf: &PortforwardDescriptor) -> bool {
+        self.config.forward_max_lifetime
+            .is_some_and(|max_lifetime| pf.opened_at.elapsed() >= Duration::from_secs(max_lifetime))
+    }
+
+    fn find_application<'a>(hosts: &'a [ApplicationDescriptor], host: &str, path: &str, verbose: bool) -> Option<(IngressMatch, &'a ApplicationDescriptor)> {
+        hosts.iter()
+            .filter_map(|desc| desc.best_ingress(host, path, verbose).map(|v| (v, desc)))
+            .max_by(|(a, _), (b, _)| a.cmp(b))
+    }
+
+    /// Where a request for the host and path would be forwarded to, without opening a port-forward. Static routes
+    /// come first, as in `fetch_address`.
+    pub fn resolve(&self, host: &str, path: &str) -> Option<Resolved<'_>> {
+        if let Some(route) = StaticRoute::find(&self.config.static_routes, host) {
+            return Some(Resolved::Static(route));
+        }
+        Self::find_application(&self.hosts, host, path, self.config.verbose_matching).map(|(_, app)| Resolved::Application(app))
+    }
+
+    /// Opens a port-forward for the ingress. Should kubectl hand out the address of another port-forward, e.g. a
+    /// recorded local port taken over by it, that one has lost the address to the new one and is closed, so requests
+    /// for the two aren't mixed up.
+    async fn open_replacing_stale(&mut self, app: &ApplicationDescriptor, ingress: &str, local_port: Option<u16>) -> io::Result<PortforwardDescriptor> {
+        let desc = PortforwardDescriptor::from_app(self.provider.as_ref(), app, app.service_port(ingress), local_port, SelftestPolicy::new(&self.config), self.circuit_breaker(), self.concurrency_limit()).await?;
+        if let Some(position) = self.port_forwards.iter().position(|other| other.portforward == desc.portforward) {
+            let stale = self.port_forwards.remove(position);
+            println!("Port-forward for {} got {}, which {:?} used, closing that one", ingress, desc.portforward.authority(), stale.hosts);
+            for host in &stale.hosts {
+                self.recorded_ports.remove(host);
+            }
+            self.publish(stale.event(EventKind::Closed, format!("Lost its address to the port-forward for {}", ingress)));
+            stale.retire().await;
+            self.save_state_file();
+        }
+        Ok(desc)
+    }
+
+    /// How long a request has to wait for --forward-rate before a port-forward is opened for it. The token is reserved
+    /// right away so requests get their turn in order, and requests for the same ingress share it.
+    fn forward_wait(&mut self, host: &str, path: &str) -> Duration {
+        if self.forward_rate.is_none() || StaticRoute::find(&self.config.static_routes, host).is_some() {
+            return Duration::from_secs(0);
+        }
+        let (ingress, application_name) = match Self::find_application(&self.hosts, host, path, false) {
+            Some((ingress_match, app)) => (ingress_match.ingress, app.application_name.clone()),
+            None => return Duration::from_secs(0),
+        };
+        let reconnecting = self.reconnecting.iter().any(|r| r.application_name == application_name && r.hosts.contains(&ingress));
+        let open = self.port_forwards.iter()
+            .any(|v| v.application_name == application_name && v.contains_ingress(&ingress) && !self.past_max_lifetime(v));
+        if reconnecting || open {
+            return Duration::from_secs(0);
+        }
+        let now = Instant::now();
+        let forward_rate = self.forward_rate.as_mut().unwrap();
+        let due = *self.throttled.entry(ingress).or_insert_with(|| now + forward_rate.reserve());
+        due.saturating_duration_since(now)
+    }
+
+    /// Closes the port-forward that didn't pass its readiness check
+    async fn close_unready(&mut self, ready: &Arc<Mutex<bool>>) {
+        if let Some(position) = self.port_forwards.iter().position(|pf| Arc::ptr_eq(&pf.ready, ready)) {
+            let pf = self.port_forwards.remove(position);
+            self.publish(pf.event(EventKind::Closed, "Did not become ready in time"));
+            // Other requests waiting for it to become ready may still hold leases, so it drains in the background
+            pf.retire().await;
+            self.save_state_file();
+        }
+    }
+
+    /// Finds the port-forward for a request, opening one if needed. Doesn't wait for --forward-rate, which
+    /// `fetch_address` does before calling this.
+    pub async fn fetch_address(&mut self, host: &str, path: &str) -> Result<Option<ForwardLease>, ForwardError> {
+        self.collect_reconnected();
+        // Static routes need neither kubectl nor any upkeep, every request gets a lease of its own
+        if let Some(route) = StaticRoute::find(&self.config.static_routes, host) {
+            let portforward = Portforward { host: route.target_host.clone(), port: route.target_port as usize };
+            let (in_flight, circuit) = (Arc::new(AtomicUsize::new(0)), Arc::new(CircuitBreaker::new(None, Duration::from_secs(0))));
+            let (concurrency, ready) = (Arc::new(ConcurrencyLimit::new(None, 0)), Arc::new(Mutex::new(true)));
+            return Ok(Some(ForwardLease::new(portforward, &format!("https://{}", route.host), &in_flight, &circuit, &concurrency, &ready)));
+        }
+        let (ingress, app) = if let Some((ingress_match, app)) = Self::find_application(&self.hosts, host, path, self.config.verbose_matching) {
+            (ingress_match.ingress, app.clone())
+        } else {
+            return Ok(None);
+        };
+        let route = format!("{} ({} in {}/{})", ingress, app.application_name, app.context, app.namespace);
+        if self.reconnecting.iter().any(|r| r.application_name == app.application_name && r.hosts.contains(&ingress)) {
+            return Err(ForwardError {
+                message: format!("Port-forward is reconnecting after {} exited", self.config.cli.program()).into(),
+                original: io::Error::new(io::ErrorKind::NotConnected, format!("{} is reconnecting", ingress)),
+                route: Some(route),
+                reconnecting: true,
+            });
+        }
+        // Applications can share a host and only differ by path, so the forward is found by the matched ingress
+        let position = self.port_forwards.iter()
+            .position(|v| v.application_name == app.application_name && v.contains_ingress(&ingress));
+        if let Some(position) = position.filter(|&position| self.past_max_lifetime(&self.port_forwards[position])) {
+            let pf = self.port_forwards.remove(position);
+            self.publish(pf.event(EventKind::Closed, "Reached its maximum lifetime"));
+            pf.retire().await;
+            self.save_state_file();
+        }
+        let mut desc = self.port_forwards.iter_mut()
+            .find(|v| v.application_name == app.application_name && v.contains_ingress(&ingress));
+        if let Some(desc) = &mut desc {
+            desc.update_ttl();
+            Ok(Some(ForwardLease::new(desc.portforward.clone(), &ingress, &desc.in_flight, &desc.circuit, &desc.concurrency, &desc.ready)))
+        } else {
+            // Requests for the same ingress wait on the lock meanwhile, and find the port-forward once it is opened
+            self.throttled.remove(&ingress);
+            let local_port = self.allocate_local_port(&ingress).map_err(|e| e.for_route(&route))?;
+            let portforward_desc = self.open_replacing_stale(&app, &ingress, local_port)
                 .await
-                .context("Could not open port-forward. Are you still connected to navtunnel?")?;
-            let portforward = portforward_desc.portforward.clone();
+                .context("Could not open port-forward. Are you still connected to navtunnel?")
+                .map_err(|e| e.for_route(&route))?;
+            let mut portforward = ForwardLease::new(portforward_desc.portforward.clone(), &ingress, &portforward_desc.in_flight,
+                                                    &portforward_desc.circuit, &portforward_desc.concurrency, &portforward_desc.ready);
+            // The check is probed by `fetch_address` after letting go of the state, it can take --ready-timeout
+            if let Some(check) = portforward_desc.ready_check().filter(|_| self.config.wait_for_ready) {
+                portforward.pending = Some(PendingReadiness {
+                    ready: portforward_desc.ready.clone().try_lock_owned().expect("Nobody else knows the port-forward yet"),
+                    probe: portforward_desc.probe.clone(),
+                    check,
+                    timeout: Duration::from_secs(self.config.ready_timeout),
+                    route,
+                });
+            }
+            for host in &portforward_desc.hosts {
+                self.recorded_ports.insert(host.clone(), portforward_desc.portforward.port as u16);
+            }
+            self.publish(portforward_desc.event(EventKind::Opened, format!("Request for {}", ingress)));
             self.port_forwards.push(portforward_desc);
+            self.save_state_file();
             Ok(Some(portforward))
         }
     }
 }
+
+/// Finds the port-forward for a request, opening one if needed. Waiting for --forward-rate and --wait-for-ready
+/// happens without holding the state, so requests to port-forwards that are already open are served meanwhile.
+pub async fn fetch_address(state: &Mutex<State>, host: &str, path: &str) -> Result<Option<ForwardLease>, ForwardError> {
+    let wait = state.lock().await.forward_wait(host, path);
+    if wait > Duration::from_secs(0) {
+        tokio::time::delay_for(wait).await;
+    }
+    let mut lease = match state.lock().await.fetch_address(host, path).await? {
+        Some(lease) => lease,
+        None => return Ok(None),
+    };
+    match lease.wait_until_ready().await {
+        Ok(()) => Ok(Some(lease)),
+        Err(route) => {
+            let (ready, ingress) = (lease.ready.clone(), lease.ingress.clone());
+            // Without this lease the port-forward closes right away unless other requests still hold one
+            drop(lease);
+            if route.is_some() {
+                state.lock().await.close_unready(&ready).await;
+            }
+            Err(ForwardError {
+                message: "Port-forward did not become ready in time".into(),
+                original: io::Error::new(io::ErrorKind::TimedOut, format!("{} did not pass its readiness check", ingress)),
+                route,
+                reconnecting: false,
+            })
+        }
+    }
+}
+
+/// Closes every port-forward for shutting down, giving up after `deadline` even when a stuck request holds on to
+/// the state. Returns whether it was done in time.
+pub async fn shut_down(state: &Mutex<State>, deadline: Duration) -> bool {
+    let started = Instant::now();
+    timeout(deadline, async {
+        let mut state = state.lock().await;
+        state.close_all(deadline.saturating_sub(started.elapsed())).await
+    }).await.unwrap_or(false)
+}
+
+/// Kills the kubectl processes of every port-forward right away, for when shutting down is stuck and autoforward exits
+/// without closing them
+pub fn kill_port_forwards() {
+    for pid in KUBECTL_PIDS.lock().unwrap().drain() {
+        println!("Killing the port-forward with pid {}", pid);
+        #[cfg(unix)]
+        PortforwardDescriptor::signal(Pid::from_raw(pid as _), Signal::SIGKILL);
+        #[cfg(not(unix))]
+        if let Err(e) = std::process::Command::new("taskkill").args(["/F", "/PID", &pid.to_string()]).output() {
+            println!("Failed to kill the port-forward with pid {}: {}", pid, e);
+        }
+    }
+}
+
+/// Opens port-forwards for the --warmup hosts the way a request would, one at a time so requests get their turn
+pub async fn warmup(state: Arc<Mutex<State>>) {
+    let hosts = state.lock().await.config.warmup.clone();
+    for host in hosts {
+        match fetch_address(&state, &host, "/").await {
+            Ok(Some(_)) => println!("Warmed up {}", host),
+            Ok(None) => println!("Could not warm up {}: no application has it as ingress", host),
+            Err(e) => println!("Could not warm up {}: {}", host, e),
+        }
+    }
+}
+
+/// The next line of output, or never once the output has ended
+async fn next_line<R: AsyncBufRead + Unpin>(lines: &mut Option<Lines<R>>) -> io::Result<Option<String>> {
+    match lines {
+        Some(lines) => lines.next_line().await,
+        None => futures_util::future::pending().await,
+    }
+}
+
+/// Echoes a line kubectl wrote to stderr, keeping the last `STDERR_LINES` of them
+fn record_stderr(stderr_lines: &std::sync::Mutex<VecDeque<String>>, line: String) {
+    eprintln!("{}", line);
+    let mut stderr_lines = stderr_lines.lock().unwrap();
+    if stderr_lines.len() == STDERR_LINES {
+        stderr_lines.pop_front();
+    }
+    stderr_lines.push_back(line);
+}
+
+/// Adds up to a tenth of the interval at random, so instances sharing backends don't probe them in lockstep
+fn jittered(interval: Duration) -> Duration {
+    interval + interval.mul_f64(rand::thread_rng().gen_range(0.0, 0.1))
+}
+
+/// Ticks the state forever, closing dead and expired port-forwards
+pub async fn run_maintenance(state: Arc<Mutex<State>>, interval: Duration) {
+    loop {
+        state.lock().await.tick().await;
+        tokio::time::delay_for(jittered(interval)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Most of the tests run kubectl stand-ins through sh, leaving their helpers unused elsewhere
+    #![cfg_attr(not(unix), allow(dead_code, unused_imports))]
+
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::process::Stdio;
+
+    use hyper::{Body, Response, Server, StatusCode};
+    use futures_util::future::BoxFuture;
+    use hyper::service::{make_service_fn, service_fn};
+    use structopt::StructOpt;
+    use tokio::process::Command;
+
+    use crate::cluster::ClusterCli;
+
+    use super::*;
+
+    /// Starts a backend answering with the given statuses in order, repeating the last one
+    fn backend(statuses: Vec<StatusCode>) -> SocketAddr {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap())
+            .serve(make_service_fn(move |_| {
+                let (requests, statuses) = (requests.clone(), statuses.clone());
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |_| {
+                        let request = requests.fetch_add(1, Ordering::SeqCst);
+                        let status = statuses[request.min(statuses.len() - 1)];
+                        async move { Ok::<_, Infallible>(Response::builder().status(status).body(Body::empty()).unwrap()) }
+                    }))
+                }
+            }));
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    /// Starts a backend that takes `delay` to answer every request
+    fn slow_backend(delay: Duration) -> SocketAddr {
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap())
+            .serve(make_service_fn(move |_| async move {
+                Ok::<_, Infallible>(service_fn(move |_| async move {
+                    tokio::time::delay_for(delay).await;
+                    Ok::<_, Infallible>(Response::new(Body::empty()))
+                }))
+            }));
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    fn application() -> ApplicationDescriptor {
+        ApplicationDescriptor {
+            application_name: "speil".to_owned(),
+            ingresses: vec!["https://speil.nais.preprod.local".to_owned()],
+            service_ports: vec![],
+            liveness: None,
+            readiness: None,
+            context: "dev-fss".to_owned(),
+            namespace: "default".to_owned(),
+            forward_context: None,
+            forward_namespace: None,
+        }
+    }
+
+    fn no_circuit_breaker() -> Arc<CircuitBreaker> {
+        Arc::new(CircuitBreaker::new(None, Duration::from_secs(0)))
+    }
+
+    fn no_concurrency_limit() -> Arc<ConcurrencyLimit> {
+        Arc::new(ConcurrencyLimit::new(None, 0))
+    }
+
+    async fn fake_port_forward(application: &ApplicationDescriptor, port: usize) -> PortforwardDescriptor {
+        let cmd = Command::new("sh")
+            .args(["-c", format!("echo 'Forwarding from 127.0.0.1:{} -> 80'; exec sleep 10", port).as_str()])
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        PortforwardDescriptor::from_process(application, DEFAULT_SERVICE_PORT, SelftestPolicy::default(), no_circuit_breaker(), no_concurrency_limit(), cmd).await.unwrap()
+    }
+
+    /// A port-forward the fake provider was asked to open
+    struct ForwardCall {
+        args: Vec<String>,
+        local_port: Option<u16>,
+        at: Instant,
+    }
+
+    /// Stands in for kubectl, listing the applications it is given and pretending to forward
+    #[derive(Default)]
+    struct FakeProvider {
+        /// Applications by name and ingress, which can change between refreshes
+        applications: std::sync::Mutex<Vec<(&'static str, &'static str)>>,
+        /// Applications listed in a context instead of `applications`
+        by_context: HashMap<&'static str, Vec<(&'static str, &'static str)>>,
+        /// Contexts that never finish listing applications, like kubectl waiting for a login
+        hanging: Vec<&'static str>,
+        /// Contexts failing to list applications, like an unreachable cluster
+        failing: std::sync::Mutex<Vec<&'static str>>,
+        /// How many times listing applications fails before it succeeds
+        failures: usize,
+        attempts: AtomicUsize,
+        /// Local ports to forward from in order, whatever port was asked for
+        ports: std::sync::Mutex<VecDeque<u16>>,
+        failing_forwards: bool,
+        forwards: std::sync::Mutex<Vec<ForwardCall>>,
+    }
+
+    impl FakeProvider {
+        fn listing(applications: Vec<(&'static str, &'static str)>) -> FakeProvider {
+            FakeProvider { applications: std::sync::Mutex::new(applications), ..FakeProvider::default() }
+        }
+
+        fn requested_ports(&self) -> Vec<Option<u16>> {
+            self.forwards.lock().unwrap().iter().map(|call| call.local_port).collect()
+        }
+    }
+
+    impl ResourceProvider for FakeProvider {
+        fn applications(&self, context: &str, _namespace: Option<&str>, _selector: Option<&str>) -> BoxFuture<'static, Result<Vec<ApplicationResource>, ForwardError>> {
+            if self.hanging.contains(&context) {
+                return futures_util::future::pending().boxed();
+            }
+            let failing = self.failing.lock().unwrap().contains(&context);
+            let result = if self.attempts.fetch_add(1, Ordering::SeqCst) < self.failures || failing {
+                Err(ForwardError { message: "Unable to connect to the server".into(), original: io::Error::other("timeout"), route: None, reconnecting: false })
+            } else {
+                let applications = self.applications.lock().unwrap();
+                Ok(self.by_context.get(context).unwrap_or(&applications).iter()
+                    .map(|(name, ingress)| serde_json::from_value(serde_json::json!({
+                        "metadata": { "name": name },
+                        "spec": { "ingresses": [ingress] },
+                    })).unwrap())
+                    .collect())
+            };
+            async move { result }.boxed()
+        }
+
+        /// Forwards from the next queued port, else the port asked for, else a new port from 54500 up
+        fn port_forward(&self, context: &str, namespace: &str, service: &str, service_port: &str, local_port: Option<u16>) -> io::Result<Child> {
+            if self.failing_forwards {
+                return Err(io::Error::other("not supported"));
+            }
+            let mut forwards = self.forwards.lock().unwrap();
+            let port = self.ports.lock().unwrap().pop_front().or(local_port).unwrap_or(54500 + forwards.len() as u16);
+            forwards.push(ForwardCall {
+                args: ClusterCli::Kubectl.port_forward_args(context, namespace, service, service_port, None, local_port),
+                local_port,
+                at: Instant::now(),
+            });
+            Command::new("sh")
+                .args(["-c", &format!("echo 'Forwarding from 127.0.0.1:{} -> 80'; exec sleep 10", port)])
+                .stdout(Stdio::piped())
+                .kill_on_drop(true)
+                .spawn()
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn keeps_last_lines_of_stderr() {
+        let cmd = Command::new("sh")
+            .args(["-c", "echo 'Forwarding from 127.0.0.1:4242 -> 80'; for i in 1 2 3 4 5 6 7; do echo \"error $i\" >&2; done"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut state = state(vec![]);
+        state.port_forwards = vec![PortforwardDescriptor::from_process(&application(), DEFAULT_SERVICE_PORT, SelftestPolicy::default(), no_circuit_breaker(), no_concurrency_limit(), cmd).await.unwrap()];
+        let portforward = state.port_forwards[0].portforward.clone();
+
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while state.stderr_lines(&portforward).last().map(String::as_str) != Some("error 7") && Instant::now() < deadline {
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+
+        assert_eq!(state.stderr_lines(&portforward), vec!["error 3", "error 4", "error 5", "error 6", "error 7"]);
+        state.close_port_forwards("speil").await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn waits_until_backend_is_ready() {
+        let addr = backend(vec![StatusCode::SERVICE_UNAVAILABLE, StatusCode::SERVICE_UNAVAILABLE, StatusCode::OK]);
+        let app = ApplicationDescriptor {
+            liveness: Some(HealthCheck::path("/isalive")),
+            readiness: Some(HealthCheck::path("/isready")),
+            ..application()
+        };
+        let descriptor = fake_port_forward(&app, addr.port() as usize).await;
+
+        assert!(descriptor.probe.wait_until_ready(&descriptor.ready_check().unwrap(), Duration::from_secs(5)).await);
+        descriptor.close().await;
+    }
+
+    /// Starts a backend redirecting `/isalive` to `/internal/isalive`, which answers 200
+    fn redirecting_backend() -> SocketAddr {
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap())
+            .serve(make_service_fn(|_| async {
+                Ok::<_, Infallible>(service_fn(|req: hyper::Request<Body>| async move {
+                    let response = match req.uri().path() {
+                        "/isalive" => Response::builder().status(StatusCode::FOUND).header(LOCATION, "/internal/isalive"),
+                        "/internal/isalive" => Response::builder().status(StatusCode::OK),
+                        _ => Response::builder().status(StatusCode::NOT_FOUND),
+                    };
+                    Ok::<_, Infallible>(response.body(Body::empty()).unwrap())
+                }))
+            }));
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn selftest_accepts_configured_statuses() {
+        let app = ApplicationDescriptor {
+            liveness: Some(HealthCheck::path("/isalive")),
+            ..application()
+        };
+        let no_content = fake_port_forward(&app, backend(vec![StatusCode::NO_CONTENT]).port() as usize).await;
+        let mut unauthorized = fake_port_forward(&app, backend(vec![StatusCode::UNAUTHORIZED]).port() as usize).await;
+
+        assert!(no_content.check_selftest().await);
+        assert!(!unauthorized.check_selftest().await);
+        unauthorized.probe.selftest.statuses = vec![StatusCode::OK, StatusCode::UNAUTHORIZED];
+        assert!(unauthorized.check_selftest().await);
+        no_content.close().await;
+        unauthorized.close().await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn selftest_follows_redirect_when_enabled() {
+        let app = ApplicationDescriptor {
+            liveness: Some(HealthCheck::path("/isalive")),
+            ..application()
+        };
+        let mut descriptor = fake_port_forward(&app, redirecting_backend().port() as usize).await;
+
+        assert!(!descriptor.check_selftest().await);
+        descriptor.probe.selftest.follow_redirect = true;
+        assert!(descriptor.check_selftest().await);
+        descriptor.close().await;
+    }
+
+    /// Starts a backend refusing HEAD with the given status and answering GET with headers and a body that never ends
+    fn get_only_backend(head_status: StatusCode) -> SocketAddr {
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap())
+            .serve(make_service_fn(move |_| async move {
+                Ok::<_, Infallible>(service_fn(move |req: hyper::Request<Body>| async move {
+                    let response = if req.method() == Method::HEAD {
+                        Response::builder().status(head_status).body(Body::empty())
+                    } else {
+                        let body = futures_util::stream::pending::<Result<Vec<u8>, io::Error>>();
+                        Response::builder().status(StatusCode::OK).body(Body::wrap_stream(body))
+                    };
+                    Ok::<_, Infallible>(response.unwrap())
+                }))
+            }));
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn selftest_falls_back_to_get_without_reading_the_body() {
+        let app = ApplicationDescriptor {
+            liveness: Some(HealthCheck::path("/isalive")),
+            ..application()
+        };
+        for head_status in [StatusCode::METHOD_NOT_ALLOWED, StatusCode::NOT_FOUND] {
+            let descriptor = fake_port_forward(&app, get_only_backend(head_status).port() as usize).await;
+
+            assert!(descriptor.check_selftest().await, "HEAD answered with {}", head_status);
+            descriptor.close().await;
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn selftest_times_out_when_backend_hangs() {
+        let app = ApplicationDescriptor {
+            liveness: Some(HealthCheck::path("/isalive")),
+            ..application()
+        };
+        let mut descriptor = fake_port_forward(&app, slow_backend(Duration::from_secs(30)).port() as usize).await;
+        descriptor.probe.selftest.timeout = Duration::from_millis(200);
+
+        let started = Instant::now();
+        assert!(!descriptor.check_selftest().await);
+        assert!(started.elapsed() < Duration::from_secs(2));
+        descriptor.close().await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn gives_up_when_backend_never_becomes_ready() {
+        let addr = backend(vec![StatusCode::SERVICE_UNAVAILABLE]);
+        let app = ApplicationDescriptor {
+            liveness: Some(HealthCheck::path("/isalive")),
+            ..application()
+        };
+        let descriptor = fake_port_forward(&app, addr.port() as usize).await;
+
+        let started = Instant::now();
+        assert!(!descriptor.probe.wait_until_ready(&descriptor.ready_check().unwrap(), Duration::from_millis(500)).await);
+        assert!(started.elapsed() < Duration::from_secs(2));
+        descriptor.close().await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn tick_publishes_failed_selftest() {
+        let addr = backend(vec![StatusCode::SERVICE_UNAVAILABLE]);
+        let app = ApplicationDescriptor {
+            liveness: Some(HealthCheck::path("/isalive")),
+            ..application()
+        };
+        let mut state = state(vec![]);
+        state.port_forwards = vec![fake_port_forward(&app, addr.port() as usize).await];
+        let mut events = state.subscribe();
+
+        state.tick().await;
+
+        assert!(state.port_forwards.is_empty());
+        let failed = events.recv().await.unwrap();
+        assert_eq!(failed.event, EventKind::SelftestFailed);
+        assert_eq!(failed.local_port, addr.port() as usize);
+        assert_eq!(failed.ingresses, app.ingresses);
+        assert_eq!(events.recv().await.unwrap().event, EventKind::Closed);
+    }
+
+    #[test]
+    fn parses_ipv4_and_ipv6_forwarding_lines() {
+        let ipv4 = parse_forwarding_line("Forwarding from 127.0.0.1:54321 -> 80").unwrap();
+        assert_eq!(ipv4, Portforward { host: "127.0.0.1".to_owned(), port: 54321 });
+        assert_eq!(ipv4.authority(), "127.0.0.1:54321");
+
+        let ipv6 = parse_forwarding_line("Forwarding from [::1]:54321 -> 8080").unwrap();
+        assert_eq!(ipv6, Portforward { host: "::1".to_owned(), port: 54321 });
+        assert_eq!(ipv6.authority(), "[::1]:54321");
+
+        assert_eq!(parse_forwarding_line("Handling connection for 54321"), None);
+        assert_eq!(parse_forwarding_line("Forwarding from 127.0.0.1:99999 -> 80"), None);
+    }
+
+    #[test]
+    fn jitter_stays_within_a_tenth_of_the_interval() {
+        for _ in 0..100 {
+            let interval = jittered(Duration::from_secs(10));
+            assert!(interval >= Duration::from_secs(10) && interval <= Duration::from_secs(11));
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn maintenance_closes_dead_forwards_promptly() {
+        let addr = backend(vec![StatusCode::OK, StatusCode::SERVICE_UNAVAILABLE]);
+        let app = ApplicationDescriptor {
+            liveness: Some(HealthCheck::path("/isalive")),
+            ..application()
+        };
+        let mut state = state(vec![]);
+        state.port_forwards = vec![fake_port_forward(&app, addr.port() as usize).await];
+        let mut events = state.subscribe();
+        let state = Arc::new(Mutex::new(state));
+        let (maintenance, abort) = abortable(run_maintenance(state.clone(), Duration::from_millis(50)));
+        tokio::spawn(maintenance);
+
+        let closed = timeout(Duration::from_secs(2), async {
+            loop {
+                if events.recv().await.unwrap().event == EventKind::Closed {
+                    break;
+                }
+            }
+        }).await;
+        abort.abort();
+
+        assert!(closed.is_ok());
+        assert!(state.lock().await.port_forwards.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn path_disjoint_ingresses_on_one_host_get_their_own_forwards() {
+        let app_a = ApplicationDescriptor {
+            application_name: "app-a".to_owned(),
+            ingresses: vec!["https://tbd.nais.preprod.local/app-a".to_owned()],
+            ..application()
+        };
+        let app_b = ApplicationDescriptor {
+            application_name: "app-b".to_owned(),
+            ingresses: vec!["https://tbd.nais.preprod.local/app-b".to_owned()],
+            ..application()
+        };
+        let forwards = vec![fake_port_forward(&app_a, 4001).await, fake_port_forward(&app_b, 4002).await];
+        let mut state = state(vec![app_a, app_b]);
+        state.port_forwards = forwards;
+
+        let a = state.fetch_address("tbd.nais.preprod.local", "/app-a/api").await.unwrap().unwrap();
+        let b = state.fetch_address("tbd.nais.preprod.local", "/app-b").await.unwrap().unwrap();
+
+        assert_eq!((a.port, b.port), (4001, 4002));
+        assert_eq!(state.port_forwards.len(), 2);
+        drop((a, b));
+        state.close_port_forwards("app-a").await;
+        state.close_port_forwards("app-b").await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn close_port_forwards_only_closes_named_application() {
+        let other = ApplicationDescriptor {
+            application_name: "spleis".to_owned(),
+            ingresses: vec!["https://spleis.nais.preprod.local".to_owned()],
+            ..application()
+        };
+        let mut state = state(vec![]);
+        state.port_forwards = vec![
+            fake_port_forward(&application(), 50001).await,
+            fake_port_forward(&other, 50002).await,
+        ];
+
+        assert_eq!(state.close_port_forwards("speil").await, 1);
+        assert_eq!(state.close_port_forwards("speil").await, 0);
+
+        let remaining = state.port_forwards().collect::<Vec<_>>();
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].application, "spleis");
+        assert_eq!(remaining[0].local.port, 50002);
+        assert!(remaining[0].ttl_seconds > 0);
+        assert_eq!(remaining[0].last_selftest, None);
+        state.close_port_forwards("spleis").await;
+    }
+
+    fn state(hosts: Vec<ApplicationDescriptor>) -> State {
+        let config = Config::from_iter(&["autoforward"]);
+        let (reconnected_sender, reconnected) = mpsc::unbounded_channel();
+        State {
+            provider: Arc::new(CliProvider::new(&config)),
+            config: Arc::new(config),
+            next_update: State::next_update(),
+            hosts,
+            loopback_hosts: HashMap::new(),
+            port_forwards: vec![],
+            reconnecting: vec![],
+            recorded_ports: HashMap::new(),
+            reconnected_sender,
+            reconnected,
+            forward_rate: None,
+            throttled: HashMap::new(),
+            warnings: vec![],
+            reload: Arc::new(Notify::new()),
+            events: broadcast::channel(EVENT_BUFFER).0,
+            ready: true,
+        }
+    }
+
+    fn resource(json: &str) -> ApplicationResource {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn create_skips_application_without_ingresses() {
+        let without_ingresses = resource(r#"{"metadata": {"name": "speil"}, "spec": {}}"#);
+        let with_ingresses = resource(r#"{"metadata": {"name": "speil"}, "spec": {"ingresses": ["https://speil.nais.preprod.local"]}}"#);
+
+        assert!(ApplicationDescriptor::create(without_ingresses, "dev-fss".to_owned(), "default".to_owned()).is_none());
+        assert_eq!(ApplicationDescriptor::create(with_ingresses, "dev-fss".to_owned(), "default".to_owned()), Some(application()));
+    }
+
+    #[test]
+    fn create_skips_ingresses_without_host() {
+        let resource = resource(r#"{"metadata": {"name": "speil"}, "spec": {"ingresses": ["https://speil.nais.preprod.local", "https://:8443/"]}}"#);
+
+        assert_eq!(ApplicationDescriptor::create(resource, "dev-fss".to_owned(), "default".to_owned()), Some(application()));
+    }
+
+    #[test]
+    fn ingresses_without_host_are_left_out_of_the_hosts() {
+        let app = ApplicationDescriptor {
+            ingresses: vec!["https://speil.nais.preprod.local".to_owned(), "https://:8443/".to_owned()],
+            ..application()
+        };
+
+        let state = state(vec![app]);
+
+        assert_eq!(state.hostnames(), vec!["speil.nais.preprod.local"]);
+        assert_eq!(state.known_hosts().len(), 1);
+    }
+
+    #[test]
+    fn create_keeps_health_checks_reachable_through_the_service() {
+        let resource = resource(r#"{"metadata": {"name": "speil"}, "spec": {"ingresses": ["https://speil.nais.preprod.local"],
+            "port": 8081, "liveness": {"path": "/isalive", "port": 8081, "scheme": "HTTPS"},
+            "readiness": {"path": "/isready", "port": 9090}}}"#);
+
+        let app = ApplicationDescriptor::create(resource, "dev-fss".to_owned(), "default".to_owned()).unwrap();
+
+        assert_eq!(app.liveness, Some(HealthCheck { path: "/isalive".to_owned(), port: Some(8081), scheme: Some(HealthScheme::Https) }));
+        assert_eq!(app.readiness, None);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn selftest_probes_over_https() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let keys = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata");
+        let tls_config = tls::server_config(&keys.join("server.crt"), &keys.join("server.key"), tls::TlsVersion::Tls12, tls::Alpn::Both, None).unwrap();
+        let acceptor = tls::tls_acceptor(listener, tls_config).await.unwrap();
+        tokio::spawn(Server::builder(acceptor).serve(make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|_| async { Ok::<_, Infallible>(Response::new(Body::empty())) }))
+        })));
+        let https = ApplicationDescriptor {
+            liveness: Some(HealthCheck { scheme: Some(HealthScheme::Https), ..HealthCheck::path("/isalive") }),
+            ..application()
+        };
+        let http = ApplicationDescriptor {
+            liveness: Some(HealthCheck::path("/isalive")),
+            ..application()
+        };
+        let over_https = fake_port_forward(&https, addr.port() as usize).await;
+        let over_http = fake_port_forward(&http, addr.port() as usize).await;
+
+        assert!(over_https.check_selftest().await);
+        assert!(!over_http.check_selftest().await);
+        over_https.close().await;
+        over_http.close().await;
+    }
+
+    #[test]
+    fn create_adds_scheme_to_bare_ingresses() {
+        let resource = resource(r#"{"metadata": {"name": "speil"}, "spec": {"ingresses": [
+            "speil.nais.preprod.local", "speil.nais.preprod.local/api", "https://speil.nais.preprod.local/admin"]}}"#);
+
+        let app = ApplicationDescriptor::create(resource, "dev-fss".to_owned(), "default".to_owned()).unwrap();
+
+        assert_eq!(app.ingresses, vec!["https://speil.nais.preprod.local", "https://speil.nais.preprod.local/api",
+                                       "https://speil.nais.preprod.local/admin"]);
+        assert_eq!(app.best_ingress("speil.nais.preprod.local", "/api/person", false).unwrap().ingress,
+                   "https://speil.nais.preprod.local/api");
+        assert_eq!(app.best_ingress("speil.nais.preprod.local", "/", false).unwrap().ingress,
+                   "https://speil.nais.preprod.local");
+        assert_eq!(state(vec![app]).hostnames(), vec!["speil.nais.preprod.local"]);
+    }
+
+    #[test]
+    fn duplicate_ingress_goes_to_first_application_by_namespace() {
+        let tbd = ApplicationDescriptor {
+            application_name: "speil".to_owned(),
+            namespace: "tbd".to_owned(),
+            ingresses: vec!["https://speil.nais.preprod.local/".to_owned(), "https://speil.nais.preprod.local/api".to_owned()],
+            ..application()
+        };
+        let default = ApplicationDescriptor {
+            application_name: "speil-gammel".to_owned(),
+            ..application()
+        };
+        let mut hosts = vec![tbd, default];
+
+        let warnings = State::remove_duplicate_ingresses(&mut hosts, &[]);
+
+        assert_eq!(warnings, vec!["https://speil.nais.preprod.local/ is claimed by both speil-gammel in default and speil in tbd, routing it to speil-gammel"]);
+        assert_eq!(hosts[0].ingresses, vec!["https://speil.nais.preprod.local/api"]);
+        assert_eq!(hosts[1].ingresses, vec!["https://speil.nais.preprod.local"]);
+    }
+
+    #[test]
+    fn duplicate_ingress_across_contexts_goes_to_prioritized_context() {
+        let prod = ApplicationDescriptor {
+            context: "prod-fss".to_owned(),
+            liveness: Some(HealthCheck::path("/isalive")),
+            ..application()
+        };
+        let config = Config::from_iter(&["autoforward", "--context-priority", "prod-fss,dev-fss"]);
+        let mut hosts = vec![application(), prod];
+
+        let warnings = State::prepare_hosts(&mut hosts, &config);
+
+        assert_eq!(warnings, vec!["https://speil.nais.preprod.local is claimed by both speil in prod-fss/default and speil in dev-fss/default, routing it to prod-fss"]);
+        assert_eq!(hosts.len(), 1);
+        assert_eq!(hosts[0].context, "prod-fss");
+        let (_, app) = State::find_application(&hosts, "speil.nais.preprod.local", "/", false).unwrap();
+        assert_eq!(app.liveness.as_ref().unwrap().path, "/isalive");
+    }
+
+    #[test]
+    fn service_port_rules_route_to_matching_application() {
+        let other = ApplicationDescriptor {
+            application_name: "spleis".to_owned(),
+            ingresses: vec!["https://speil.nais.preprod.local/spleis".to_owned()],
+            ..application()
+        };
+        let mut hosts = vec![application(), other];
+        let rules = vec![
+            "https://speil.nais.preprod.local/metrics=9090".parse().unwrap(),
+            "https://speil.nais.preprod.local/spleis/metrics=metrics".parse().unwrap(),
+            "https://unknown.nais.preprod.local/metrics=9090".parse().unwrap(),
+        ];
+        State::assign_service_ports(&mut hosts, &rules);
+
+        let speil = &hosts[0];
+        assert_eq!(speil.service_port("https://speil.nais.preprod.local"), "80");
+        assert_eq!(speil.service_port("https://speil.nais.preprod.local/metrics"), "9090");
+        assert_eq!(speil.ingresses_on_port("80"), vec!["https://speil.nais.preprod.local"]);
+        assert_eq!(speil.ingresses_on_port("9090"), vec!["https://speil.nais.preprod.local/metrics"]);
+        assert_eq!(hosts[1].service_port("https://speil.nais.preprod.local/spleis/metrics"), "metrics");
+
+        let state = state(hosts);
+        let (ingress, app) = State::find_application(&state.hosts, "speil.nais.preprod.local", "/metrics/prometheus", false).unwrap();
+        assert_eq!(app.service_port(&ingress.ingress), "9090");
+        let (ingress, app) = State::find_application(&state.hosts, "speil.nais.preprod.local", "/api", false).unwrap();
+        assert_eq!(app.service_port(&ingress.ingress), "80");
+    }
+
+    #[test]
+    fn wildcard_matches_single_label() {
+        let app = ApplicationDescriptor {
+            ingresses: vec!["https://*.nais.preprod.local/".to_owned()],
+            ..application()
+        };
+
+        assert_eq!(app.best_ingress("speil.nais.preprod.local", "/", false).map(|m| m.exact_host), Some(false));
+        assert_eq!(app.best_ingress("nais.preprod.local", "/", false), None);
+        assert_eq!(app.best_ingress("a.speil.nais.preprod.local", "/", false), None);
+        assert_eq!(app.best_ingress(".nais.preprod.local", "/", false), None);
+    }
+
+    #[test]
+    fn exact_host_is_preferred_over_wildcard() {
+        let wildcard = ApplicationDescriptor {
+            application_name: "catch-all".to_owned(),
+            ingresses: vec!["https://*.nais.preprod.local/".to_owned()],
+            ..application()
+        };
+        let exact = ApplicationDescriptor {
+            ingresses: vec!["https://speil.nais.preprod.local/".to_owned()],
+            ..application()
+        };
+        let state = state(vec![wildcard, exact]);
+
+        let (_, app) = State::find_application(&state.hosts, "speil.nais.preprod.local", "/", false).unwrap();
+        assert_eq!(app.application_name, "speil");
+        let (_, app) = State::find_application(&state.hosts, "spleis.nais.preprod.local", "/", false).unwrap();
+        assert_eq!(app.application_name, "catch-all");
+    }
+
+    #[test]
+    fn paths_match_on_segments_ignoring_trailing_slash() {
+        assert_eq!(match_path("/api", "/api"), Some((4, true)));
+        assert_eq!(match_path("/api", "/api/"), Some((4, false)));
+        assert_eq!(match_path("/api/", "/api"), Some((4, false)));
+        assert_eq!(match_path("/api/", "/api/v2"), Some((4, false)));
+        assert_eq!(match_path("/api", "/apiv2"), None);
+        assert_eq!(match_path("/", "/api"), Some((0, false)));
+    }
+
+    #[test]
+    fn exact_path_wins_over_trailing_slash_variant() {
+        let app = ApplicationDescriptor {
+            ingresses: vec!["https://speil.nais.preprod.local/api/".to_owned(), "https://speil.nais.preprod.local/api".to_owned()],
+            ..application()
+        };
+
+        let best = |path| app.best_ingress("speil.nais.preprod.local", path, false).map(|m| m.ingress);
+        assert_eq!(best("/api"), Some("https://speil.nais.preprod.local/api".to_owned()));
+        assert_eq!(best("/api/"), Some("https://speil.nais.preprod.local/api/".to_owned()));
+        // Neither is exact for a sub path, the tie is broken on the ingress so the choice stays stable
+        assert_eq!(best("/api/v2"), Some("https://speil.nais.preprod.local/api/".to_owned()));
+        assert_eq!(best("/apiv2"), None);
+    }
+
+    #[test]
+    fn longer_path_wins_for_sub_path() {
+        let app = ApplicationDescriptor {
+            ingresses: vec!["https://speil.nais.preprod.local/api".to_owned(), "https://speil.nais.preprod.local/api/v2/".to_owned()],
+            ..application()
+        };
+
+        let best = app.best_ingress("speil.nais.preprod.local", "/api/v2/person", false).map(|m| m.ingress);
+        assert_eq!(best, Some("https://speil.nais.preprod.local/api/v2/".to_owned()));
+    }
+
+    #[test]
+    fn root_ingress_catches_paths_without_a_specific_ingress() {
+        let app = ApplicationDescriptor {
+            ingresses: vec!["https://speil.nais.preprod.local/".to_owned(), "https://speil.nais.preprod.local/api".to_owned()],
+            ..application()
+        };
+        let best = |path| app.best_ingress("speil.nais.preprod.local", path, false).map(|m| m.ingress);
+
+        assert_eq!(best("/api/person"), Some("https://speil.nais.preprod.local/api".to_owned()));
+        assert_eq!(best("/api"), Some("https://speil.nais.preprod.local/api".to_owned()));
+        assert_eq!(best("/other"), Some("https://speil.nais.preprod.local/".to_owned()));
+        assert_eq!(best("/apiv2"), Some("https://speil.nais.preprod.local/".to_owned()));
+        assert_eq!(best("/"), Some("https://speil.nais.preprod.local/".to_owned()));
+    }
+
+    #[test]
+    fn root_ingress_of_one_application_loses_to_path_of_another() {
+        let spleis = ApplicationDescriptor {
+            application_name: "spleis".to_owned(),
+            ingresses: vec!["https://speil.nais.preprod.local/api".to_owned()],
+            ..application()
+        };
+        let hosts = vec![application(), spleis];
+        let application_for = |path| State::find_application(&hosts, "speil.nais.preprod.local", path, false)
+            .map(|(_, app)| app.application_name.as_str());
+
+        assert_eq!(application_for("/api/person"), Some("spleis"));
+        assert_eq!(application_for("/other"), Some("speil"));
+        assert_eq!(application_for("/"), Some("speil"));
+    }
+
+    #[test]
+    fn resolves_application_without_opening_port_forward() {
+        let spleis = ApplicationDescriptor {
+            application_name: "spleis".to_owned(),
+            ingresses: vec!["https://speil.nais.preprod.local/api".to_owned(), "https://spleis.nais.preprod.local".to_owned()],
+            ..application()
+        };
+        let state = state(vec![application(), spleis]);
+        let resolve = |host, path| state.resolve(host, path).and_then(Resolved::application).map(ApplicationDescriptor::application_name);
+
+        assert_eq!(resolve("speil.nais.preprod.local", "/"), Some("speil"));
+        assert_eq!(resolve("speil.nais.preprod.local", "/api/person"), Some("spleis"));
+        assert_eq!(resolve("speil.nais.preprod.local", "/apiv2"), Some("speil"));
+        assert_eq!(resolve("spleis.nais.preprod.local", "/"), Some("spleis"));
+        assert_eq!(resolve("sparkel.nais.preprod.local", "/"), None);
+        assert!(state.port_forwards.is_empty());
+    }
+
+    #[test]
+    fn known_hosts_are_sorted_and_deduplicated() {
+        let state = state(vec![
+            ApplicationDescriptor {
+                ingresses: vec!["https://speil.nais.preprod.local/".to_owned(), "https://speil.nais.preprod.local/api".to_owned()],
+                ..application()
+            },
+            ApplicationDescriptor {
+                application_name: "spleis".to_owned(),
+                ingresses: vec!["https://spleis.nais.preprod.local/".to_owned()],
+                namespace: "tbd".to_owned(),
+                ..application()
+            },
+            ApplicationDescriptor {
+                application_name: "arbeid".to_owned(),
+                ingresses: vec!["https://arbeid.nais.preprod.local/".to_owned()],
+                ..application()
+            },
+        ]);
+
+        let hosts = state.known_hosts().iter().map(KnownHost::to_string).collect::<Vec<_>>();
+        assert_eq!(hosts, vec![
+            "arbeid.nais.preprod.local (dev-fss/default)",
+            "speil.nais.preprod.local (dev-fss/default)",
+            "spleis.nais.preprod.local (dev-fss/tbd)",
+        ]);
+    }
+
+    #[test]
+    fn routes_describe_every_ingress() {
+        let state = state(vec![ApplicationDescriptor {
+            ingresses: vec!["https://speil.nais.preprod.local/".to_owned(), "https://speil.nais.preprod.local/metrics".to_owned()],
+            service_ports: vec![("https://speil.nais.preprod.local/metrics".to_owned(), "9090".to_owned())],
+            liveness: Some(HealthCheck::path("/isalive")),
+            ..application()
+        }]);
+
+        let routes = state.routes();
+
+        assert_eq!(routes.len(), 2);
+        assert_eq!(routes[1], Route {
+            ingress: "https://speil.nais.preprod.local/metrics",
+            host: "speil.nais.preprod.local".to_owned(),
+            path: "/metrics".to_owned(),
+            application: "speil",
+            namespace: "default",
+            context: "dev-fss",
+            service_port: "9090",
+            via: None,
+            liveness: Some("/isalive"),
+            readiness: None,
+        });
+        assert_eq!(routes[0].service_port, DEFAULT_SERVICE_PORT);
+    }
+
+    #[test]
+    fn hostnames_skip_wildcards() {
+        let state = state(vec![ApplicationDescriptor {
+            ingresses: vec!["https://*.nais.preprod.local/".to_owned(), "https://speil.nais.preprod.local/".to_owned()],
+            ..application()
+        }]);
+
+        assert_eq!(state.hostnames(), vec!["speil.nais.preprod.local".to_owned()]);
+    }
+
+    #[test]
+    fn ingress_with_port_or_query_uses_the_clean_host() {
+        let app = ApplicationDescriptor {
+            ingresses: vec!["https://speil.nais.preprod.local:8443/api".to_owned(), "https://spleis.nais.preprod.local?debug=true".to_owned()],
+            ..application()
+        };
+
+        assert_eq!(app.best_ingress("speil.nais.preprod.local", "/api/person", false).map(|m| m.ingress),
+                   Some("https://speil.nais.preprod.local:8443/api".to_owned()));
+        assert_eq!(app.best_ingress("spleis.nais.preprod.local", "/", false).map(|m| m.ingress),
+                   Some("https://spleis.nais.preprod.local?debug=true".to_owned()));
+        assert_eq!(state(vec![app]).hostnames(), vec!["speil.nais.preprod.local", "spleis.nais.preprod.local"]);
+    }
+
+    #[test]
+    fn takes_host_only_from_ingresses_that_have_one() {
+        assert_eq!(ingress_host("HTTPS://speil.nais.preprod.local/api"), Some("speil.nais.preprod.local".to_owned()));
+        assert_eq!(ingress_host("https://:8443/"), None);
+        assert_eq!(ingress_host("https://?x"), None);
+        assert_eq!(ingress_host(""), None);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn reads_forwarding_line_from_stderr() {
+        let cmd = Command::new("sh")
+            .args(["-c", "echo 'Starting' >&2; echo 'Forwarding from 127.0.0.1:54322 -> 80' >&2; exec sleep 10"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .unwrap();
+
+        let descriptor = timeout(Duration::from_secs(5), PortforwardDescriptor::from_process(&application(), DEFAULT_SERVICE_PORT, SelftestPolicy::default(), no_circuit_breaker(), no_concurrency_limit(), cmd))
+            .await
+            .expect("did not see the forwarding line")
+            .unwrap();
+
+        assert_eq!(descriptor.portforward, Portforward { host: "127.0.0.1".to_owned(), port: 54322 });
+        assert_eq!(descriptor.stderr_lines.lock().unwrap().iter().collect::<Vec<_>>(), vec!["Starting"]);
+        descriptor.close().await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn close_returns_when_killed_process_leaves_output_open() {
+        // The shell ignores SIGINT and the background sleep keeps stdout open after the shell is killed
+        let cmd = Command::new("sh")
+            .args(["-c", "trap '' INT; echo 'Forwarding from 127.0.0.1:54321 -> 80'; sleep 10 & wait"])
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let descriptor = PortforwardDescriptor::from_process(&application(), DEFAULT_SERVICE_PORT, SelftestPolicy::default(), no_circuit_breaker(), no_concurrency_limit(), cmd).await.unwrap();
+        assert_eq!(descriptor.portforward, Portforward { host: "127.0.0.1".to_owned(), port: 54321 });
+
+        let started = Instant::now();
+        timeout(Duration::from_secs(10), descriptor.close()).await
+            .expect("close() did not return");
+
+        assert!(started.elapsed() < Duration::from_secs(6));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn kill_does_not_signal_exited_process() {
+        let process = Command::new("true")
+            .spawn()
+            .unwrap();
+        tokio::time::delay_for(Duration::from_millis(200)).await;
+
+        let started = Instant::now();
+        PortforwardDescriptor::kill(process).await;
+
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn signal_treats_missing_process_as_success() {
+        // Pids are capped well below i32::MAX on Linux and macOS, so this one can't exist
+        assert!(PortforwardDescriptor::signal(Pid::from_raw(i32::MAX), Signal::SIGINT));
+    }
+
+    #[tokio::test]
+    async fn retries_failed_discovery() {
+        let config = Arc::new(Config::from_iter(&["autoforward", "--context", "dev-fss", "--namespace", "default",
+            "--discovery-backoff-ms", "10"]));
+        let provider = Arc::new(FakeProvider { failures: 2, ..FakeProvider::listing(vec![("speil", INGRESS)]) });
+
+        let state = State::with_provider(config, provider.clone()).await.unwrap();
+
+        assert_eq!(provider.attempts.load(Ordering::SeqCst), 3);
+        assert_eq!(state.hostnames(), vec!["speil.nais.preprod.local"]);
+    }
+
+    #[tokio::test]
+    async fn gives_up_discovery_after_configured_attempts() {
+        let config = Arc::new(Config::from_iter(&["autoforward", "--context", "dev-fss", "--namespace", "default",
+            "--discovery-attempts", "2", "--discovery-backoff-ms", "10"]));
+        let provider = Arc::new(FakeProvider { failures: 2, ..FakeProvider::listing(vec![("speil", INGRESS)]) });
+
+        let state = State::with_provider(config, provider.clone()).await.unwrap();
+
+        assert_eq!(provider.attempts.load(Ordering::SeqCst), 2);
+        assert!(state.hostnames().is_empty());
+    }
+
+    #[tokio::test]
+    async fn refresh_keeps_applications_when_discovery_gives_up() {
+        let config = Arc::new(Config::from_iter(&["autoforward", "--context", "dev-fss", "--namespace", "default",
+            "--discovery-attempts", "2", "--discovery-backoff-ms", "10"]));
+        let provider = Arc::new(FakeProvider::listing(vec![("speil", INGRESS)]));
+        let state = Mutex::new(State::with_provider(config, provider.clone()).await.unwrap());
+        provider.failing.lock().unwrap().push("dev-fss");
+
+        State::refresh(&state, |_| {}).await;
+
+        assert_eq!(provider.attempts.load(Ordering::SeqCst), 3);
+        assert_eq!(state.lock().await.hostnames(), vec!["speil.nais.preprod.local"]);
+    }
+
+    #[tokio::test]
+    async fn hanging_discovery_times_out() {
+        let config = Config::from_iter(&["autoforward", "--context", "dev-fss", "--namespace", "default",
+            "--discovery-attempts", "1", "--discovery-timeout", "1"]);
+
+        let result = timeout(Duration::from_secs(5),
+                             State::fetch_with_retry(&config, &FakeProvider { hanging: vec!["dev-fss"], ..FakeProvider::default() }, "dev-fss".to_owned(), Some("default".to_owned()))).await;
+
+        let error = result.expect("discovery should time out").unwrap_err();
+        assert_eq!(error.original.kind(), io::ErrorKind::TimedOut);
+        assert!(error.original.to_string().contains("dev-fss/default"));
+    }
+
+    #[tokio::test]
+    async fn refresh_merges_healthy_context_while_another_hangs() {
+        let config = Arc::new(Config::from_iter(&["autoforward", "--namespace", "default"]));
+        let state = Arc::new(Mutex::new(State::from_descriptors(config, Arc::new(FakeProvider { hanging: vec!["prod-fss"], ..FakeProvider::listing(vec![("speil", INGRESS)]) }), vec![])));
+        let merges = Arc::new(AtomicUsize::new(0));
+        let (refresh, abort) = {
+            let (state, merges) = (state.clone(), merges.clone());
+            abortable(async move {
+                State::refresh(&state, |_| {
+                    merges.fetch_add(1, Ordering::SeqCst);
+                }).await
+            })
+        };
+        let refresh = tokio::spawn(refresh);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while merges.load(Ordering::SeqCst) == 0 && Instant::now() < deadline {
+            tokio::time::delay_for(Duration::from_millis(20)).await;
+        }
+
+        let state = state.lock().await;
+        assert_eq!(state.hostnames(), vec!["speil.nais.preprod.local"]);
+        assert!(State::find_application(&state.hosts, "speil.nais.preprod.local", "/", false).is_some());
+        assert_eq!(merges.load(Ordering::SeqCst), 1);
+        drop(state);
+        abort.abort();
+        assert!(refresh.await.unwrap().is_err(), "the hanging context should still be discovering");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn refresh_replaces_applications_and_closes_forwards_of_vanished_ones() {
+        let config = Arc::new(Config::from_iter(&["autoforward", "--context", "dev-fss", "--namespace", "default"]));
+        let provider = Arc::new(FakeProvider::listing(vec![("speil", INGRESS)]));
+        let state = Mutex::new(State::from_descriptors(config, provider.clone(), vec![]));
+        State::refresh(&state, |_| {}).await;
+        {
+            let mut state = state.lock().await;
+            assert_eq!(state.hostnames(), vec!["speil.nais.preprod.local"]);
+            let speil = state.hosts[0].clone();
+            state.port_forwards.push(fake_port_forward(&speil, 54700).await);
+        }
+        State::refresh(&state, |_| {}).await;
+        assert_eq!(state.lock().await.port_forwards.len(), 1);
+
+        *provider.applications.lock().unwrap() = vec![("spleis", "https://spleis.nais.preprod.local")];
+        State::refresh(&state, |_| {}).await;
+
+        let state = state.lock().await;
+        assert_eq!(state.hostnames(), vec!["spleis.nais.preprod.local"]);
+        assert!(state.port_forwards.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn refresh_keeps_applications_and_forwards_of_failing_context() {
+        let config = Arc::new(Config::from_iter(&["autoforward", "--context", "dev-fss,prod-fss", "--namespace", "default",
+            "--discovery-attempts", "1"]));
+        let provider = Arc::new(FakeProvider {
+            by_context: vec![("prod-fss", vec![("spleis", "https://spleis.nais.adeo.no")])].into_iter().collect(),
+            ..FakeProvider::listing(vec![("speil", INGRESS)])
+        });
+        let state = Mutex::new(State::from_descriptors(config, provider.clone(), vec![]));
+        State::refresh(&state, |_| {}).await;
+        {
+            let mut state = state.lock().await;
+            let spleis = state.hosts.iter().find(|app| app.context == "prod-fss").unwrap().clone();
+            state.port_forwards.push(fake_port_forward(&spleis, 54710).await);
+        }
+
+        provider.failing.lock().unwrap().push("prod-fss");
+        State::refresh(&state, |_| {}).await;
+
+        let mut state = state.lock().await;
+        assert_eq!(state.hostnames(), vec!["speil.nais.preprod.local", "spleis.nais.adeo.no"]);
+        assert_eq!(state.port_forwards.len(), 1);
+        state.close_port_forwards("spleis").await;
+    }
+
+    #[tokio::test]
+    async fn partial_refresh_leaves_cache_alone() {
+        let cache = tempfile::NamedTempFile::new().unwrap();
+        let config = Arc::new(Config::from_iter(&["autoforward", "--context", "dev-fss,prod-fss", "--namespace", "default",
+            "--discovery-attempts", "1", "--cache", cache.path().to_str().unwrap()]));
+        let provider = Arc::new(FakeProvider {
+            by_context: vec![("prod-fss", vec![("spleis", "https://spleis.nais.adeo.no")])].into_iter().collect(),
+            ..FakeProvider::listing(vec![("speil", INGRESS)])
+        });
+        let state = Mutex::new(State::with_provider(config.clone(), provider.clone()).await.unwrap());
+        let cached = || State::from_cache(config.clone()).unwrap().hostnames();
+        assert_eq!(cached(), vec!["speil.nais.preprod.local", "spleis.nais.adeo.no"]);
+
+        provider.failing.lock().unwrap().push("prod-fss");
+        State::refresh(&state, |_| {}).await;
+
+        assert_eq!(cached(), vec!["speil.nais.preprod.local", "spleis.nais.adeo.no"]);
+    }
+
+    #[tokio::test]
+    async fn finds_host_by_loopback_address_after_refresh() {
+        let config = Arc::new(Config::from_iter(&["autoforward", "--context", "dev-fss", "--namespace", "default",
+                                                 "--loopback-range", "127.1.0.0/16"]));
+        let provider = Arc::new(FakeProvider::listing(vec![("speil", INGRESS)]));
+        let state = Mutex::new(State::from_descriptors(config, provider.clone(), vec![]));
+        assert!(state.lock().await.loopback_hosts.is_empty());
+
+        State::refresh(&state, |_| {}).await;
+
+        let state = state.lock().await;
+        let (address, host) = state.host_addresses().remove(0);
+        assert_eq!(state.host_for_address(address), Some(host));
+        assert_eq!(state.host_for_address(IpAddr::from([127, 0, 0, 1])), None);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn shared_host_stays_in_hosts_file_while_an_application_still_uses_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let hosts_file = dir.path().join("hosts");
+        std::fs::write(&hosts_file, "127.0.0.1 localhost\n").unwrap();
+        let entries = |state: &State| {
+            hosts::update_hosts_file(&hosts_file, &state.host_addresses(), false, hosts::HostsFormat::EtcHosts).unwrap();
+            std::fs::read_to_string(&hosts_file).unwrap().matches("tbd.nais.preprod.local").count()
+        };
+        let config = Arc::new(Config::from_iter(&["autoforward", "--context", "dev-fss", "--namespace", "default"]));
+        let provider = Arc::new(FakeProvider::listing(vec![
+            ("app-a", "https://tbd.nais.preprod.local/app-a"),
+            ("app-b", "https://tbd.nais.preprod.local/app-b"),
+        ]));
+        let state = Mutex::new(State::from_descriptors(config, provider.clone(), vec![]));
+        State::refresh(&state, |_| {}).await;
+        {
+            let mut state = state.lock().await;
+            assert_eq!(entries(&state), 1);
+            assert_eq!(state.resolve("tbd.nais.preprod.local", "/app-a/api").and_then(Resolved::application).map(ApplicationDescriptor::application_name), Some("app-a"));
+            assert_eq!(state.resolve("tbd.nais.preprod.local", "/app-b").and_then(Resolved::application).map(ApplicationDescriptor::application_name), Some("app-b"));
+            let app_a = state.hosts.iter().find(|app| app.application_name == "app-a").unwrap().clone();
+            state.port_forwards.push(fake_port_forward(&app_a, 54701).await);
+        }
+
+        *provider.applications.lock().unwrap() = vec![("app-b", "https://tbd.nais.preprod.local/app-b")];
+        State::refresh(&state, |_| {}).await;
+
+        let state = state.lock().await;
+        assert_eq!(entries(&state), 1);
+        assert_eq!(state.resolve("tbd.nais.preprod.local", "/app-a/api"), None);
+        assert_eq!(state.resolve("tbd.nais.preprod.local", "/app-b").and_then(Resolved::application).map(ApplicationDescriptor::application_name), Some("app-b"));
+        assert!(state.port_forwards.is_empty());
+    }
+
+    #[tokio::test]
+    async fn static_route_is_preferred_and_needs_no_kubectl() {
+        let config = Arc::new(Config::from_iter(&["autoforward", "--static-route", "speil.nais.preprod.local=localhost:3000"]));
+        let mut state = State::from_descriptors(config, Arc::new(FakeProvider { failing_forwards: true, ..FakeProvider::default() }), vec![application()]);
+
+        assert!(matches!(state.resolve("Speil.nais.preprod.local", "/"), Some(Resolved::Static(route)) if route.target_port == 3000));
+        let lease = state.fetch_address("Speil.nais.preprod.local", "/").await.unwrap().unwrap();
+
+        assert_eq!(lease.authority(), "localhost:3000");
+        assert_eq!(lease.ingress(), "https://speil.nais.preprod.local");
+        assert!(state.port_forwards.is_empty());
+        assert_eq!(state.hostnames(), vec!["speil.nais.preprod.local"]);
+    }
+
+    #[tokio::test]
+    async fn failing_port_forward_names_the_route() {
+        let config = Arc::new(Config::from_iter(&["autoforward"]));
+        let mut state = State::from_descriptors(config, Arc::new(FakeProvider { failing_forwards: true, ..FakeProvider::default() }), vec![application()]);
+
+        let error = state.fetch_address("speil.nais.preprod.local", "/").await.err().unwrap();
+
+        assert_eq!(error.to_string(), "https://speil.nais.preprod.local (speil in dev-fss/default): \
+                                       Could not open port-forward. Are you still connected to navtunnel?");
+    }
+
+    #[test]
+    fn filters_hosts_that_are_not_managed() {
+        let config = Arc::new(Config::from_iter(&["autoforward", "--deny-host", "spleis.*"]));
+        let mut spleis = application();
+        spleis.application_name = "spleis".to_owned();
+        spleis.ingresses = vec!["https://spleis.nais.preprod.local".to_owned()];
+        let mut speil = application();
+        speil.ingresses.push("https://spleis.nais.preprod.local/speil".to_owned());
+
+        let state = State::from_descriptors(config, Arc::new(FakeProvider::default()),
+                                            vec![speil, spleis]);
+
+        assert_eq!(state.hostnames(), vec!["speil.nais.preprod.local"]);
+        assert_eq!(state.hosts.len(), 1);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn state_file_follows_open_port_forwards() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("forwards.json");
+        let config = Arc::new(Config::from_iter(&["autoforward", "--state-file", path.to_str().unwrap()]));
+        let provider = Arc::new(FakeProvider::default());
+        let mut state = State::from_descriptors(config, provider, vec![application()]);
+        let written = || serde_json::from_slice::<serde_json::Value>(&std::fs::read(&path).unwrap()).unwrap();
+
+        state.fetch_address("speil.nais.preprod.local", "/").await.unwrap().unwrap();
+        assert_eq!(written()[0]["local"], "127.0.0.1:54500");
+
+        state.close_port_forwards("speil").await;
+        assert_eq!(written(), serde_json::json!([]));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn reconnects_port_forward_whose_kubectl_exited() {
+        let provider = Arc::new(FakeProvider::default());
+        let mut state = State::from_descriptors(Arc::new(Config::from_iter(&["autoforward"])), provider.clone(), vec![application()]);
+        let cmd = Command::new("sh")
+            .args(["-c", "echo 'Forwarding from 127.0.0.1:54600 -> 80'"])
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        state.port_forwards.push(PortforwardDescriptor::from_process(&application(), DEFAULT_SERVICE_PORT, SelftestPolicy::default(), no_circuit_breaker(), no_concurrency_limit(), cmd).await.unwrap());
+        let (circuit, concurrency) = (state.port_forwards[0].circuit.clone(), state.port_forwards[0].concurrency.clone());
+        let mut events = state.subscribe();
+        tokio::time::delay_for(Duration::from_millis(200)).await;
+
+        state.tick().await;
+
+        assert!(state.port_forwards.is_empty());
+        let error = state.fetch_address("speil.nais.preprod.local", "/").await.err().expect("should be reconnecting");
+        assert!(error.is_reconnecting());
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let lease = loop {
+            match state.fetch_address("speil.nais.preprod.local", "/").await {
+                Ok(lease) => break lease.unwrap(),
+                Err(e) if e.is_reconnecting() && Instant::now() < deadline => tokio::time::delay_for(Duration::from_millis(50)).await,
+                Err(e) => panic!("did not reconnect: {}", e),
+            }
+        };
+        assert_eq!(lease.port, 54600);
+        assert_eq!(provider.requested_ports(), vec![Some(54600)]);
+        assert_eq!(state.port_forwards[0].hosts, vec!["https://speil.nais.preprod.local"]);
+        assert!(Arc::ptr_eq(&state.port_forwards[0].circuit, &circuit), "the circuit breaker should carry over");
+        assert!(Arc::ptr_eq(&state.port_forwards[0].concurrency, &concurrency), "the concurrency limit should carry over");
+        assert_eq!(events.try_recv().unwrap().reason, "kubectl exited, reconnecting");
+        assert_eq!(events.try_recv().unwrap().reason, "Reconnected after kubectl exited");
+        drop(lease);
+        state.close_all(Duration::from_secs(5)).await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn close_all_closes_port_forwards_concurrently() {
+        let mut state = state(vec![]);
+        for port in 54400..54410 {
+            // Ignoring SIGINT makes each close wait for its timeout before killing the process
+            let cmd = Command::new("sh")
+                .args(["-c", &format!("trap '' INT; echo 'Forwarding from 127.0.0.1:{} -> 80'; exec sleep 30", port)])
+                .stdout(Stdio::piped())
+                .spawn()
+                .unwrap();
+            state.port_forwards.push(PortforwardDescriptor::from_process(&application(), DEFAULT_SERVICE_PORT, SelftestPolicy::default(), no_circuit_breaker(), no_concurrency_limit(), cmd).await.unwrap());
+        }
+        let mut events = state.subscribe();
+
+        let started = Instant::now();
+        state.close_all(Duration::from_secs(20)).await;
+
+        assert!(started.elapsed() < Duration::from_secs(8), "Closing took {:?}", started.elapsed());
+        assert!(state.port_forwards.is_empty());
+        assert_eq!(events.try_recv().unwrap().event, EventKind::Closed);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn recycles_port_forward_past_max_lifetime() {
+        let mut state = state(vec![application()]);
+        state.config = Arc::new(Config::from_iter(&["autoforward", "--forward-max-lifetime", "60"]));
+        state.provider = Arc::new(FakeProvider::default());
+        let mut old = fake_port_forward(&application(), 54499).await;
+        old.opened_at = Instant::now() - Duration::from_secs(61);
+        state.port_forwards.push(old);
+        let mut events = state.subscribe();
+
+        let portforward = state.fetch_address("speil.nais.preprod.local", "/").await.unwrap().unwrap();
+
+        assert_eq!(portforward.port, 54500);
+        assert_eq!(state.port_forwards.len(), 1);
+        assert_eq!(events.try_recv().unwrap().reason, "Reached its maximum lifetime");
+        assert_eq!(events.try_recv().unwrap().event, EventKind::Opened);
+        drop(portforward);
+        state.close_port_forwards("speil").await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn expired_port_forward_stays_open_for_requests_in_flight() {
+        let mut state = state(vec![application()]);
+        state.port_forwards.push(fake_port_forward(&application(), 54498).await);
+        let pid = Pid::from_raw(state.port_forwards[0].port_forward_command.id() as _);
+        let lease = state.fetch_address("speil.nais.preprod.local", "/").await.unwrap().unwrap();
+        state.port_forwards[0].ttl = SystemTime::now() - Duration::from_secs(1);
+
+        state.tick().await;
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+
+        assert!(state.port_forwards.is_empty());
+        assert!(nix::sys::signal::kill(pid, None).is_ok(), "kubectl was stopped while a request used it");
+        drop(lease);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while nix::sys::signal::kill(pid, None).is_ok() && Instant::now() < deadline {
+            tokio::time::delay_for(Duration::from_millis(20)).await;
+        }
+        assert!(nix::sys::signal::kill(pid, None).is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn tick_runs_selftests_concurrently() {
+        let app = ApplicationDescriptor {
+            liveness: Some(HealthCheck::path("/isalive")),
+            ..application()
+        };
+        let mut state = state(vec![]);
+        for _ in 0..4 {
+            let backend = slow_backend(Duration::from_millis(500));
+            state.port_forwards.push(fake_port_forward(&app, backend.port() as usize).await);
+        }
+        let expired = fake_port_forward(&application(), 54497).await;
+        state.port_forwards.push(PortforwardDescriptor { ttl: SystemTime::now() - Duration::from_secs(1), ..expired });
+
+        let started = Instant::now();
+        state.tick().await;
+
+        assert!(started.elapsed() < Duration::from_millis(1500), "tick took {:?}", started.elapsed());
+        assert_eq!(state.port_forwards.len(), 4);
+        assert!(state.port_forwards.iter().all(|pf| pf.last_selftest == Some(true)));
+        state.close_port_forwards("speil").await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn tick_closes_port_forward_past_max_lifetime() {
+        let mut state = state(vec![]);
+        state.config = Arc::new(Config::from_iter(&["autoforward", "--forward-max-lifetime", "60"]));
+        let mut old = fake_port_forward(&application(), 54498).await;
+        old.opened_at = Instant::now() - Duration::from_secs(61);
+        state.port_forwards.push(old);
+        state.port_forwards.push(fake_port_forward(&application(), 54497).await);
+
+        state.tick().await;
+
+        assert_eq!(state.port_forwards.len(), 1);
+        assert_eq!(state.port_forwards[0].portforward.port, 54497);
+        state.close_port_forwards("speil").await;
+    }
+
+    const INGRESS: &str = "https://speil.nais.preprod.local";
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn allocates_free_local_ports() {
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let taken_port = taken.local_addr().unwrap().port();
+        let mut state = state(vec![]);
+        state.config = Arc::new(Config::from_iter(&["autoforward", "--local-ports", &format!("{}-{}", taken_port, taken_port + 2)]));
+        state.port_forwards.push(fake_port_forward(&application(), taken_port as usize + 1).await);
+
+        assert_eq!(state.allocate_local_port(INGRESS).unwrap(), Some(taken_port + 2));
+        state.config = Arc::new(Config::from_iter(&["autoforward", "--local-ports", &taken_port.to_string()]));
+        assert_eq!(state.allocate_local_port(INGRESS).unwrap_err().original.kind(), io::ErrorKind::AddrInUse);
+        state.config = Arc::new(Config::from_iter(&["autoforward"]));
+        assert_eq!(state.allocate_local_port(INGRESS).unwrap(), None);
+        state.close_port_forwards("speil").await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn reuses_local_port_recorded_in_state_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("forwards.json");
+        let free_port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let record = |port: u16| state_file::store(&path, std::iter::empty(), &vec![(INGRESS.to_owned(), port)].into_iter().collect()).unwrap();
+        let restart = || {
+            let config = Arc::new(Config::from_iter(&["autoforward", "--state-file", path.to_str().unwrap()]));
+            let provider = Arc::new(FakeProvider::default());
+            (State::from_descriptors(config, provider.clone(), vec![application()]), provider)
+        };
+
+        record(free_port);
+        let (mut state, provider) = restart();
+        let lease = state.fetch_address("speil.nais.preprod.local", "/").await.unwrap().unwrap();
+        assert_eq!(lease.port, free_port as usize);
+        assert_eq!(provider.requested_ports(), vec![Some(free_port)]);
+        drop(lease);
+        state.close_all(Duration::from_secs(5)).await;
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "[]");
+        assert_eq!(state_file::recorded_ports(&path)[INGRESS], free_port);
+
+        record(taken.local_addr().unwrap().port());
+        let (mut state, provider) = restart();
+        state.fetch_address("speil.nais.preprod.local", "/").await.unwrap().unwrap();
+        assert_eq!(provider.requested_ports(), vec![None]);
+        state.close_all(Duration::from_secs(5)).await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn opens_port_forwards_no_faster_than_forward_rate() {
+        let config = Arc::new(Config::from_iter(&["autoforward", "--forward-rate", "5", "--forward-burst", "1"]));
+        let provider = Arc::new(FakeProvider::default());
+        let names = ["speil", "spleis", "sparkel", "spesialist"];
+        let hosts = names.iter().map(|name| ApplicationDescriptor {
+            application_name: name.to_string(),
+            ingresses: vec![format!("https://{}.nais.preprod.local", name)],
+            ..application()
+        }).collect();
+        let state = Arc::new(Mutex::new(State::from_descriptors(config, provider.clone(), hosts)));
+
+        let requests = names.iter().map(|name| {
+            let state = state.clone();
+            async move { fetch_address(&state, &format!("{}.nais.preprod.local", name), "/").await.unwrap().unwrap() }
+        });
+        let leases = join_all(requests).await;
+
+        let spawned = provider.forwards.lock().unwrap().iter().map(|call| call.at).collect::<Vec<_>>();
+        assert_eq!(spawned.len(), names.len());
+        for pair in spawned.windows(2) {
+            assert!(pair[1] - pair[0] >= Duration::from_millis(180), "spawned {:?} apart", pair[1] - pair[0]);
+        }
+        drop(leases);
+        state.lock().await.close_all(Duration::from_secs(5)).await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn serves_open_port_forward_while_another_waits_for_forward_rate() {
+        let config = Arc::new(Config::from_iter(&["autoforward", "--forward-rate", "0.5", "--forward-burst", "1"]));
+        let provider = Arc::new(FakeProvider::default());
+        let spleis = ApplicationDescriptor {
+            application_name: "spleis".to_owned(),
+            ingresses: vec!["https://spleis.nais.preprod.local".to_owned()],
+            ..application()
+        };
+        let state = Arc::new(Mutex::new(State::from_descriptors(config, provider.clone(), vec![application(), spleis])));
+        drop(fetch_address(&state, "speil.nais.preprod.local", "/").await.unwrap().unwrap());
+
+        let throttled = tokio::spawn({
+            let state = state.clone();
+            async move { fetch_address(&state, "spleis.nais.preprod.local", "/").await.map(drop) }
+        });
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        let lease = timeout(Duration::from_millis(500), fetch_address(&state, "speil.nais.preprod.local", "/")).await
+            .expect("the open port-forward should be served without waiting for the rate limit");
+
+        assert!(lease.unwrap().is_some());
+        assert_eq!(provider.forwards.lock().unwrap().len(), 1);
+        throttled.await.unwrap().unwrap();
+        assert_eq!(provider.forwards.lock().unwrap().len(), 2);
+        state.lock().await.close_all(Duration::from_secs(5)).await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn waits_for_ready_without_holding_the_state() {
+        let config = Arc::new(Config::from_iter(&["autoforward", "--wait-for-ready", "--ready-timeout", "1"]));
+        let backend = backend(vec![StatusCode::SERVICE_UNAVAILABLE]);
+        let provider = Arc::new(FakeProvider { ports: std::sync::Mutex::new(VecDeque::from(vec![backend.port()])), ..FakeProvider::default() });
+        let speil = ApplicationDescriptor { liveness: Some(HealthCheck::path("/isalive")), ..application() };
+        let spleis = ApplicationDescriptor {
+            application_name: "spleis".to_owned(),
+            ingresses: vec!["https://spleis.nais.preprod.local".to_owned()],
+            ..application()
+        };
+        let state = Arc::new(Mutex::new(State::from_descriptors(config, provider, vec![speil, spleis])));
+
+        let probing = tokio::spawn({
+            let state = state.clone();
+            async move { fetch_address(&state, "speil.nais.preprod.local", "/").await.map(drop) }
+        });
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        let lease = timeout(Duration::from_millis(500), fetch_address(&state, "spleis.nais.preprod.local", "/")).await
+            .expect("other port-forwards should be served while one is probed");
+        drop(lease.unwrap().unwrap());
+
+        let error = probing.await.unwrap().unwrap_err();
+        assert_eq!(error.message, "Port-forward did not become ready in time");
+        assert!(state.lock().await.port_forwards.iter().all(|pf| pf.application_name != "speil"));
+        state.lock().await.close_all(Duration::from_secs(5)).await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn closes_unready_port_forward_without_waiting_for_other_leases() {
+        let mut state = state(vec![application()]);
+        state.port_forwards.push(fake_port_forward(&application(), 54703).await);
+        // Another request waiting for the same port-forward to become ready
+        let lease = state.fetch_address("speil.nais.preprod.local", "/").await.unwrap().unwrap();
+
+        timeout(Duration::from_secs(2), state.close_unready(&lease.ready.clone())).await
+            .expect("closing shouldn't wait for the other lease while holding the state");
+
+        assert!(state.port_forwards.is_empty());
+        drop(lease);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn shutting_down_gives_up_at_the_deadline() {
+        let mut state = state(vec![application()]);
+        state.port_forwards.push(fake_port_forward(&application(), 54702).await);
+        // The lease keeps the port-forward draining long past the deadline
+        let lease = state.fetch_address("speil.nais.preprod.local", "/").await.unwrap().unwrap();
+        let state = Mutex::new(state);
+
+        let started = Instant::now();
+        assert!(!shut_down(&state, Duration::from_millis(300)).await);
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        drop(lease);
+        assert!(shut_down(&state, Duration::from_secs(5)).await);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn warmup_opens_port_forwards_for_known_hosts() {
+        let config = Arc::new(Config::from_iter(&["autoforward", "--warmup", "speil.nais.preprod.local,unknown.nais.preprod.local"]));
+        let provider = Arc::new(FakeProvider::default());
+        let state = Arc::new(Mutex::new(State::from_descriptors(config, provider.clone(), vec![application()])));
+
+        warmup(state.clone()).await;
+
+        let mut state = state.lock().await;
+        assert_eq!(state.port_forwards.len(), 1);
+        assert!(state.port_forwards[0].contains_ingress(INGRESS));
+        assert_eq!(provider.requested_ports().len(), 1);
+        state.close_all(Duration::from_secs(5)).await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn forward_via_opens_port_forward_through_another_context() {
+        let config = Arc::new(Config::from_iter(&["autoforward", "--forward-via", "dev-fss/speil=jump-fss/tbd"]));
+        let provider = Arc::new(FakeProvider::default());
+        let prod = ApplicationDescriptor {
+            context: "prod-fss".to_owned(),
+            ingresses: vec!["https://speil.nais.adeo.no".to_owned()],
+            ..application()
+        };
+        let mut state = State::from_descriptors(config, provider.clone(), vec![application(), prod]);
+
+        state.fetch_address("speil.nais.preprod.local", "/").await.unwrap();
+        state.fetch_address("speil.nais.adeo.no", "/").await.unwrap();
+
+        let forwards = provider.forwards.lock().unwrap().iter().map(|call| call.args[..5].join(" ")).collect::<Vec<_>>();
+        assert_eq!(forwards, vec![
+            "port-forward --context jump-fss --namespace tbd",
+            "port-forward --context prod-fss --namespace default",
+        ]);
+        assert_eq!(state.resolve("speil.nais.preprod.local", "/").and_then(Resolved::application).unwrap().context(), "dev-fss");
+        let hosts = state.known_hosts().iter().map(KnownHost::to_string).collect::<Vec<_>>();
+        assert_eq!(hosts, vec![
+            "speil.nais.adeo.no (prod-fss/default)",
+            "speil.nais.preprod.local (dev-fss/default via jump-fss/tbd)",
+        ]);
+        let vias = state.routes().into_iter().map(|route| route.via).collect::<Vec<_>>();
+        assert_eq!(vias, vec![Some("jump-fss/tbd".to_owned()), None]);
+        state.close_all(Duration::from_secs(5)).await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn closes_stale_port_forward_whose_address_another_got() {
+        let spleis = ApplicationDescriptor {
+            application_name: "spleis".to_owned(),
+            ingresses: vec!["https://spleis.nais.preprod.local".to_owned()],
+            ..application()
+        };
+        let provider = Arc::new(FakeProvider { ports: std::sync::Mutex::new(vec![54700, 54700].into()), ..FakeProvider::default() });
+        let config = Arc::new(Config::from_iter(&["autoforward"]));
+        let mut state = State::from_descriptors(config, provider.clone(), vec![application(), spleis]);
+        drop(state.fetch_address("speil.nais.preprod.local", "/").await.unwrap().unwrap());
+        let mut events = state.subscribe();
+
+        let spleis = state.fetch_address("spleis.nais.preprod.local", "/").await.unwrap().unwrap();
+
+        assert_eq!(spleis.port, 54700);
+        assert_eq!(state.port_forwards.len(), 1);
+        assert_eq!(state.port_forwards[0].application_name, "spleis");
+        let closed = events.try_recv().unwrap();
+        assert_eq!((closed.event, closed.reason.as_str()), (EventKind::Closed, "Lost its address to the port-forward for https://spleis.nais.preprod.local"));
+        assert_eq!(events.try_recv().unwrap().event, EventKind::Opened);
+        drop(spleis);
+        state.close_all(Duration::from_secs(5)).await;
+    }
+}
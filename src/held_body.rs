@@ -0,0 +1,142 @@
+use std::any::Any;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use http_body::SizeHint;
+use hyper::{Body, HeaderMap, Response};
+use hyper::body::{Bytes, HttpBody};
+
+/// The values held on to until the response body is sent, kept with the response until the server makes it a
+/// `HeldBody`
+#[derive(Default)]
+struct Guards(Vec<Box<dyn Any + Send + Sync>>);
+
+/// Holds on to `guard`, e.g. the lease of the port-forward the response comes from, until the whole body is sent to
+/// the client rather than until the headers are
+pub fn hold_until_sent<G: Send + Sync + 'static>(mut response: Response<Body>, guard: G) -> Response<Body> {
+    let extensions = response.extensions_mut();
+    if extensions.get::<Guards>().is_none() {
+        extensions.insert(Guards::default());
+    }
+    extensions.get_mut::<Guards>().unwrap().0.push(Box::new(guard));
+    response
+}
+
+/// A response body passed on untouched, trailers and all, letting go of what was held with `hold_until_sent` once it
+/// has been sent or the client has gone away
+pub struct HeldBody {
+    inner: Body,
+    guards: Option<Guards>,
+    sent: u64,
+    on_sent: Option<Box<dyn FnOnce(u64) + Send + Sync>>,
+}
+
+impl HeldBody {
+    pub fn response(response: Response<Body>) -> Response<HeldBody> {
+        let (mut parts, inner) = response.into_parts();
+        let guards = parts.extensions.remove::<Guards>();
+        Response::from_parts(parts, HeldBody { inner, guards, sent: 0, on_sent: None })
+    }
+
+    /// Calls `on_sent` with the number of body bytes sent once the body is done, however it was framed, or once the
+    /// client has gone away
+    pub fn when_sent<F: FnOnce(u64) + Send + Sync + 'static>(&mut self, on_sent: F) {
+        self.on_sent = Some(Box::new(on_sent));
+    }
+
+    fn finish(&mut self) {
+        self.guards = None;
+        if let Some(on_sent) = self.on_sent.take() {
+            on_sent(self.sent);
+        }
+    }
+}
+
+impl Drop for HeldBody {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+impl HttpBody for HeldBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_data(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, hyper::Error>>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_data(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(data))) => this.sent += data.len() as u64,
+            Poll::Ready(None) | Poll::Ready(Some(Err(_))) => this.finish(),
+            Poll::Pending => {}
+        }
+        poll
+    }
+
+    fn poll_trailers(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<Option<HeaderMap>, hyper::Error>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_trailers(cx);
+        if poll.is_ready() {
+            this.finish();
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+    use super::*;
+
+    struct Flag(Arc<AtomicBool>);
+
+    impl Drop for Flag {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test]
+    async fn holds_guards_until_the_body_is_sent() {
+        let (mut upstream, body) = Body::channel();
+        let (first, second) = (Arc::new(AtomicBool::new(false)), Arc::new(AtomicBool::new(false)));
+        let response = hold_until_sent(Response::new(body), Flag(first.clone()));
+        let response = hold_until_sent(response, Flag(second.clone()));
+
+        let mut body = HeldBody::response(response).into_body();
+        upstream.send_data("chunk".into()).await.unwrap();
+        assert_eq!(body.data().await.unwrap().unwrap(), "chunk");
+        assert!(!first.load(Ordering::SeqCst) && !second.load(Ordering::SeqCst));
+
+        drop(upstream);
+        assert!(body.data().await.is_none());
+        assert!(first.load(Ordering::SeqCst) && second.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn counts_the_bytes_sent() {
+        let (mut upstream, body) = Body::channel();
+        let sent = Arc::new(AtomicU64::new(u64::MAX));
+        let mut body = HeldBody::response(Response::new(body)).into_body();
+        let counted = sent.clone();
+        body.when_sent(move |bytes| counted.store(bytes, Ordering::SeqCst));
+
+        upstream.send_data("first".into()).await.unwrap();
+        body.data().await.unwrap().unwrap();
+        upstream.send_data("second".into()).await.unwrap();
+        body.data().await.unwrap().unwrap();
+        drop(upstream);
+        assert!(body.data().await.is_none());
+        assert_eq!(sent.load(Ordering::SeqCst), 11);
+    }
+}
@@ -1,30 +1,252 @@
+use std::collections::{BTreeMap, BTreeSet};
 use std::io;
+use std::net::{IpAddr, Ipv4Addr};
 use std::path::Path;
-use std::io::Write;
-use std::fs::OpenOptions;
 
-const HEADER: &'static [u8] = b"### START AUTOFORWARD";
-const FOOTER: &'static [u8] = b"### END AUTOFORWARD";
+use crate::atomic_file;
+use crate::config::{Config, LoopbackRange};
+
+/// How the entries are written, as hosts file lines or as dnsmasq configuration for a local resolver
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HostsFormat {
+    EtcHosts,
+    Dnsmasq,
+}
+
+impl std::str::FromStr for HostsFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "etc-hosts" => Ok(HostsFormat::EtcHosts),
+            "dnsmasq" => Ok(HostsFormat::Dnsmasq),
+            _ => Err(format!("Expected etc-hosts or dnsmasq, got {}", s)),
+        }
+    }
+}
+
+const HEADER: &[u8] = b"### START AUTOFORWARD";
+const FOOTER: &[u8] = b"### END AUTOFORWARD";
 
 #[cfg(unix)]
 pub fn hosts_file() -> &'static Path { Path::new("/etc/hosts") }
 
+#[cfg(windows)]
+pub fn hosts_file() -> &'static Path { Path::new(r"C:\Windows\System32\drivers\etc\hosts") }
+
 #[cfg(unix)]
-const LINE_SEPARATOR: &'static [u8] = b"\n";
+const LINE_SEPARATOR: &[u8] = b"\n";
 
 #[cfg(windows)]
-const LINE_SEPARATOR: &'static [u8] = b"\r\n";
+const LINE_SEPARATOR: &[u8] = b"\r\n";
+
+/// The hosts file entries are written to, --hosts-file or the system one
+pub fn hosts_path(config: &Config) -> &Path {
+    config.hosts_file.as_deref().unwrap_or_else(|| hosts_file())
+}
+
+/// Whether the hosts file can be written, which takes root unless it is a --hosts-file
+pub fn is_writable(config: &Config) -> bool {
+    #[cfg(unix)]
+    return config.hosts_file.is_some() || nix::unistd::getuid().is_root();
+    #[cfg(not(unix))]
+    {
+        let _ = config;
+        true
+    }
+}
+
+/// Opens the hosts file for appending without writing anything, to tell whether it can be updated
+pub fn try_open(path: &Path) -> Result<(), io::Error> {
+    std::fs::OpenOptions::new().append(true).open(path).map(drop)
+}
+
+/// Writes the entries for the given hosts to the hosts file, printing the conflicts or why it failed
+pub fn write_entries(config: &Config, hosts: &[(IpAddr, String)]) {
+    if !is_writable(config) {
+        println!("Unable to update hosts entries, application needs to be run as root");
+        return;
+    }
+    let path = hosts_path(config);
+    println!("Updating hosts entries in {}", path.display());
+    match update_hosts_file(path, hosts, config.force, config.hosts_format) {
+        Ok(conflicts) if conflicts.is_empty() => {}
+        Ok(conflicts) if config.force => println!("Removed existing hosts entries for {}", conflicts.join(", ")),
+        Ok(conflicts) => println!("Warning: {} already defined outside the autoforward block and may not be routed \
+                                   through the proxy, use --force to remove them", conflicts.join(", ")),
+        Err(e) => println!("{}", update_failure_message(path, &e)),
+    }
+}
+
+/// Writes the entries for the given hosts, returning the hosts that are also defined outside the autoforward block.
+/// With `remove_conflicts` those definitions are removed, otherwise it is undefined which entry takes effect.
+/// Conflicts are only looked for in hosts files, a dnsmasq snippet is left as it is outside the block.
+pub fn update_hosts_file(path: &Path, hosts: &[(IpAddr, String)], remove_conflicts: bool, format: HostsFormat) -> Result<Vec<String>, io::Error> {
+    let original = std::fs::read(path)?;
+    let mut input_bytes = original.clone();
+
+    let mut conflicts = vec![];
+    if format == HostsFormat::EtcHosts {
+        let names = hosts.iter().map(|(_, host)| host.clone()).collect::<Vec<_>>();
+        let (without_conflicts, found) = remove_conflicting_entries(&input_bytes, &names);
+        if remove_conflicts {
+            input_bytes = without_conflicts;
+        }
+        conflicts = found;
+    }
+    let result = insert_or_replace_entries(&input_bytes, &generate_host_entries(hosts, format));
+    if result != original {
+        atomic_file::write(path, &result)?;
+    }
+    Ok(conflicts)
+}
+
+/// Removes the given hosts from entries outside the autoforward block, dropping entries left without hosts.
+/// Returns the result along with the hosts that were found.
+fn remove_conflicting_entries(input: &[u8], hosts: &[String]) -> (Vec<u8>, Vec<String>) {
+    let mut result = Vec::with_capacity(input.len());
+    let mut conflicts = Vec::new();
+    let mut in_block = false;
+    for line in input.split_inclusive(|b| *b == b'\n') {
+        if line.starts_with(HEADER) {
+            in_block = true;
+        } else if line.starts_with(FOOTER) {
+            in_block = false;
+        }
+        let text = match std::str::from_utf8(line) {
+            Ok(text) if !in_block => text,
+            _ => {
+                result.extend_from_slice(line);
+                continue;
+            }
+        };
+        let (entry, comment) = match text.find('#') {
+            Some(index) => text.split_at(index),
+            None => (text, ""),
+        };
+        let mut fields = entry.split_whitespace();
+        let address = fields.next();
+        let (conflicting, kept): (Vec<&str>, Vec<&str>) = fields.partition(|name| hosts.iter().any(|host| host == name));
+        if conflicting.is_empty() {
+            result.extend_from_slice(line);
+            continue;
+        }
+        conflicts.extend(conflicting.into_iter().map(str::to_owned));
+        if !kept.is_empty() {
+            result.extend_from_slice(address.unwrap_or_default().as_bytes());
+            for name in kept {
+                result.push(b' ');
+                result.extend_from_slice(name.as_bytes());
+            }
+            let comment = comment.trim_end_matches(['\r', '\n']);
+            if !comment.is_empty() {
+                result.push(b' ');
+                result.extend_from_slice(comment.as_bytes());
+            }
+            result.extend_from_slice(LINE_SEPARATOR);
+        }
+    }
+    (result, conflicts)
+}
+
+/// Explains a failed hosts file update, suggesting a way around it when the file can't be written
+pub fn update_failure_message(path: &Path, e: &io::Error) -> String {
+    match e.kind() {
+        io::ErrorKind::PermissionDenied | io::ErrorKind::ReadOnlyFilesystem => format!(
+            "Could not write {}: {}. Continuing without hosts entries, clients have to reach the proxy directly with the \
+             right Host header. Start with --no-hosts to skip this, or point --hosts-file at a writable file.",
+            path.display(), e),
+        _ => format!("Failed to update hosts entries in {}: {}", path.display(), e),
+    }
+}
+
+/// Removes the block of entries managed by autoforward, returning how many entries it held
+pub fn clean_hosts_file(path: &Path) -> Result<usize, io::Error> {
+    let input_bytes = std::fs::read(path)?;
+
+    match remove_hosts_block(&input_bytes) {
+        Some((result, removed)) => {
+            atomic_file::write(path, &result)?;
+            Ok(removed)
+        }
+        None => Ok(0),
+    }
+}
+
+/// Comments out the entries managed by autoforward, keeping them in the file to be enabled again. Returns how many
+/// entries were disabled.
+pub fn disable_hosts_file(path: &Path) -> Result<usize, io::Error> {
+    rewrite_block_entries(path, |line| (!line.starts_with(b"#")).then(|| [b"# ".as_slice(), line].concat()))
+}
 
-pub fn update_hosts_file(path: &Path, hosts: &Vec<String>) -> Result<(), io::Error> {
+/// Enables the entries commented out by `disable_hosts_file`, returning how many were enabled
+pub fn enable_hosts_file(path: &Path) -> Result<usize, io::Error> {
+    rewrite_block_entries(path, |line| line.strip_prefix(b"# ").map(<[u8]>::to_vec))
+}
+
+fn rewrite_block_entries(path: &Path, rewrite: impl Fn(&[u8]) -> Option<Vec<u8>>) -> Result<usize, io::Error> {
     let input_bytes = std::fs::read(path)?;
 
-    let mut output = OpenOptions::new()
-        .write(true)
-        .truncate(true)
-        .open(path)?;
-    let result = insert_or_replace_entries(&input_bytes, &generate_host_entries(hosts));
-    output.write_all(&result)?;
-    Ok(())
+    match map_block_entries(&input_bytes, rewrite)? {
+        Some((result, changed)) if changed > 0 => {
+            atomic_file::write(path, &result)?;
+            Ok(changed)
+        }
+        _ => Ok(0),
+    }
+}
+
+/// Replaces the entries in the autoforward block that `rewrite` returns a new line for, returning the result and how
+/// many entries changed, or `None` without a block. A block missing its footer, as left by editing the file by hand,
+/// is an error.
+fn map_block_entries(input: &[u8], rewrite: impl Fn(&[u8]) -> Option<Vec<u8>>) -> io::Result<Option<(Vec<u8>, usize)>> {
+    let start = match input.windows(HEADER.len()).position(|v| v == HEADER) {
+        Some(header) => header + HEADER.len(),
+        None => return Ok(None),
+    };
+    let end = match input[start..].windows(FOOTER.len()).position(|v| v == FOOTER) {
+        Some(footer) => start + footer,
+        None => return Err(io::Error::new(io::ErrorKind::InvalidData, "the autoforward block has no end line")),
+    };
+
+    let mut result = Vec::with_capacity(input.len() + end - start);
+    result.extend_from_slice(&input[..start]);
+    let mut changed = 0;
+    for line in input[start..end].split_inclusive(|b| *b == b'\n') {
+        let content_length = line.len() - line.iter().rev().take_while(|b| b.is_ascii_whitespace()).count();
+        let (content, ending) = line.split_at(content_length);
+        let rewritten = if content.is_empty() { None } else { rewrite(content) };
+        match rewritten {
+            Some(rewritten) => {
+                result.extend_from_slice(&rewritten);
+                result.extend_from_slice(ending);
+                changed += 1;
+            }
+            None => result.extend_from_slice(line),
+        }
+    }
+    result.extend_from_slice(&input[end..]);
+    Ok(Some((result, changed)))
+}
+
+fn remove_hosts_block(input: &[u8]) -> Option<(Vec<u8>, usize)> {
+    let start = input.windows(HEADER.len()).position(|v| v == HEADER)?;
+    let end = input.windows(FOOTER.len()).position(|v| v == FOOTER)
+        .expect("Found header without any footer following");
+
+    let block = &input[start + HEADER.len()..end];
+    let removed = block.split(|b| *b == b'\n')
+        .filter(|line| line.iter().any(|b| !b.is_ascii_whitespace()))
+        .count();
+
+    let mut rest = &input[end + FOOTER.len()..];
+    if rest.starts_with(LINE_SEPARATOR) {
+        rest = &rest[LINE_SEPARATOR.len()..];
+    }
+    let mut result = Vec::with_capacity(start + rest.len());
+    result.extend_from_slice(&input[..start]);
+    result.extend_from_slice(rest);
+    Some((result, removed))
 }
 
 fn insert_or_replace_entries(input: &'_ [u8], replacement: &[u8]) -> Vec<u8> {
@@ -33,40 +255,78 @@ fn insert_or_replace_entries(input: &'_ [u8], replacement: &[u8]) -> Vec<u8> {
             .expect("Found header without any footer following");
 
         let mut result = Vec::with_capacity(start + HEADER.len() + LINE_SEPARATOR.len() + replacement.len() + (input.len() - end));
-        result.write(&input[..start]);
-        result.write(HEADER);
-        result.write(LINE_SEPARATOR);
-        result.write(replacement);
-        result.write(FOOTER);
-        result.write(&input[end + FOOTER.len()..]);
+        result.extend_from_slice(&input[..start]);
+        result.extend_from_slice(HEADER);
+        result.extend_from_slice(LINE_SEPARATOR);
+        result.extend_from_slice(replacement);
+        result.extend_from_slice(FOOTER);
+        result.extend_from_slice(&input[end + FOOTER.len()..]);
         result
     } else {
         let mut result = Vec::with_capacity((3*LINE_SEPARATOR.len()) + HEADER.len() + FOOTER.len() + input.len());
-        result.write(input);
-        result.write(LINE_SEPARATOR);
-        result.write(HEADER);
-        result.write(LINE_SEPARATOR);
-        result.write(replacement);
-        result.write(FOOTER);
-        result.write(LINE_SEPARATOR);
+        result.extend_from_slice(input);
+        result.extend_from_slice(LINE_SEPARATOR);
+        result.extend_from_slice(HEADER);
+        result.extend_from_slice(LINE_SEPARATOR);
+        result.extend_from_slice(replacement);
+        result.extend_from_slice(FOOTER);
+        result.extend_from_slice(LINE_SEPARATOR);
 
         result
     }
 }
 
-fn generate_host_entries(hosts: &Vec<String>) -> Vec<u8> {
-    let loopback = b"127.0.0.1";
-    let bytes = hosts.into_iter()
-        .map(|v| v.as_bytes().len() + loopback.len() + 1 + LINE_SEPARATOR.len())
+/// Gives every host an address from the range, or 127.0.0.1 without one. The address only depends on the hostname
+/// and the hosts sorting before it with the same hash, so most hosts keep their address as other hosts come and go.
+pub fn assign_addresses(hosts: &[String], range: Option<LoopbackRange>) -> Vec<(IpAddr, String)> {
+    let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+    let range = match range {
+        Some(range) => range,
+        None => return hosts.iter().map(|host| (localhost, host.clone())).collect(),
+    };
+    let available = range.usable_addresses();
+    let mut taken = BTreeSet::new();
+    // 127.0.0.1 is where every host is served, so it can't identify one
+    if let Some(offset) = range.offset_of(Ipv4Addr::LOCALHOST) {
+        taken.insert(offset);
+    }
+    let mut sorted = hosts.iter().collect::<Vec<_>>();
+    sorted.sort();
+    let mut addresses = BTreeMap::new();
+    for host in sorted {
+        if taken.len() as u32 >= available {
+            break;
+        }
+        let mut offset = fnv1a(host.as_bytes()) % available;
+        while !taken.insert(offset) {
+            offset = (offset + 1) % available;
+        }
+        addresses.insert(host, IpAddr::V4(range.address(offset)));
+    }
+    hosts.iter()
+        .map(|host| (addresses.get(host).copied().unwrap_or(localhost), host.clone()))
+        .collect()
+}
+
+/// A hash that, unlike the one in std, stays the same across runs and Rust versions
+fn fnv1a(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5, |hash, &b| (hash ^ b as u32).wrapping_mul(0x0100_0193))
+}
+
+fn generate_host_entries(hosts: &[(IpAddr, String)], format: HostsFormat) -> Vec<u8> {
+    let bytes = hosts.iter()
+        .map(|(_, v)| v.len() + "address=//255.255.255.255".len() + LINE_SEPARATOR.len())
         .sum();
 
     let mut result = Vec::with_capacity(bytes);
 
-    for host in hosts {
-        result.write(loopback);
-        result.write(b" ");
-        result.write(host.as_bytes());
-        result.write(LINE_SEPARATOR);
+    for (address, host) in hosts {
+        let entry = match format {
+            HostsFormat::EtcHosts => format!("{} {}", address, host),
+            HostsFormat::Dnsmasq => format!("address=/{}/{}", host, address),
+        };
+        result.extend_from_slice(entry.as_bytes());
+        result.extend_from_slice(LINE_SEPARATOR);
     }
 
     result
@@ -85,7 +345,7 @@ mod tests {
 ### END AUTOFORWARD
 127.0.0.1 localhost
 "#.as_bytes();
-        let hosts = generate_host_entries(&vec!["new.nais.preprod.local".to_owned()]);
+        let hosts = generate_host_entries(&assign_addresses(&["new.nais.preprod.local".to_owned()], None), HostsFormat::EtcHosts);
         let expected = r#"# This is a commentæøå¡™£¢∞∞§¶•ª¶§∞¢£🦀
 ### START AUTOFORWARD
 127.0.0.1 new.nais.preprod.local
@@ -103,7 +363,7 @@ mod tests {
 127.0.0.1 localhost
 "#.as_bytes();
 
-        let hosts = generate_host_entries(&vec!["new.nais.preprod.local".to_owned()]);
+        let hosts = generate_host_entries(&assign_addresses(&["new.nais.preprod.local".to_owned()], None), HostsFormat::EtcHosts);
 
         let expected = r#"# This is a commentæøå¡™£¢∞∞§¶•ª¶§∞¢£🦀
 127.0.0.1 localhost
@@ -117,19 +377,244 @@ mod tests {
     }
 
 
+    #[test]
+    fn finds_and_removes_conflicting_entries() {
+        let input = r#"127.0.0.1 localhost
+127.0.0.1 speil.nais.preprod.local
+10.0.0.1 spleis.nais.preprod.local other.local # manual
+### START AUTOFORWARD
+127.0.0.1 speil.nais.preprod.local
+127.0.0.1 spleis.nais.preprod.local
+### END AUTOFORWARD
+"#.as_bytes();
+        let hosts = vec!["speil.nais.preprod.local".to_owned(), "spleis.nais.preprod.local".to_owned()];
+
+        let (result, conflicts) = remove_conflicting_entries(input, &hosts);
+
+        assert_eq!(conflicts, hosts);
+        assert_eq!(str::from_utf8(&result).unwrap(), r#"127.0.0.1 localhost
+10.0.0.1 other.local # manual
+### START AUTOFORWARD
+127.0.0.1 speil.nais.preprod.local
+127.0.0.1 spleis.nais.preprod.local
+### END AUTOFORWARD
+"#);
+    }
+
+    #[test]
+    fn update_hosts_keeps_conflicts_unless_told_to_remove_them() {
+        let hosts = vec!["localhost".to_owned()];
+        let target_hosts = tempfile::NamedTempFile::new().unwrap();
+        std::fs::copy(Path::new("testdata/hosts"), target_hosts.path()).unwrap();
+
+        assert_eq!(update_hosts_file(target_hosts.path(), &assign_addresses(&hosts, None), false, HostsFormat::EtcHosts).unwrap(), hosts);
+        assert!(std::fs::read_to_string(&target_hosts).unwrap().starts_with("127.0.0.1 localhost"));
+
+        assert_eq!(update_hosts_file(target_hosts.path(), &assign_addresses(&hosts, None), true, HostsFormat::EtcHosts).unwrap(), hosts);
+        assert!(std::fs::read_to_string(&target_hosts).unwrap().trim_start().starts_with("### START AUTOFORWARD"));
+        assert!(update_hosts_file(target_hosts.path(), &assign_addresses(&hosts, None), true, HostsFormat::EtcHosts).unwrap().is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn update_leaves_unchanged_file_alone() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hosts");
+        std::fs::copy(Path::new("testdata/hosts"), &path).unwrap();
+        let hosts = assign_addresses(&["speil.nais.preprod.local".to_owned()], None);
+        update_hosts_file(&path, &hosts, false, HostsFormat::EtcHosts).unwrap();
+        let inode = std::fs::metadata(&path).unwrap().ino();
+
+        update_hosts_file(&path, &hosts, false, HostsFormat::EtcHosts).unwrap();
+
+        assert_eq!(std::fs::metadata(&path).unwrap().ino(), inode);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn update_fails_on_read_only_file() {
+        // procfs is read-only even for root, so no temporary file can be created next to it
+        let path = Path::new("/proc/version");
+        let original = std::fs::read(path).unwrap();
+
+        assert!(update_hosts_file(path, &assign_addresses(&["speil.nais.preprod.local".to_owned()], None), false, HostsFormat::EtcHosts).is_err());
+        assert_eq!(std::fs::read(path).unwrap(), original);
+    }
+
+    #[test]
+    fn suggests_no_hosts_when_file_is_not_writable() {
+        let path = Path::new("/etc/hosts");
+        for kind in [io::ErrorKind::PermissionDenied, io::ErrorKind::ReadOnlyFilesystem] {
+            let message = update_failure_message(path, &io::Error::from(kind));
+            assert!(message.contains("--no-hosts") && message.contains("--hosts-file"), "{}", message);
+        }
+        assert!(!update_failure_message(path, &io::Error::from(io::ErrorKind::InvalidData)).contains("--no-hosts"));
+    }
+
+    fn hosts(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn assigns_stable_addresses_from_range() {
+        let range = Some("127.1.0.0/16".parse().unwrap());
+        let assigned = assign_addresses(&hosts(&["speil.nais.preprod.local", "spleis.nais.preprod.local"]), range);
+        let reordered = assign_addresses(&hosts(&["spleis.nais.preprod.local", "speil.nais.preprod.local", "new.local"]), range);
+
+        assert_ne!(assigned[0].0, assigned[1].0);
+        assert!(assigned.iter().all(|(address, _)| address.to_string().starts_with("127.1.")));
+        assert_eq!(assigned[0], reordered[1]);
+        assert_eq!(assigned[1], reordered[0]);
+        assert_eq!(assign_addresses(&hosts(&["speil.nais.preprod.local"]), None)[0].0, IpAddr::V4(Ipv4Addr::LOCALHOST));
+    }
+
+    #[test]
+    fn falls_back_to_localhost_when_range_is_full() {
+        // 127.0.0.1 and 127.0.0.2 are the only usable addresses, and 127.0.0.1 is kept for the shared listener
+        let range = Some("127.0.0.0/30".parse().unwrap());
+        let assigned = assign_addresses(&hosts(&["a.local", "b.local"]), range);
+
+        let addresses = assigned.iter().map(|(address, _)| address.to_string()).collect::<BTreeSet<_>>();
+        assert_eq!(addresses, ["127.0.0.1", "127.0.0.2"].iter().map(|a| a.to_string()).collect());
+    }
+
+    #[test]
+    fn writes_assigned_addresses() {
+        let entries = vec![("127.1.0.7".parse().unwrap(), "speil.nais.preprod.local".to_owned())];
+
+        assert_eq!(str::from_utf8(&generate_host_entries(&entries, HostsFormat::EtcHosts)).unwrap(), "127.1.0.7 speil.nais.preprod.local\n");
+    }
+
+    #[test]
+    fn disabled_entries_can_be_enabled_again() {
+        let input = "127.0.0.1 localhost\n### START AUTOFORWARD\n127.0.0.1 speil.nais.preprod.local\n\
+                     127.0.0.1 spleis.nais.preprod.local\n### END AUTOFORWARD\n";
+        let target_hosts = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(target_hosts.path(), input).unwrap();
+
+        assert_eq!(disable_hosts_file(target_hosts.path()).unwrap(), 2);
+        assert_eq!(std::fs::read_to_string(&target_hosts).unwrap(), "127.0.0.1 localhost\n### START AUTOFORWARD\n\
+            # 127.0.0.1 speil.nais.preprod.local\n# 127.0.0.1 spleis.nais.preprod.local\n### END AUTOFORWARD\n");
+        assert_eq!(disable_hosts_file(target_hosts.path()).unwrap(), 0);
+
+        assert_eq!(enable_hosts_file(target_hosts.path()).unwrap(), 2);
+        assert_eq!(std::fs::read_to_string(&target_hosts).unwrap(), input);
+        assert_eq!(enable_hosts_file(target_hosts.path()).unwrap(), 0);
+    }
+
+    #[test]
+    fn disabling_entries_without_end_line_fails() {
+        let input = "127.0.0.1 localhost\n### START AUTOFORWARD\n127.0.0.1 speil.nais.preprod.local\n";
+        let target_hosts = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(target_hosts.path(), input).unwrap();
+
+        assert_eq!(disable_hosts_file(target_hosts.path()).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(std::fs::read_to_string(&target_hosts).unwrap(), input);
+    }
+
+    #[test]
+    fn update_replaces_disabled_entries() {
+        let target_hosts = tempfile::NamedTempFile::new().unwrap();
+        std::fs::copy(Path::new("testdata/hosts"), target_hosts.path()).unwrap();
+        let hosts = assign_addresses(&["reddit.com".to_owned()], None);
+        update_hosts_file(target_hosts.path(), &hosts, false, HostsFormat::EtcHosts).unwrap();
+        let enabled = std::fs::read_to_string(&target_hosts).unwrap();
+
+        disable_hosts_file(target_hosts.path()).unwrap();
+        assert!(std::fs::read_to_string(&target_hosts).unwrap().contains("# 127.0.0.1 reddit.com"));
+        update_hosts_file(target_hosts.path(), &hosts, false, HostsFormat::EtcHosts).unwrap();
+
+        assert_eq!(std::fs::read_to_string(&target_hosts).unwrap(), enabled);
+        assert_eq!(remove_conflicting_entries(enabled.as_bytes(), &["reddit.com".to_owned()]).1, Vec::<String>::new());
+    }
+
+    #[test]
+    fn remove_block() {
+        let input = r#"127.0.0.1 localhost
+### START AUTOFORWARD
+127.0.0.1 speil.nais.preprod.local
+127.0.0.1 spleis.nais.preprod.local
+### END AUTOFORWARD
+::1 localhost
+"#.as_bytes();
+
+        let (result, removed) = remove_hosts_block(input).unwrap();
+
+        assert_eq!(str::from_utf8(&result).unwrap(), "127.0.0.1 localhost\n::1 localhost\n");
+        assert_eq!(removed, 2);
+        assert_eq!(remove_hosts_block(&result), None);
+    }
+
+    #[test]
+    fn clean_hosts_is_idempotent() {
+        let target_hosts = tempfile::NamedTempFile::new().unwrap();
+        std::fs::copy(Path::new("testdata/hosts"), target_hosts.path()).unwrap();
+        let original = std::fs::read_to_string(&target_hosts).unwrap();
+        update_hosts_file(target_hosts.path(), &assign_addresses(&["reddit.com".to_owned()], None), false, HostsFormat::EtcHosts).unwrap();
+
+        assert_eq!(clean_hosts_file(target_hosts.path()).unwrap(), 1);
+        let cleaned = std::fs::read_to_string(&target_hosts).unwrap();
+        assert_eq!(clean_hosts_file(target_hosts.path()).unwrap(), 0);
+
+        assert_eq!(std::fs::read_to_string(&target_hosts).unwrap(), cleaned);
+        assert!(!cleaned.contains("AUTOFORWARD"));
+        assert!(original.starts_with(cleaned.trim_end()));
+    }
+
+    #[test]
+    fn writes_dnsmasq_addresses() {
+        let entries = vec![("127.1.0.7".parse().unwrap(), "speil.nais.preprod.local".to_owned())];
+
+        assert_eq!(str::from_utf8(&generate_host_entries(&entries, HostsFormat::Dnsmasq)).unwrap(),
+                   "address=/speil.nais.preprod.local/127.1.0.7\n");
+    }
+
+    #[test]
+    fn replaces_dnsmasq_block_keeping_the_rest_of_the_snippet() {
+        let snippet = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(snippet.path(), "address=/speil.nais.preprod.local/10.0.0.1\nserver=/nav.no/10.0.0.53\n").unwrap();
+
+        let conflicts = update_hosts_file(snippet.path(), &assign_addresses(&["speil.nais.preprod.local".to_owned()], None), true, HostsFormat::Dnsmasq).unwrap();
+        update_hosts_file(snippet.path(), &assign_addresses(&["spleis.nais.preprod.local".to_owned()], None), true, HostsFormat::Dnsmasq).unwrap();
+
+        assert!(conflicts.is_empty());
+        assert_eq!(std::fs::read_to_string(snippet.path()).unwrap(), "address=/speil.nais.preprod.local/10.0.0.1\nserver=/nav.no/10.0.0.53\n\n\
+                   ### START AUTOFORWARD\naddress=/spleis.nais.preprod.local/127.0.0.1\n### END AUTOFORWARD\n");
+        assert_eq!(clean_hosts_file(snippet.path()).unwrap(), 1);
+    }
+
     #[test]
     fn update_hosts_does_not_replace() {
         let hosts = vec!["reddit.com".to_owned()];
         let target_hosts = tempfile::NamedTempFile::new().unwrap();
-        std::fs::copy(Path::new("testdata/hosts"), &target_hosts.path()).unwrap();
-        update_hosts_file(&target_hosts.path(), &hosts).unwrap();
+        std::fs::copy(Path::new("testdata/hosts"), target_hosts.path()).unwrap();
+        update_hosts_file(target_hosts.path(), &assign_addresses(&hosts, None), false, HostsFormat::EtcHosts).unwrap();
         let original = std::fs::read_to_string(&target_hosts).unwrap();
 
-        update_hosts_file(&target_hosts.path(), &hosts).unwrap();
-        update_hosts_file(&target_hosts.path(), &hosts).unwrap();
+        update_hosts_file(target_hosts.path(), &assign_addresses(&hosts, None), false, HostsFormat::EtcHosts).unwrap();
+        update_hosts_file(target_hosts.path(), &assign_addresses(&hosts, None), false, HostsFormat::EtcHosts).unwrap();
 
         let updated = std::fs::read_to_string(&target_hosts).unwrap();
 
         assert_eq!(original, updated);
     }
+
+    #[test]
+    fn writes_entries_to_hosts_file_option() {
+        use structopt::StructOpt;
+
+        let target_hosts = tempfile::NamedTempFile::new().unwrap();
+        let config = Config::from_iter(&["autoforward", "--hosts-file", target_hosts.path().to_str().unwrap()]);
+
+        assert!(is_writable(&config));
+        assert!(try_open(target_hosts.path()).is_ok());
+        assert!(try_open(&target_hosts.path().with_extension("missing")).is_err());
+        write_entries(&config, &assign_addresses(&["speil.nais.preprod.local".to_owned()], None));
+
+        let written = std::fs::read_to_string(&target_hosts).unwrap();
+        assert!(written.contains("127.0.0.1 speil.nais.preprod.local"), "{}", written);
+        assert_eq!(hosts_path(&Config::from_iter(&["autoforward"])), hosts_file());
+    }
 }
@@ -0,0 +1,120 @@
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::{delay_for, Delay};
+
+use crate::tls::ClientStream;
+
+/// A client connection that fails reads and writes once it has made no progress for --idle-timeout, so clients
+/// stalling halfway through a handshake or request don't hold on to their connection. The timeout is paused while
+/// the proxy is working on a request, a slow backend isn't the client's fault.
+pub struct IdleTimeout<S> {
+    inner: S,
+    timeout: Option<Duration>,
+    idle: Option<Delay>,
+    busy: Arc<AtomicUsize>,
+}
+
+/// Marks the connection it belongs to as busy
+#[derive(Clone)]
+pub struct BusyHandle(Arc<AtomicUsize>);
+
+impl BusyHandle {
+    pub fn busy(&self) -> Busy {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        Busy(self.0.clone())
+    }
+}
+
+/// Pauses the idle timeout of a connection until dropped
+pub struct Busy(Arc<AtomicUsize>);
+
+impl Drop for Busy {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl<S> IdleTimeout<S> {
+    /// Wraps the connection, it never times out without a timeout
+    pub fn new(inner: S, timeout: Option<Duration>) -> IdleTimeout<S> {
+        IdleTimeout { inner, timeout, idle: None, busy: Arc::new(AtomicUsize::new(0)) }
+    }
+
+    /// A handle marking the connection busy, for the requests served over it to hold
+    pub fn busy_handle(&self) -> BusyHandle {
+        BusyHandle(self.busy.clone())
+    }
+
+    /// Called when the inner stream is pending, errors out once the connection has been idle too long
+    fn poll_idle<T>(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<T>> {
+        let timeout = match self.timeout {
+            Some(timeout) if self.busy.load(Ordering::SeqCst) == 0 => timeout,
+            // The response written when the request is done restarts the timeout
+            _ => {
+                self.idle = None;
+                return Poll::Pending;
+            }
+        };
+        let idle = self.idle.get_or_insert_with(|| delay_for(timeout));
+        match Pin::new(idle).poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(io::Error::new(io::ErrorKind::TimedOut, "Client connection was idle for too long"))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    /// Restarts the timeout when bytes were read or written, flushing alone doesn't count as progress
+    fn progress<T>(&mut self, result: Poll<io::Result<T>>, transferred: impl Fn(&T) -> bool, cx: &mut Context<'_>) -> Poll<io::Result<T>> {
+        match result {
+            Poll::Pending => self.poll_idle(cx),
+            Poll::Ready(Ok(value)) if transferred(&value) => {
+                self.idle = None;
+                Poll::Ready(Ok(value))
+            }
+            ready => ready,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for IdleTimeout<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        this.progress(result, |&n| n > 0, cx)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for IdleTimeout<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.inner).poll_write(cx, buf);
+        this.progress(result, |&n| n > 0, cx)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.inner).poll_flush(cx);
+        this.progress(result, |_| false, cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+impl<S: ClientStream> ClientStream for IdleTimeout<S> {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
@@ -1,13 +1,246 @@
-use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+
+/// The port nais applications listen on unless their spec says otherwise
+pub const DEFAULT_APPLICATION_PORT: u16 = 8080;
 
 #[derive(Clone, Deserialize, Debug)]
-pub struct KubernetesResponse {
-    pub items: Vec<ApplicationResource>,
+pub struct KubernetesResponse<T = ApplicationResource> {
+    pub items: Vec<T>,
+}
+
+/// The kind of resource applications and their ingresses are discovered from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResourceKind {
+    /// The nais `Application` resource
+    Application,
+    /// `networking.k8s.io/v1` `Ingress`
+    Ingress,
+    /// Gateway API `HTTPRoute`
+    HttpRoute,
+}
+
+impl FromStr for ResourceKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "application" => Ok(ResourceKind::Application),
+            "ingress" => Ok(ResourceKind::Ingress),
+            "httproute" => Ok(ResourceKind::HttpRoute),
+            _ => Err(format!("Expected application, ingress or httproute, got {}", s)),
+        }
+    }
+}
+
+impl ResourceKind {
+    /// The resource name as given to `kubectl get`
+    pub fn resource_name(self) -> &'static str {
+        match self {
+            ResourceKind::Application => "application",
+            ResourceKind::Ingress => "ingresses.networking.k8s.io",
+            ResourceKind::HttpRoute => "httproutes.gateway.networking.k8s.io",
+        }
+    }
+
+    /// Parses the output of `kubectl get -o json` into applications, one for each service routed to
+    pub fn parse_applications(self, json: &[u8]) -> serde_json::Result<Vec<ApplicationResource>> {
+        match self {
+            ResourceKind::Application => Ok(serde_json::from_slice::<KubernetesResponse>(json)?.items),
+            ResourceKind::Ingress => Ok(group_by_service(parse_items::<IngressResource>(json)?.iter().flat_map(IngressResource::routes))),
+            ResourceKind::HttpRoute => Ok(group_by_service(parse_items::<HttpRouteResource>(json)?.iter().flat_map(HttpRouteResource::routes))),
+        }
+    }
+}
+
+fn parse_items<T: DeserializeOwned>(json: &[u8]) -> serde_json::Result<Vec<T>> {
+    Ok(serde_json::from_slice::<KubernetesResponse<T>>(json)?.items)
+}
+
+/// An ingress routed to a port of a service
+struct Route {
+    service: String,
+    namespace: Option<String>,
+    ingress: String,
+    port: String,
+}
+
+fn ingress_url(host: &str, path: Option<&str>) -> String {
+    match path.map(|path| path.trim_end_matches('/')).filter(|path| !path.is_empty()) {
+        Some(path) => format!("https://{}{}", host, path),
+        None => format!("https://{}", host),
+    }
+}
+
+/// Collects the routes into an application per service, keeping the order services were first seen in
+fn group_by_service(routes: impl Iterator<Item = Route>) -> Vec<ApplicationResource> {
+    let mut order = vec![];
+    let mut services: BTreeMap<String, (Option<String>, ApplicationResourceSpec)> = BTreeMap::new();
+    for route in routes {
+        let (_, spec) = services.entry(route.service.clone()).or_insert_with(|| {
+            order.push(route.service.clone());
+            (route.namespace.clone(), ApplicationResourceSpec { ingresses: Some(vec![]), port: None, liveness: None, readiness: None, service_ports: vec![] })
+        });
+        let ingresses = spec.ingresses.get_or_insert_with(Vec::new);
+        if ingresses.contains(&route.ingress) {
+            continue;
+        }
+        ingresses.push(route.ingress.clone());
+        if route.port != "80" {
+            spec.service_ports.push((route.ingress, route.port));
+        }
+    }
+    order.into_iter()
+        .map(|name| {
+            let (namespace, spec) = services.remove(&name).unwrap();
+            ApplicationResource { spec, metadata: ResourceMetadata { name, namespace } }
+        })
+        .collect()
+}
+
+/// The namespace of a routing resource, which the services it routes to share
+#[derive(Default, Deserialize)]
+struct RouteMetadata {
+    namespace: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct IngressResource {
+    #[serde(default)]
+    metadata: RouteMetadata,
+    spec: IngressSpec,
+}
+
+#[derive(Deserialize)]
+struct IngressSpec {
+    #[serde(default)]
+    rules: Vec<IngressRule>,
+}
+
+#[derive(Deserialize)]
+struct IngressRule {
+    host: Option<String>,
+    http: Option<IngressHttp>,
+}
+
+#[derive(Deserialize)]
+struct IngressHttp {
+    paths: Vec<IngressPath>,
+}
+
+#[derive(Deserialize)]
+struct IngressPath {
+    path: Option<String>,
+    backend: IngressBackend,
+}
+
+#[derive(Deserialize)]
+struct IngressBackend {
+    service: Option<IngressServiceBackend>,
+}
+
+#[derive(Deserialize)]
+struct IngressServiceBackend {
+    name: String,
+    port: IngressServicePort,
+}
+
+#[derive(Deserialize)]
+struct IngressServicePort {
+    number: Option<u16>,
+    name: Option<String>,
+}
+
+impl IngressResource {
+    /// Every path routed to a service. Rules without a host can't be told apart by the proxy and are left out.
+    fn routes(&self) -> impl Iterator<Item = Route> + '_ {
+        self.spec.rules.iter()
+            .filter_map(|rule| Some((rule.host.as_ref()?, rule.http.as_ref()?)))
+            .flat_map(move |(host, http)| http.paths.iter().filter_map(move |path| {
+                let service = path.backend.service.as_ref()?;
+                let port = service.port.number.map(|number| number.to_string()).or_else(|| service.port.name.clone())?;
+                Some(Route {
+                    service: service.name.clone(),
+                    namespace: self.metadata.namespace.clone(),
+                    ingress: ingress_url(host, path.path.as_deref()),
+                    port,
+                })
+            }))
+    }
+}
+
+#[derive(Deserialize)]
+struct HttpRouteResource {
+    #[serde(default)]
+    metadata: RouteMetadata,
+    spec: HttpRouteSpec,
+}
+
+#[derive(Deserialize)]
+struct HttpRouteSpec {
+    #[serde(default)]
+    hostnames: Vec<String>,
+    #[serde(default)]
+    rules: Vec<HttpRouteRule>,
+}
+
+#[derive(Deserialize)]
+struct HttpRouteRule {
+    #[serde(default)]
+    matches: Vec<HttpRouteMatch>,
+    #[serde(default, rename = "backendRefs")]
+    backend_refs: Vec<HttpRouteBackend>,
+}
+
+#[derive(Deserialize)]
+struct HttpRouteMatch {
+    path: Option<HttpRoutePath>,
+}
+
+#[derive(Deserialize)]
+struct HttpRoutePath {
+    value: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct HttpRouteBackend {
+    name: String,
+    /// Only services can be port-forwarded to
+    kind: Option<String>,
+    port: Option<u16>,
+}
+
+impl HttpRouteResource {
+    /// Every path of every hostname routed to the first service backend of a rule, weights aren't taken into account
+    fn routes(&self) -> impl Iterator<Item = Route> + '_ {
+        self.spec.rules.iter()
+            .filter_map(|rule| {
+                let backend = rule.backend_refs.iter().find(|backend| backend.kind.as_deref().unwrap_or("Service") == "Service")?;
+                let paths = rule.matches.iter()
+                    .map(|m| m.path.as_ref().and_then(|path| path.value.as_deref()))
+                    .collect::<Vec<_>>();
+                Some((backend, if paths.is_empty() { vec![None] } else { paths }))
+            })
+            .flat_map(move |(backend, paths)| self.spec.hostnames.iter().flat_map(move |host| {
+                paths.clone().into_iter().map(move |path| Route {
+                    service: backend.name.clone(),
+                    namespace: self.metadata.namespace.clone(),
+                    ingress: ingress_url(host, path),
+                    port: backend.port.unwrap_or(80).to_string(),
+                })
+            }))
+    }
 }
 
 #[derive(Clone, Deserialize, Debug)]
 pub struct ResourceMetadata {
     pub name: String,
+    /// Left out when the resource was listed without a namespace, e.g. by a test
+    #[serde(default)]
+    pub namespace: Option<String>,
 }
 
 #[derive(Clone, Deserialize, Debug)]
@@ -19,11 +252,104 @@ pub struct ApplicationResource {
 #[derive(Clone, Deserialize, Debug)]
 pub struct ApplicationResourceSpec {
     pub ingresses: Option<Vec<String>>,
+    pub port: Option<u16>,
     pub liveness: Option<HealthCheck>,
     pub readiness: Option<HealthCheck>,
+    /// Ingresses routed to another service port than 80, only known when discovering from routing resources
+    #[serde(skip)]
+    pub service_ports: Vec<(String, String)>,
 }
 
-#[derive(Clone, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
 pub struct HealthCheck {
     pub path: String,
+    /// The container port the check is served on, the application port if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scheme: Option<HealthScheme>,
+}
+
+impl HealthCheck {
+    /// A plain HTTP check on the application port
+    pub fn path(path: &str) -> HealthCheck {
+        HealthCheck { path: path.to_owned(), port: None, scheme: None }
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum HealthScheme {
+    #[serde(rename = "HTTP", alias = "http")]
+    Http,
+    #[serde(rename = "HTTPS", alias = "https")]
+    Https,
+}
+
+impl HealthScheme {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            HealthScheme::Http => "http",
+            HealthScheme::Https => "https",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ingresses(application: &ApplicationResource) -> Vec<&str> {
+        application.spec.ingresses.iter().flatten().map(String::as_str).collect()
+    }
+
+    #[test]
+    fn groups_ingress_paths_by_service() {
+        let json = br#"{"items": [{"metadata": {"name": "speil", "namespace": "tbd"}, "spec": {"rules": [
+            {"host": "speil.nais.preprod.local", "http": {"paths": [
+                {"path": "/", "pathType": "Prefix", "backend": {"service": {"name": "speil", "port": {"number": 80}}}},
+                {"path": "/api", "pathType": "Prefix", "backend": {"service": {"name": "spleis", "port": {"name": "http"}}}}
+            ]}},
+            {"http": {"paths": [{"path": "/", "backend": {"service": {"name": "catch-all", "port": {"number": 80}}}}]}},
+            {"host": "speil.intern.nav.no", "http": {"paths": [
+                {"backend": {"service": {"name": "speil", "port": {"number": 8080}}}}
+            ]}}
+        ]}}]}"#;
+
+        let applications = ResourceKind::Ingress.parse_applications(json).unwrap();
+
+        assert_eq!(applications.len(), 2);
+        assert_eq!(applications[0].metadata.name, "speil");
+        assert_eq!(applications[0].metadata.namespace.as_deref(), Some("tbd"));
+        assert_eq!(ingresses(&applications[0]), vec!["https://speil.nais.preprod.local", "https://speil.intern.nav.no"]);
+        assert_eq!(applications[0].spec.service_ports, vec![("https://speil.intern.nav.no".to_owned(), "8080".to_owned())]);
+        assert_eq!(applications[1].metadata.name, "spleis");
+        assert_eq!(ingresses(&applications[1]), vec!["https://speil.nais.preprod.local/api"]);
+        assert_eq!(applications[1].spec.service_ports, vec![("https://speil.nais.preprod.local/api".to_owned(), "http".to_owned())]);
+    }
+
+    #[test]
+    fn maps_http_route_hostnames_and_paths_to_service() {
+        let json = br#"{"items": [{"spec": {
+            "hostnames": ["speil.nais.preprod.local"],
+            "rules": [
+                {"matches": [{"path": {"type": "PathPrefix", "value": "/api"}}], "backendRefs": [{"name": "spleis", "port": 8080}]},
+                {"backendRefs": [{"name": "bucket", "kind": "Backend"}, {"name": "speil"}]}
+            ]
+        }}]}"#;
+
+        let applications = ResourceKind::HttpRoute.parse_applications(json).unwrap();
+
+        assert_eq!(applications.iter().map(|app| app.metadata.name.as_str()).collect::<Vec<_>>(), vec!["spleis", "speil"]);
+        assert_eq!(ingresses(&applications[0]), vec!["https://speil.nais.preprod.local/api"]);
+        assert_eq!(applications[0].spec.service_ports.len(), 1);
+        assert_eq!(ingresses(&applications[1]), vec!["https://speil.nais.preprod.local"]);
+        assert!(applications[1].spec.service_ports.is_empty());
+    }
+
+    #[test]
+    fn parses_resource_kinds() {
+        assert_eq!("ingress".parse(), Ok(ResourceKind::Ingress));
+        assert_eq!("httproute".parse(), Ok(ResourceKind::HttpRoute));
+        assert!("service".parse::<ResourceKind>().is_err());
+    }
 }
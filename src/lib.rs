@@ -0,0 +1,39 @@
+extern crate futures_util;
+extern crate hyper;
+#[cfg(unix)]
+extern crate nix;
+extern crate pin_utils;
+extern crate regex;
+extern crate rustls;
+extern crate serde_json;
+extern crate tokio;
+extern crate tokio_rustls;
+
+pub mod access_log;
+pub mod admin;
+pub mod atomic_file;
+pub mod basic_auth;
+pub mod body_limit;
+pub mod cache;
+pub mod circuit_breaker;
+pub mod cluster;
+pub mod concurrency_limit;
+pub mod config;
+pub mod connections;
+pub mod error_pages;
+pub mod events;
+pub mod held_body;
+pub mod metrics;
+pub mod provider;
+pub mod preflight;
+pub mod proxy;
+pub mod rate_limit;
+pub mod responses;
+pub mod state_file;
+pub mod kubernetes;
+pub mod tls;
+pub mod tunnel;
+pub mod upstream;
+pub mod forwarding;
+pub mod hosts;
+pub mod idle_timeout;
@@ -1,122 +1,376 @@
-extern crate futures_util;
-extern crate hyper;
 #[cfg(unix)]
-extern crate nix;
-extern crate pin_utils;
-extern crate regex;
-extern crate rustls;
-extern crate serde_json;
-extern crate tokio;
-extern crate tokio_rustls;
-
-use std::convert::Infallible;
-use std::str::FromStr;
+use std::fs;
+use std::collections::HashSet;
+use std::io;
+use std::net::IpAddr;
+#[cfg(unix)]
+use std::path::Path;
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
 use std::sync::Arc;
 use std::time::Duration;
 
-use hyper::{Body, Client, Request, Response, Server, StatusCode, Uri};
-use hyper::service::{make_service_fn, service_fn};
+use futures_util::future::join;
+use futures_util::stream::{self, StreamExt};
+use structopt::StructOpt;
 use tokio::net::TcpListener;
-use tokio::sync::Mutex;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, Mutex};
 
-use forwarding::State;
-use crate::forwarding::ForwardError;
+use autoforward::config::{Command, Config};
+use autoforward::forwarding::{self, State};
+use autoforward::{hosts, preflight, proxy, tls};
+#[cfg(unix)]
+use autoforward::access_log;
 
-mod kubernetes;
-mod tls;
-mod forwarding;
-mod hosts;
+/// Prints what the server certificate is valid for, warning about every discovered host it doesn't cover
+async fn print_cert_info(config: &Arc<Config>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let info = tls::cert_info(&config.cert)?;
+    println!("Certificate {}", config.cert.display());
+    println!("  Subject:    {}", info.subject);
+    println!("  DNS names:  {}", info.subject_alt_names.join(", "));
+    println!("  Not before: {}", info.not_before);
+    println!("  Not after:  {}", info.not_after);
+    let state = State::new(config.clone()).await?;
+    let uncovered = state.hostnames().into_iter().filter(|host| !info.covers(host)).collect::<Vec<_>>();
+    if uncovered.is_empty() {
+        println!("All discovered hosts are covered by the certificate");
+    }
+    for host in uncovered {
+        println!("WARNING: {} is not covered by the certificate, clients will reject it", host);
+    }
+    Ok(())
+}
 
-#[cfg(unix)]
-fn update_hosts_on_root(state: &State) {
-    let uid = nix::unistd::getuid();
-    if uid.is_root() {
-        println!("Process started as root. Updating hosts entries");
-        hosts::update_hosts_file(hosts::hosts_file(), &state.hostnames());
-    } else {
-        println!("Unable to update hosts entries, application needs to be run as root");
+/// Checks everything autoforward needs to run without binding any ports or touching the hosts file, printing each
+/// problem found. Returns whether there were none.
+async fn check(config: &Arc<Config>) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+    let mut problems = vec![];
+    let key = tls::key_file(&config.cert, config.key.as_deref());
+    let tls_config = tls::server_config(&config.cert, &key, config.tls_min_version, config.alpn, config.client_ca.as_deref());
+    if let Err(e) = &tls_config {
+        problems.push(format!("Could not load the certificate {} and key {}: {}", config.cert.display(), key.display(), e));
+    }
+    if !config.no_hosts {
+        let path = hosts::hosts_path(config);
+        if let Err(e) = hosts::try_open(path) {
+            problems.push(format!("{} can't be written ({}), run as root or use --hosts-file", path.display(), e));
+        }
     }
+    let state = State::without_caching(config.clone()).await;
+    let hostnames = state.hostnames();
+    if hostnames.is_empty() {
+        problems.push("No applications were discovered, are you connected to navtunnel?".to_owned());
+    }
+    problems.extend(state.warnings().iter().cloned());
+    if tls_config.is_ok() {
+        match tls::cert_info(&config.cert) {
+            Ok(info) => problems.extend(hostnames.iter()
+                .filter(|host| !info.covers(host))
+                .map(|host| format!("{} is not covered by the certificate, clients will reject it", host))),
+            Err(e) => problems.push(format!("Could not read the certificate {}: {}", config.cert.display(), e)),
+        }
+    }
+    println!("Discovered {} hosts in {} applications", hostnames.len(), state.routes().iter()
+        .map(|route| (route.application, route.namespace, route.context)).collect::<HashSet<_>>().len());
+    for problem in &problems {
+        println!("Problem: {}", problem);
+    }
+    if problems.is_empty() {
+        println!("Everything looks good");
+    }
+    Ok(problems.is_empty())
 }
 
-#[tokio::main]
-pub async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+/// Where the proxy accepts connections from clients. The TCP listener is joined by the listeners sent by
+/// `LoopbackListeners`, if any.
+enum Listener {
+    Tcp(TcpListener, Option<mpsc::UnboundedReceiver<TcpListener>>),
     #[cfg(unix)]
-    let mut tcp = if nix::unistd::getuid().is_root() {
-        TcpListener::bind(&"127.0.0.1:443").await?
-    } else {
-        TcpListener::bind(&"127.0.0.1:8443").await?
-    };
+    Unix(UnixListener),
+}
+
+impl Listener {
+    #[cfg(unix)]
+    async fn bind(config: &Config) -> io::Result<Listener> {
+        if let Some(path) = &config.unix_socket {
+            // A socket left behind by an earlier run would make binding fail, anything else is left alone
+            if fs::symlink_metadata(path).map(|metadata| metadata.file_type().is_socket()).unwrap_or(false) {
+                fs::remove_file(path)?;
+            }
+            return Ok(Listener::Unix(UnixListener::bind(path)?));
+        }
+        let tcp = if nix::unistd::getuid().is_root() {
+            TcpListener::bind(&"127.0.0.1:443").await?
+        } else {
+            TcpListener::bind(&"127.0.0.1:8443").await?
+        };
+        Ok(Listener::Tcp(tcp, None))
+    }
+
     #[cfg(not(unix))]
-    let mut tcp = TcpListener::bind(&"127.0.0.1:443")
-        .await
-        .context("Autoforward needs to be run as administrator on Windows to bind on port 443 and update hosts file")?;
-    let state = {
-        let state = State::new(vec!["dev-fss".to_owned(), "prod-fss".to_owned()], vec!["default".to_owned(), "tbd".to_owned()]).await?;
-        #[cfg(unix)]
-        update_hosts_on_root(&state);
-        #[cfg(not(unix))]
-        hosts::update_hosts_file(hosts::hosts_file(), &state.hostnames());
-
-        Arc::new(Mutex::new(state))
-    };
+    async fn bind(_config: &Config) -> io::Result<Listener> {
+        TcpListener::bind(&"127.0.0.1:443")
+            .await
+            .map(|tcp| Listener::Tcp(tcp, None))
+            .map_err(|e| io::Error::new(e.kind(), format!(
+                "Autoforward needs to be run as administrator on Windows to bind on port 443 and update hosts file: {}", e)))
+    }
 
-    // TODO?: nix::unistd::setuid(Uid::from_raw(unimplemented!())).unwrap();
+    /// Lets connections be accepted on other loopback addresses as well, on the same port
+    fn loopback_listeners(&mut self) -> io::Result<Option<LoopbackListeners>> {
+        match self {
+            Listener::Tcp(tcp, added) => {
+                let (sender, receiver) = mpsc::unbounded_channel();
+                *added = Some(receiver);
+                Ok(Some(LoopbackListeners { port: tcp.local_addr()?.port(), bound: HashSet::new(), sender }))
+            }
+            #[cfg(unix)]
+            Listener::Unix(_) => Ok(None),
+        }
+    }
 
-    let local_state = state.clone();
+    async fn serve(self, tls_config: rustls::ServerConfig, state: Arc<Mutex<State>>, config: Arc<Config>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        match self {
+            Listener::Tcp(tcp, None) => proxy::serve(tcp, tls_config, state, config).await,
+            Listener::Tcp(tcp, Some(added)) => {
+                proxy::serve(stream::select(tcp, added.flatten_unordered(None)), tls_config, state, config).await
+            }
+            #[cfg(unix)]
+            Listener::Unix(unix) => proxy::serve(unix, tls_config, state, config).await,
+        }
+    }
+}
 
-    tokio::spawn(async move {
-        loop {
-            local_state.lock().await.tick().await;
-            tokio::time::delay_for(Duration::from_secs(10)).await;
+/// Binds a listener on each loopback address hosts are given with --loopback-range
+struct LoopbackListeners {
+    port: u16,
+    bound: HashSet<IpAddr>,
+    sender: mpsc::UnboundedSender<TcpListener>,
+}
+
+impl LoopbackListeners {
+    /// Listens on the addresses not bound yet. 127.0.0.1 is left out, the proxy already listens on it. An address
+    /// that can't be bound is only warned about, its host is still reached by the Host header on 127.0.0.1.
+    async fn listen_on(&mut self, hosts: &[(IpAddr, String)]) {
+        for (address, host) in hosts {
+            if *address == IpAddr::from([127, 0, 0, 1]) || !self.bound.insert(*address) {
+                continue;
+            }
+            match TcpListener::bind((*address, self.port)).await {
+                Ok(listener) => {
+                    // The receiver only goes away when the proxy stops
+                    let _ = self.sender.send(listener);
+                }
+                Err(e) => println!("Warning: could not listen on {}:{} for {}: {}. Requests for it are routed by their Host \
+                                    header only. On macOS, add the address to lo0 with `sudo ifconfig lo0 alias {}`.",
+                                   address, self.port, host, e, address),
+            }
         }
-    });
+    }
+}
 
-    let service_fun = make_service_fn(move |_| {
-        let inner = state.clone();
-        async {
-            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
-                handle_req(req, inner.clone())
-            }))
+/// Comments the hosts entries back in, or out, with --disable-hosts-on-exit
+fn set_hosts_enabled(config: &Config, enabled: bool) {
+    if !config.disable_hosts_on_exit || config.no_hosts || !hosts::is_writable(config) {
+        return;
+    }
+    let path = hosts::hosts_path(config);
+    let result = if enabled { hosts::enable_hosts_file(path) } else { hosts::disable_hosts_file(path) };
+    match result {
+        Ok(0) => {}
+        Ok(count) if enabled => println!("Enabled {} hosts entries from the last run", count),
+        Ok(count) => println!("Disabled {} hosts entries", count),
+        Err(e) => println!("{}", hosts::update_failure_message(path, &e)),
+    }
+}
+
+/// Removes the hosts entries as a last resort when shutting down is stuck, so hosts aren't left pointing at nothing.
+/// With --disable-hosts-on-exit they are commented out instead, to be enabled again on the next start
+fn remove_hosts_entries(config: &Config) {
+    if config.no_hosts || !hosts::is_writable(config) {
+        return;
+    }
+    let path = hosts::hosts_path(config);
+    if let Err(e) = hosts::clean_hosts_file(path) {
+        println!("{}", hosts::update_failure_message(path, &e));
+    }
+}
+
+/// Writes the hosts entries unless --no-hosts is set, listening on the loopback address of every host
+async fn update_hosts(addresses: &[(IpAddr, String)], config: &Config, loopback: &mut Option<LoopbackListeners>) {
+    if !config.no_hosts {
+        hosts::write_entries(config, addresses);
+    }
+    if let Some(loopback) = loopback {
+        loopback.listen_on(addresses).await;
+    }
+}
+
+/// Reloads the applications when receiving SIGHUP
+#[cfg(unix)]
+fn reload_on_hangup(state: Arc<Mutex<State>>) -> io::Result<()> {
+    let mut hangups = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            state.lock().await.request_reload();
         }
     });
-    let server = Server::builder(tls::tls_acceptor(&mut tcp).await?)
-        .serve(service_fun);
-
-    server.await?;
     Ok(())
 }
 
-async fn handle_req(mut req: Request<Body>, state: Arc<Mutex<State>>) -> Result<Response<Body>, ForwardError> {
-    let client = Client::new();
-    let request_host = if let Some(host) = req.headers().get("Host") {
-        host.to_str().map(|h| {
-            if let Some(index) = h.find(':') {
-                h[0..index].to_owned()
-            } else {
-                h.to_owned()
+/// Resolves on Ctrl-C, or on SIGTERM as sent by service managers and `kill`, so both shut down cleanly
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        if let Ok(mut terminate) = signal(SignalKind::terminate()) {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            }
+            return;
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+#[tokio::main]
+pub async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut config = Config::from_args();
+    if let Some(Command::Clean) = config.command {
+        let removed = hosts::clean_hosts_file(hosts::hosts_path(&config))?;
+        println!("Removed {} autoforward entries from {}", removed, hosts::hosts_path(&config).display());
+        return Ok(());
+    }
+    if let Err(message) = config.validate() {
+        eprintln!("{}", message);
+        std::process::exit(1);
+    }
+    #[cfg(unix)]
+    if config.access_log.as_deref() == Some(Path::new("-")) {
+        access_log::reserve_stdout()?;
+    }
+    if config.has_context_patterns() {
+        match config.cli.contexts().await {
+            Ok(available) => match config.expand_contexts(&available) {
+                Ok(warnings) => for warning in warnings {
+                    println!("Warning: {}", warning);
+                },
+                Err(message) => {
+                    eprintln!("{}", message);
+                    std::process::exit(1);
+                }
+            },
+            Err(message) => {
+                eprintln!("Could not list the contexts to match --context against: {}", message);
+                std::process::exit(1);
             }
-        }).unwrap()
-    } else {
-        return Ok(Response::builder()
-            .status(StatusCode::BAD_REQUEST)
-            .body(Body::from(format!("The proxy requires a Host header to work.")))
-            .unwrap());
+        }
+    }
+    let config = Arc::new(config);
+    if let Some(address) = config.forward_address.filter(|address| !address.is_loopback()) {
+        println!("Warning: port-forwards bind to {} and can be reached from the network", address);
+    }
+    if !config.no_preflight {
+        if let Err(message) = preflight::preflight(config.cli, config.contexts.first().map(String::as_str)).await {
+            eprintln!("{}", message);
+            std::process::exit(1);
+        }
+    }
+    if let Some(Command::DumpRoutes) = config.command {
+        let state = State::new(config.clone()).await?;
+        println!("{}", serde_json::to_string_pretty(&state.routes())?);
+        return Ok(());
+    }
+    if let Some(Command::Check) = config.command {
+        if !check(&config).await? {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    if config.print_cert_info {
+        print_cert_info(&config).await?;
+        return Ok(());
+    }
+    let tls_config = tls::server_config(&config.cert, &tls::key_file(&config.cert, config.key.as_deref()),
+                                        config.tls_min_version, config.alpn, config.client_ca.as_deref())?;
+
+    let mut listener = Listener::bind(&config).await?;
+    let mut loopback = match config.loopback_range {
+        Some(_) => listener.loopback_listeners()?,
+        None => None,
     };
-    let uri = if let Some(portforward) = state.lock().await.fetch_address(&request_host, req.uri().path()).await? {
-        let request_uri = req.uri();
-        format!("http://{}:{}{}", portforward.host, portforward.port, request_uri.path())
-    } else {
-        return Ok(Response::builder()
-            .status(StatusCode::NOT_FOUND)
-            .body(Body::from(format!("No service found for {}", request_host)))
-            .unwrap());
+    set_hosts_enabled(&config, true);
+    // Without a cache the proxy starts out knowing no applications, each namespace is added as it is discovered
+    // The hosts entries are only written again when they change, which most merged namespaces don't do
+    let mut written = None;
+    let state = match State::from_cache(config.clone()) {
+        Some(state) => {
+            let addresses = state.host_addresses();
+            update_hosts(&addresses, &config, &mut loopback).await;
+            written = Some(addresses);
+            state
+        }
+        None => State::undiscovered(config.clone()),
     };
-    println!("Handling request for {}, forwarding to {}", &request_host, &uri);
-    *req.uri_mut() = Uri::from_str(uri.as_str()).unwrap();
-    Ok::<_, _>(match client.request(req).await {
-        Ok(value) => value,
-        Err(e) => Response::builder()
-            .status(StatusCode::BAD_GATEWAY)
-            .body(Body::from(format!("{}", e))).unwrap(),
-    })
+    let state = Arc::new(Mutex::new(state));
+    {
+        let (state, config) = (state.clone(), config.clone());
+        let reload_requests = state.lock().await.reload_requests();
+        tokio::spawn(async move {
+            // Reloads run one at a time, requests made meanwhile lead to a single reload after it
+            let mut warmed_up = false;
+            loop {
+                let (sender, mut merged) = mpsc::unbounded_channel();
+                let refresh = State::refresh(&state, move |state| {
+                    let _ = sender.send(state.host_addresses());
+                });
+                let apply = async {
+                    while let Some(addresses) = merged.recv().await {
+                        if written.as_ref() != Some(&addresses) {
+                            update_hosts(&addresses, &config, &mut loopback).await;
+                            written = Some(addresses);
+                        }
+                    }
+                };
+                join(refresh, apply).await;
+                state.lock().await.set_ready();
+                if !warmed_up {
+                    tokio::spawn(forwarding::warmup(state.clone()));
+                    warmed_up = true;
+                }
+                reload_requests.notified().await;
+                println!("Reloading applications");
+            }
+        });
+    }
+    #[cfg(unix)]
+    reload_on_hangup(state.clone())?;
+
+    // TODO?: nix::unistd::setuid(Uid::from_raw(unimplemented!())).unwrap();
+
+    tokio::spawn(forwarding::run_maintenance(state.clone(), Duration::from_secs(config.tick_interval)));
+
+    tokio::select! {
+        result = listener.serve(tls_config, state.clone(), config.clone()) => result,
+        _ = shutdown_signal() => {
+            println!("Shutting down, closing port-forwards");
+            if !forwarding::shut_down(&state, Duration::from_secs(config.shutdown_timeout)).await {
+                println!("Shutting down took longer than {} seconds, killing the port-forwards and exiting",
+                         config.shutdown_timeout);
+                forwarding::kill_port_forwards();
+                if config.disable_hosts_on_exit {
+                    set_hosts_enabled(&config, false);
+                } else {
+                    remove_hosts_entries(&config);
+                }
+                std::process::exit(1);
+            }
+            set_hosts_enabled(&config, false);
+            Ok(())
+        }
+    }
 }
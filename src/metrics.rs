@@ -0,0 +1,158 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// The host label of requests that didn't match any application. Labeling them by the Host they were sent for
+/// would add a histogram for every host a client makes up.
+pub const UNKNOWN_HOST: &str = "(unknown)";
+
+/// Upper bounds in seconds of the latency histogram buckets, the Prometheus client defaults
+const BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Default)]
+pub struct Metrics {
+    pub connections_active: AtomicUsize,
+    pub connections_queued: AtomicUsize,
+    pub connections_rejected: AtomicUsize,
+    pub upstream_requests: AtomicUsize,
+    pub upstream_requests_active: AtomicUsize,
+    pub upstream_connections_created: AtomicUsize,
+    pub upstream_connections_open: AtomicUsize,
+    /// Time spent finding or opening the port-forward for a request
+    pub forward_seconds: Histograms,
+    /// Time from sending a request to the port-forward until its response headers arrived
+    pub upstream_response_seconds: Histograms,
+}
+
+/// A latency histogram per host
+#[derive(Default)]
+pub struct Histograms {
+    hosts: Mutex<BTreeMap<String, Histogram>>,
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [usize; BUCKETS.len()],
+    sum: f64,
+    count: usize,
+}
+
+impl Histograms {
+    pub fn observe(&self, host: &str, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let mut hosts = self.hosts.lock().unwrap();
+        let histogram = hosts.entry(host.to_owned()).or_default();
+        if let Some(bucket) = BUCKETS.iter().position(|&bound| seconds <= bound) {
+            histogram.buckets[bucket] += 1;
+        }
+        histogram.sum += seconds;
+        histogram.count += 1;
+    }
+
+    fn render(&self, output: &mut String, name: &str, help: &str) {
+        writeln!(output, "# HELP {} {}", name, help).unwrap();
+        writeln!(output, "# TYPE {} histogram", name).unwrap();
+        for (host, histogram) in self.hosts.lock().unwrap().iter() {
+            let host = escape_label(host);
+            let mut cumulative = 0;
+            for (bound, count) in BUCKETS.iter().zip(&histogram.buckets) {
+                cumulative += count;
+                writeln!(output, "{}_bucket{{host=\"{}\",le=\"{}\"}} {}", name, host, bound, cumulative).unwrap();
+            }
+            writeln!(output, "{}_bucket{{host=\"{}\",le=\"+Inf\"}} {}", name, host, histogram.count).unwrap();
+            writeln!(output, "{}_sum{{host=\"{}\"}} {}", name, host, histogram.sum).unwrap();
+            writeln!(output, "{}_count{{host=\"{}\"}} {}", name, host, histogram.count).unwrap();
+        }
+    }
+}
+
+impl Metrics {
+    /// Renders the metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+        write_metric(&mut output, "autoforward_connections_active", "gauge",
+                     "Connections currently being served", load(&self.connections_active));
+        write_metric(&mut output, "autoforward_connections_queued", "gauge",
+                     "Connections waiting for the connection limit", load(&self.connections_queued));
+        write_metric(&mut output, "autoforward_connections_rejected_total", "counter",
+                     "Connections rejected by the connection limit", load(&self.connections_rejected));
+        write_metric(&mut output, "autoforward_upstream_requests_total", "counter",
+                     "Requests sent to port-forwards", load(&self.upstream_requests));
+        write_metric(&mut output, "autoforward_upstream_connections_created_total", "counter",
+                     "Connections opened to port-forwards", load(&self.upstream_connections_created));
+        write_metric(&mut output, "autoforward_upstream_connections_reused_total", "counter",
+                     "Requests sent on a pooled connection", self.upstream_connections_reused());
+        write_metric(&mut output, "autoforward_upstream_connections_open", "gauge",
+                     "Connections to port-forwards held by the pool", load(&self.upstream_connections_open));
+        write_metric(&mut output, "autoforward_upstream_connections_idle", "gauge",
+                     "Pooled connections not waiting for a response", self.upstream_connections_idle());
+        self.forward_seconds.render(&mut output, "autoforward_forward_seconds",
+                                    "Time spent finding or opening the port-forward for a request");
+        self.upstream_response_seconds.render(&mut output, "autoforward_upstream_response_seconds",
+                                              "Time until the port-forward answered a request with its headers");
+        output
+    }
+
+    // hyper doesn't expose its pool, so reuse and idleness are derived from what passes through the connector.
+    // A request that didn't open a connection reused one, and an open connection without a request in flight is
+    // idle. Both are approximate: a connection hyper opens for a request can end up serving another one.
+    fn upstream_connections_reused(&self) -> usize {
+        load(&self.upstream_requests).saturating_sub(load(&self.upstream_connections_created))
+    }
+
+    fn upstream_connections_idle(&self) -> usize {
+        load(&self.upstream_connections_open).saturating_sub(load(&self.upstream_requests_active))
+    }
+}
+
+fn load(value: &AtomicUsize) -> usize {
+    value.load(Ordering::Relaxed)
+}
+
+/// Escapes a label value, the host comes straight from the client
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn write_metric(output: &mut String, name: &str, kind: &str, help: &str, value: usize) {
+    writeln!(output, "# HELP {} {}", name, help).unwrap();
+    writeln!(output, "# TYPE {} {}", name, kind).unwrap();
+    writeln!(output, "{} {}", name, value).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_prometheus_text_format() {
+        let metrics = Metrics::default();
+        metrics.connections_active.store(3, Ordering::Relaxed);
+
+        let output = metrics.render();
+
+        assert!(output.contains("# TYPE autoforward_connections_active gauge\nautoforward_connections_active 3\n"));
+        assert!(output.contains("autoforward_connections_rejected_total 0\n"));
+    }
+
+    #[test]
+    fn renders_latency_histograms_per_host() {
+        let metrics = Metrics::default();
+        metrics.upstream_response_seconds.observe("speil.nais.preprod.local", Duration::from_millis(30));
+        metrics.upstream_response_seconds.observe("speil.nais.preprod.local", Duration::from_millis(700));
+        metrics.upstream_response_seconds.observe("speil.nais.preprod.local", Duration::from_secs(20));
+        metrics.forward_seconds.observe("spleis\".nais", Duration::from_millis(1));
+
+        let output = metrics.render();
+
+        assert!(output.contains("# TYPE autoforward_upstream_response_seconds histogram\n"));
+        assert!(output.contains("autoforward_upstream_response_seconds_bucket{host=\"speil.nais.preprod.local\",le=\"0.025\"} 0\n"));
+        assert!(output.contains("autoforward_upstream_response_seconds_bucket{host=\"speil.nais.preprod.local\",le=\"0.05\"} 1\n"));
+        assert!(output.contains("autoforward_upstream_response_seconds_bucket{host=\"speil.nais.preprod.local\",le=\"1\"} 2\n"));
+        assert!(output.contains("autoforward_upstream_response_seconds_bucket{host=\"speil.nais.preprod.local\",le=\"+Inf\"} 3\n"));
+        assert!(output.contains("autoforward_upstream_response_seconds_count{host=\"speil.nais.preprod.local\"} 3\n"));
+        assert!(output.contains("autoforward_forward_seconds_count{host=\"spleis\\\".nais\"} 1\n"));
+    }
+}
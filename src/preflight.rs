@@ -0,0 +1,75 @@
+use std::io;
+use std::process::Stdio;
+
+use tokio::process::Command;
+
+use crate::cluster::ClusterCli;
+
+#[derive(Debug)]
+enum Failure {
+    Missing,
+    Failed(String),
+    Io(io::Error),
+}
+
+/// Checks that the cluster tool is installed and, given a context, that its cluster can be reached. Returns a
+/// message explaining what to do when a check fails.
+pub async fn preflight(cli: ClusterCli, context: Option<&str>) -> Result<(), String> {
+    match run(cli.command(cli.client_version_args())).await {
+        Ok(()) => {}
+        Err(Failure::Missing) => return Err(format!(
+            "Could not find {}. Install it and make sure it is on your PATH.", cli.program())),
+        Err(Failure::Failed(stderr)) => return Err(format!(
+            "{} version --client failed, is it installed correctly?\n{}", cli.program(), stderr)),
+        Err(Failure::Io(e)) => return Err(format!("Failed to run {}: {}", cli.program(), e)),
+    }
+    if let Some(context) = context {
+        match run(cli.command(cli.server_version_args(context))).await {
+            Ok(()) => {}
+            Err(Failure::Failed(stderr)) => return Err(format!(
+                "Could not reach the cluster for context {}. Is navtunnel running?\n{}", context, stderr)),
+            Err(e) => return Err(format!("Failed to run {}: {:?}", cli.program(), e)),
+        }
+    }
+    Ok(())
+}
+
+async fn run(mut command: Command) -> Result<(), Failure> {
+    let output = command
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => Failure::Missing,
+            _ => Failure::Io(e),
+        })?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(Failure::Failed(String::from_utf8_lossy(&output.stderr).trim().to_owned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reports_missing_binary() {
+        let result = run(Command::new("autoforward-test-missing-binary")).await;
+
+        assert!(matches!(result, Err(Failure::Missing)));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn reports_stderr_of_failing_command() {
+        let mut command = Command::new("sh");
+        command.args(["-c", "echo 'Unable to connect to the server' >&2; exit 1"]);
+
+        match run(command).await {
+            Err(Failure::Failed(stderr)) => assert_eq!(stderr, "Unable to connect to the server"),
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+}
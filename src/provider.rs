@@ -0,0 +1,103 @@
+use std::io;
+use std::net::IpAddr;
+use std::process::{Output, Stdio};
+
+use futures_util::future::{BoxFuture, FutureExt};
+use tokio::process::Child;
+
+use crate::cluster::ClusterCli;
+use crate::config::Config;
+use crate::forwarding::{ForwardError, ToForwardError};
+use crate::kubernetes::{ApplicationResource, ResourceKind};
+
+/// Discovers applications and opens port-forwards to them. The cluster tool is used outside of tests.
+pub trait ResourceProvider: Send + Sync {
+    /// Lists the applications in the namespace, or in the one the context is set to without a namespace
+    fn applications(&self, context: &str, namespace: Option<&str>, selector: Option<&str>) -> BoxFuture<'static, Result<Vec<ApplicationResource>, ForwardError>>;
+
+    /// Starts a process forwarding `local_port`, or any free local port, to the service, printing
+    /// `Forwarding from <host>:<port> -> <port>` to stdout once it is ready
+    fn port_forward(&self, context: &str, namespace: &str, service: &str, service_port: &str, local_port: Option<u16>) -> io::Result<Child>;
+}
+
+pub struct CliProvider {
+    cli: ClusterCli,
+    kind: ResourceKind,
+    forward_address: Option<IpAddr>,
+}
+
+impl CliProvider {
+    pub fn new(config: &Config) -> CliProvider {
+        CliProvider {
+            cli: config.cli,
+            kind: config.resource_kind,
+            forward_address: config.forward_address,
+        }
+    }
+}
+
+impl ResourceProvider for CliProvider {
+    fn applications(&self, context: &str, namespace: Option<&str>, selector: Option<&str>) -> BoxFuture<'static, Result<Vec<ApplicationResource>, ForwardError>> {
+        let (kind, program) = (self.kind, self.cli.program());
+        let mut command = self.cli.command(self.cli.get_applications_args(context, namespace, kind, selector));
+        // Dropping the future when discovery times out stops kubectl as well
+        command.kill_on_drop(true);
+        async move {
+            let cmd = command
+                .output()
+                .await
+                .context(format!("Failed to execute {} get application", program))?;
+            parse_output(program, kind, cmd)
+        }.boxed()
+    }
+
+    fn port_forward(&self, context: &str, namespace: &str, service: &str, service_port: &str, local_port: Option<u16>) -> io::Result<Child> {
+        self.cli.command(self.cli.port_forward_args(context, namespace, service, service_port, self.forward_address, local_port))
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            // A reconnect given up halfway drops kubectl without closing it
+            .kill_on_drop(true)
+            .spawn()
+    }
+}
+
+/// The applications listed by a finished `kubectl get`, or an error marking the context and namespace as failed when
+/// kubectl failed or printed something else than expected, e.g. for an older CRD or from an HTML proxy page
+fn parse_output(program: &str, kind: ResourceKind, output: Output) -> Result<Vec<ApplicationResource>, ForwardError> {
+    if !output.status.success() {
+        return Err(ForwardError {
+            message: format!("Failed to execute {} get application, got invalid exit code. Is navtunnel running?", program).into(),
+            original: io::Error::other(String::from_utf8_lossy(&output.stderr).trim().to_owned()),
+            route: None,
+            reconnecting: false,
+        });
+    }
+    kind.parse_applications(&output.stdout)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        .context(format!("Failed to parse the output of {} get application", program))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn output(code: i32, stdout: &[u8], stderr: &[u8]) -> Output {
+        use std::os::unix::process::ExitStatusExt;
+        use std::process::ExitStatus;
+
+        Output { status: ExitStatus::from_raw(code << 8), stdout: stdout.to_vec(), stderr: stderr.to_vec() }
+    }
+
+    #[test]
+    fn fails_on_unexpected_kubectl_output() {
+        let failed = parse_output("oc", ResourceKind::Application, output(1, b"", b"proxy error \xff")).unwrap_err();
+        assert!(failed.original.to_string().starts_with("proxy error"));
+        assert!(failed.to_string().starts_with("Failed to execute oc get application"));
+
+        let html = parse_output("kubectl", ResourceKind::Application, output(0, b"<html>Sign in</html>", b"")).unwrap_err();
+        assert_eq!(html.original.kind(), io::ErrorKind::InvalidData);
+
+        let empty = parse_output("kubectl", ResourceKind::Application, output(0, br#"{"items": []}"#, b"")).unwrap();
+        assert!(empty.is_empty());
+    }
+}
@@ -0,0 +1,536 @@
+use std::convert::Infallible;
+use std::error::Error;
+use std::io;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use hyper::{Body, Method, Request, Response, Server, StatusCode, Uri, Version};
+use hyper::header::{CONNECTION, HOST, HeaderValue};
+use hyper::http::uri::Authority;
+use futures_util::{Stream, TryStreamExt};
+use hyper::service::{make_service_fn, service_fn};
+use tokio::sync::Mutex;
+
+use crate::{admin, forwarding, tls, tunnel, upstream};
+use crate::access_log::{AccessLog, AccessLogEntry};
+use crate::basic_auth::BasicAuth;
+use crate::body_limit::BodyLimit;
+use crate::config::{Config, HostRewrite, RequestHeader, UpstreamHost, UpstreamPrefix};
+use crate::connections::ConnectionLimit;
+use crate::error_pages::{error_page, with_error_page};
+use crate::forwarding::{ForwardError, Portforward, State};
+use crate::held_body::{HeldBody, hold_until_sent};
+use crate::idle_timeout::IdleTimeout;
+use crate::metrics::{Metrics, UNKNOWN_HOST};
+use crate::responses::{circuit_open_response, error_response, forward_error_response, queue_full_response};
+use crate::tls::{Alpn, AlpnChecked, ClientStream, Sni};
+use crate::upstream::UpstreamClient;
+
+/// Serves requests on the incoming connections, routing them through port-forwards until the server fails
+pub async fn serve<S: ClientStream>(incoming: impl Stream<Item = io::Result<S>> + Send, tls_config: rustls::ServerConfig, state: Arc<Mutex<State>>, config: Arc<Config>) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let access_log = match &config.access_log {
+        Some(path) => Some(Arc::new(AccessLog::open(path)?)),
+        None => None,
+    };
+    let metrics = Arc::new(Metrics::default());
+    let connection_limit = Arc::new(ConnectionLimit::new(config.max_connections, config.over_limit, metrics.clone()));
+    let client = upstream::upstream_client(Duration::from_secs(config.connect_timeout), config.upstream_http2, metrics.clone());
+    let idle_timeout = config.idle_timeout.map(Duration::from_secs);
+    let incoming = incoming.map_ok(move |stream| IdleTimeout::new(stream, idle_timeout));
+    let alpn = config.alpn;
+    let service_fun = make_service_fn(move |conn: &AlpnChecked<IdleTimeout<S>>| {
+        let conn = conn.get_ref();
+        let inner = state.clone();
+        let client = client.clone();
+        let config = config.clone();
+        let metrics = metrics.clone();
+        let access_log = access_log.clone();
+        let connection_limit = connection_limit.clone();
+        let remote_addr = conn.get_ref().0.peer_addr();
+        let local_addr = conn.get_ref().0.local_addr();
+        let sni = tls::sni(conn);
+        let busy = conn.get_ref().0.busy_handle();
+        async move {
+            let connection = connection_limit.acquire().await;
+            Ok::<_, Infallible>(service_fn(move |mut req: Request<Body>| {
+                if let Some(sni) = &sni {
+                    req.extensions_mut().insert(sni.clone());
+                }
+                if let Some(local_addr) = local_addr {
+                    req.extensions_mut().insert(Destination(local_addr.ip()));
+                }
+                let rejected = connection.is_none();
+                let access_log = access_log.clone();
+                let entry = access_log.as_ref().map(|_| AccessLogEntry::from_request(&req, remote_addr));
+                let (inner, client, config, metrics) = (inner.clone(), client.clone(), config.clone(), metrics.clone());
+                let busy = busy.busy();
+                async move {
+                    let response = if rejected {
+                        Ok(over_limit_response())
+                    } else {
+                        // The connection stays busy while the response body is streamed
+                        handle_req(req, inner, client, config, metrics).await.map(|response| hold_until_sent(response, busy))
+                    };
+                    let mut response = response.map(HeldBody::response);
+                    if let (Some(access_log), Some(entry)) = (access_log, entry) {
+                        match &mut response {
+                            // Written once the body is sent, so streamed bodies are counted too
+                            Ok(response) => {
+                                let status = response.status();
+                                response.body_mut().when_sent(move |bytes| access_log.write(&entry.complete(status, bytes)));
+                            }
+                            // hyper drops the connection without an answer
+                            Err(_) => access_log.write(&entry),
+                        }
+                    }
+                    response
+                }
+            }))
+        }
+    });
+    let server = Server::builder(tls::tls_acceptor(incoming, tls_config).await?)
+        .http1_only(alpn == Alpn::Http1)
+        .http2_only(alpn == Alpn::H2)
+        .serve(service_fun);
+
+    server.await?;
+    Ok(())
+}
+
+fn over_limit_response() -> Response<Body> {
+    let mut response = error_response(StatusCode::SERVICE_UNAVAILABLE, "The proxy is serving too many connections, try again later.");
+    response.headers_mut().insert(CONNECTION, HeaderValue::from_static("close"));
+    response
+}
+
+/// Forwards requests from HTTP/2 clients as HTTP/1.1 unless the backends speak HTTP/2, an HTTP/1 client refuses them.
+/// Requests from HTTP/1.0 clients are sent as HTTP/1.1 too, so the backend keeps the pooled connection open. hyper
+/// still answers the client in HTTP/1.0 and closes its connection.
+fn set_upstream_version(req: &mut Request<Body>, upstream_http2: bool) {
+    match req.version() {
+        Version::HTTP_2 if !upstream_http2 => *req.version_mut() = Version::HTTP_11,
+        Version::HTTP_10 => *req.version_mut() = Version::HTTP_11,
+        _ => {}
+    }
+}
+
+/// The address a client connected to, which identifies the host with --loopback-range
+struct Destination(IpAddr);
+
+/// Hosts a request may be routed by: the Host header without its port, the host of an HTTP/2 request URI, then the
+/// SNI of the connection, skipping repeats
+fn candidate_hosts(req: &Request<Body>) -> Vec<String> {
+    let mut candidates = Vec::with_capacity(3);
+    if let Some(host) = host_header(req) {
+        candidates.push(host);
+    }
+    // HTTP/2 requests carry the host in the URI instead of a header
+    if let Some(host) = req.uri().host() {
+        if !candidates.iter().any(|candidate| candidate == host) {
+            candidates.push(host.to_owned());
+        }
+    }
+    if let Some(Sni(sni)) = req.extensions().get::<Sni>() {
+        if !candidates.contains(sni) {
+            candidates.push(sni.clone());
+        }
+    }
+    candidates
+}
+
+/// Whether an HTTP/2 request is for another host than the server name the client gave when connecting. HTTP/1.1
+/// clients naming another host do it on purpose, like `curl -H Host:` does.
+fn is_misdirected(req: &Request<Body>) -> bool {
+    let sni = match req.extensions().get::<Sni>() {
+        Some(Sni(sni)) if req.version() == Version::HTTP_2 => sni,
+        _ => return false,
+    };
+    let host = req.uri().host().map(str::to_owned).or_else(|| host_header(req));
+    host.is_some_and(|host| !host.eq_ignore_ascii_case(sni))
+}
+
+/// The host of the Host header without its port. Like `Uri::host`, an IPv6 address keeps its brackets.
+fn host_header(req: &Request<Body>) -> Option<String> {
+    req.headers().get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .and_then(|host| host.parse::<Authority>().ok())
+        .map(|authority| authority.host().to_owned())
+}
+
+/// Replaces the Host header by the target of the first matching --host-rewrite rule, so the request is routed and
+/// forwarded as if the client had sent that host
+fn rewrite_host(req: &mut Request<Body>, rewrites: &[HostRewrite]) {
+    let rewrite = req.headers().get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .and_then(|host| rewrites.iter().find(|rewrite| rewrite.matches(host)));
+    if let Some(rewrite) = rewrite {
+        let to = rewrite.to.clone();
+        req.headers_mut().insert(HOST, to);
+    }
+}
+
+/// Adds the --request-header headers, replacing those the client sent with the same name
+fn add_request_headers(req: &mut Request<Body>, headers: &[RequestHeader]) {
+    for header in headers {
+        req.headers_mut().insert(header.name.clone(), header.value.clone());
+    }
+}
+
+/// The URI to send the request to through the port-forward, keeping the path and query of the request behind the
+/// --upstream-prefix, if any. Whatever scheme and authority the client sent are replaced, the Host header is dealt
+/// with separately.
+fn build_upstream_uri(portforward: &Portforward, prefix: Option<&str>, req_uri: &Uri) -> Result<Uri, hyper::http::Error> {
+    let mut path_and_query = match prefix {
+        // The slashes where the prefix and path meet are collapsed, the rest of the path is passed on as it came
+        Some(prefix) => format!("{}/{}", prefix.trim_end_matches('/'), req_uri.path().trim_start_matches('/')),
+        None => req_uri.path().to_owned(),
+    };
+    if let Some(query) = req_uri.query() {
+        path_and_query.push('?');
+        path_and_query.push_str(query);
+    }
+    Uri::builder()
+        .scheme("http")
+        .authority(portforward.authority().as_str())
+        .path_and_query(path_and_query.as_str())
+        .build()
+}
+
+/// Sets the Host header the backend sees. Unless it is removed here, hyper keeps it rather than deriving it from the
+/// port-forward address.
+fn set_upstream_host(req: &mut Request<Body>, upstream_host: &UpstreamHost) {
+    match upstream_host {
+        UpstreamHost::Preserve => {
+            // HTTP/2 requests carry the host in the URI instead of a header
+            if !req.headers().contains_key(HOST) {
+                if let Some(authority) = req.uri().authority().and_then(|a| HeaderValue::from_str(a.as_str()).ok()) {
+                    req.headers_mut().insert(HOST, authority);
+                }
+            }
+        }
+        UpstreamHost::Loopback => {
+            req.headers_mut().remove(HOST);
+        }
+        UpstreamHost::Fixed(host) => {
+            req.headers_mut().insert(HOST, host.clone());
+        }
+    }
+}
+
+/// Whether the upstream answered that it couldn't reach its own upstream, which counts against its circuit breaker
+fn is_gateway_failure(status: StatusCode) -> bool {
+    status == StatusCode::BAD_GATEWAY || status == StatusCode::GATEWAY_TIMEOUT
+}
+
+async fn handle_req(mut req: Request<Body>, state: Arc<Mutex<State>>, client: UpstreamClient, config: Arc<Config>, metrics: Arc<Metrics>) -> Result<Response<Body>, ForwardError> {
+    if req.uri().path() == admin::READY_PATH {
+        return Ok(admin::ready_response(&state).await);
+    }
+    if let Some(basic_auth) = config.basic_auth() {
+        let exempt = config.basic_auth_exempt_metrics && req.uri().path() == admin::METRICS_PATH;
+        if !exempt && !basic_auth.authorize(&mut req) {
+            return Ok(BasicAuth::challenge(&req));
+        }
+    }
+    if config.admin && admin::is_admin_request(&req) {
+        return Ok(admin::handle_admin(req, state, metrics).await);
+    }
+    if req.method() == Method::CONNECT {
+        return tunnel::handle_connect(req, state).await;
+    }
+    let body_limit = config.max_body_size.map(|limit| BodyLimit::apply(&mut req, limit));
+    if let Some(body_limit) = body_limit.as_ref().filter(|body_limit| body_limit.exceeded()) {
+        return Ok(body_limit.too_large_response());
+    }
+    if config.strict_sni && is_misdirected(&req) {
+        return Ok(error_response(StatusCode::MISDIRECTED_REQUEST, "The request is for another host than this connection, connect again."));
+    }
+    rewrite_host(&mut req, &config.host_rewrites);
+    let mut candidates = candidate_hosts(&req);
+    if let Some(Destination(address)) = req.extensions().get::<Destination>() {
+        if let Some(host) = state.lock().await.host_for_address(*address) {
+            if !candidates.contains(&host) {
+                candidates.push(host);
+            }
+        }
+    }
+    let request_host = match candidates.first() {
+        Some(host) => host.clone(),
+        None => return Ok(error_response(StatusCode::BAD_REQUEST, "The proxy requires a Host header to work.")),
+    };
+    // Only requests for a path can be forwarded, not the asterisk-form of `OPTIONS * HTTP/1.1`
+    if !req.uri().path().starts_with('/') {
+        return Ok(error_response(StatusCode::BAD_REQUEST, format!("The request URI {} can't be forwarded, it has no path.", req.uri())));
+    }
+    let forward_started = Instant::now();
+    let mut found = None;
+    for host in &candidates {
+        found = match forwarding::fetch_address(&state, host, req.uri().path()).await {
+            Ok(found) => found,
+            // Only hosts of a discovered application fail, so the host is as known as an ingress
+            Err(e) => {
+                metrics.forward_seconds.observe(host, forward_started.elapsed());
+                return Ok(with_error_page(config.error_pages.as_deref(), forward_error_response(&e), &request_host, None).await);
+            }
+        };
+        if found.is_some() {
+            break;
+        }
+    }
+    let portforward = if let Some(portforward) = found {
+        portforward
+    } else {
+        metrics.forward_seconds.observe(UNKNOWN_HOST, forward_started.elapsed());
+        let mut message = format!("No service found for {}", request_host);
+        if config.list_hosts {
+            message.push_str("\n\nKnown hosts:\n");
+            for host in state.lock().await.known_hosts() {
+                message.push_str(&format!("  {}\n", host));
+            }
+        }
+        return Ok(error_page(config.error_pages.as_deref(), StatusCode::NOT_FOUND, message, &request_host, None).await);
+    };
+    let forward_time = forward_started.elapsed();
+    let ingress_host = portforward.ingress_host();
+    metrics.forward_seconds.observe(&ingress_host, forward_time);
+    if let Err(retry_after) = portforward.circuit().check() {
+        return Ok(with_error_page(config.error_pages.as_deref(), circuit_open_response(retry_after), &request_host,
+                                  Some(&portforward.authority())).await);
+    }
+    // Like the lease, the turn lasts until the response body is sent
+    let turn = match portforward.concurrency().acquire().await {
+        Ok(turn) => turn,
+        Err(_) => return Ok(with_error_page(config.error_pages.as_deref(), queue_full_response(), &request_host,
+                                            Some(&portforward.authority())).await),
+    };
+    let prefix = UpstreamPrefix::find(&config.upstream_prefixes, portforward.ingress());
+    let uri = match build_upstream_uri(&portforward, prefix, req.uri()) {
+        Ok(uri) => uri,
+        // Any path of a request makes a valid URI, so the port-forward address or --upstream-prefix is to blame
+        Err(e) => return Ok(error_response(StatusCode::BAD_GATEWAY,
+                                           format!("The request URI {} can't be forwarded to {}: {}", req.uri(), portforward.authority(), e))),
+    };
+    println!("Handling request for {}, forwarding to {}", &request_host, &uri);
+    set_upstream_host(&mut req, &config.upstream_host);
+    add_request_headers(&mut req, &config.request_headers);
+    *req.uri_mut() = uri;
+    set_upstream_version(&mut req, config.upstream_http2);
+    // The upstream body is passed on untouched so any trailers hyper receives are forwarded as well, and so its
+    // Content-Length and Content-Encoding stay valid. Anything changing the body has to fix those headers up.
+    // Holding the lease keeps the port-forward open until the body is streamed to the client
+    let upstream_started = Instant::now();
+    let result = upstream::send(&client, req, &metrics).await;
+    let upstream_time = upstream_started.elapsed();
+    metrics.upstream_response_seconds.observe(&ingress_host, upstream_time);
+    if config.log_timing {
+        println!("Request for {} took {:?} to get a port-forward and {:?} for the upstream to answer",
+                 &request_host, forward_time, upstream_time);
+    }
+    // A body over the limit is the client's fault, not the upstream's
+    let too_large = result.is_err() && body_limit.as_ref().is_some_and(BodyLimit::exceeded);
+    portforward.circuit().record(too_large || result.as_ref().is_ok_and(|response| !is_gateway_failure(response.status())));
+    let message = match result {
+        Ok(value) => return Ok(hold_until_sent(value, (portforward, turn))),
+        Err(_) if body_limit.as_ref().is_some_and(BodyLimit::exceeded) => return Ok(body_limit.unwrap().too_large_response()),
+        Err(e) if config.debug_upstream => {
+            let mut message = format!("{}", e);
+            let stderr_lines = state.lock().await.stderr_lines(&portforward);
+            if !stderr_lines.is_empty() {
+                message.push_str("\n\nOutput from the port-forward:\n");
+                for line in stderr_lines {
+                    message.push_str(&format!("  {}\n", line));
+                }
+            }
+            message
+        }
+        Err(e) => format!("{}", e),
+    };
+    Ok(error_page(config.error_pages.as_deref(), StatusCode::BAD_GATEWAY, message, &request_host, Some(&portforward.authority())).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use hyper::Client;
+
+    use super::*;
+
+    fn request(host: Option<&str>, sni: Option<&str>) -> Request<Body> {
+        let mut builder = Request::builder().uri("/");
+        if let Some(host) = host {
+            builder = builder.header(HOST, host);
+        }
+        let mut req = builder.body(Body::empty()).unwrap();
+        if let Some(sni) = sni {
+            req.extensions_mut().insert(Sni(sni.to_owned()));
+        }
+        req
+    }
+
+    /// Starts a backend answering with the Host header it received
+    fn host_echo_backend() -> std::net::SocketAddr {
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap())
+            .serve(make_service_fn(|_| async {
+                Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+                    let host = req.headers().get(HOST).map(|h| h.to_str().unwrap().to_owned()).unwrap_or_default();
+                    Ok::<_, Infallible>(Response::new(Body::from(host)))
+                }))
+            }));
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    async fn upstream_host_seen(upstream_host: &str) -> String {
+        let addr = host_echo_backend();
+        let mut req = request(Some("speil.nais.preprod.local"), None);
+        set_upstream_host(&mut req, &upstream_host.parse().unwrap());
+        *req.uri_mut() = Uri::from_str(&format!("http://{}/", addr)).unwrap();
+
+        let response = Client::new().request(req).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn upstream_receives_expected_host() {
+        assert_eq!(upstream_host_seen("preserve").await, "speil.nais.preprod.local");
+        assert!(upstream_host_seen("loopback").await.starts_with("127.0.0.1:"));
+        assert_eq!(upstream_host_seen("speil.intern.nav.no").await, "speil.intern.nav.no");
+    }
+
+    #[tokio::test]
+    async fn forwards_http2_requests_to_http1_backend() {
+        let addr = host_echo_backend();
+        let mut req = request(Some("speil.nais.preprod.local"), None);
+        *req.version_mut() = Version::HTTP_2;
+        *req.uri_mut() = Uri::from_str(&format!("http://{}/", addr)).unwrap();
+
+        set_upstream_version(&mut req, false);
+        let response = Client::new().request(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn forwards_http10_requests_as_http11() {
+        let mut req = request(Some("speil.nais.preprod.local"), None);
+        *req.version_mut() = Version::HTTP_10;
+
+        set_upstream_version(&mut req, true);
+
+        assert_eq!(req.version(), Version::HTTP_11);
+    }
+
+    #[tokio::test]
+    async fn backend_receives_request_headers() {
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap())
+            .serve(make_service_fn(|_| async {
+                Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+                    let tenants = req.headers().get_all("x-tenant").iter().map(|v| v.to_str().unwrap()).collect::<Vec<_>>();
+                    let token = req.headers().get("x-bypass-token").map(|v| v.to_str().unwrap().to_owned()).unwrap_or_default();
+                    Ok::<_, Infallible>(Response::new(Body::from(format!("{} {}", tenants.join(","), token))))
+                }))
+            }));
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        let mut req = request(Some("speil.nais.preprod.local"), None);
+        req.headers_mut().append("x-tenant", HeaderValue::from_static("client"));
+        req.headers_mut().append("x-tenant", HeaderValue::from_static("other"));
+        *req.uri_mut() = Uri::from_str(&format!("http://{}/", addr)).unwrap();
+
+        add_request_headers(&mut req, &["X-Tenant: tbd".parse().unwrap(), "X-Bypass-Token: secret".parse().unwrap()]);
+        let response = Client::new().request(req).await.unwrap();
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"tbd secret");
+    }
+
+    #[test]
+    fn falls_back_to_sni() {
+        assert_eq!(candidate_hosts(&request(Some("localhost:8443"), Some("speil.nais.preprod.local"))),
+                   vec!["localhost", "speil.nais.preprod.local"]);
+        assert_eq!(candidate_hosts(&request(None, Some("speil.nais.preprod.local"))), vec!["speil.nais.preprod.local"]);
+        assert_eq!(candidate_hosts(&request(Some("speil.nais.preprod.local"), Some("speil.nais.preprod.local"))),
+                   vec!["speil.nais.preprod.local"]);
+        assert!(candidate_hosts(&request(None, None)).is_empty());
+        let mut http2 = request(None, Some("localhost"));
+        *http2.uri_mut() = Uri::from_static("https://speil.nais.preprod.local/");
+        assert_eq!(candidate_hosts(&http2), vec!["speil.nais.preprod.local", "localhost"]);
+    }
+
+    #[test]
+    fn strips_port_from_ipv6_host() {
+        assert_eq!(candidate_hosts(&request(Some("[::1]:8443"), None)), vec!["[::1]"]);
+        assert_eq!(candidate_hosts(&request(Some("[::1]"), None)), vec!["[::1]"]);
+        let mut http2 = request(Some("[::1]:8443"), Some("localhost"));
+        *http2.version_mut() = Version::HTTP_2;
+        assert!(is_misdirected(&http2));
+    }
+
+    #[test]
+    fn detects_http2_request_for_another_host_than_sni() {
+        let http2 = |uri: &'static str| {
+            let mut req = request(None, Some("speil.nais.preprod.local"));
+            *req.version_mut() = Version::HTTP_2;
+            *req.uri_mut() = Uri::from_static(uri);
+            req
+        };
+
+        assert!(is_misdirected(&http2("https://spleis.nais.preprod.local/")));
+        assert!(!is_misdirected(&http2("https://Speil.nais.preprod.local:443/")));
+        assert!(!is_misdirected(&request(Some("spleis.nais.preprod.local"), Some("speil.nais.preprod.local"))));
+        assert!(!is_misdirected(&request(Some("spleis.nais.preprod.local"), None)));
+    }
+
+    #[test]
+    fn rewrites_matching_host() {
+        let rewrites = vec!["localhost=speil.nais.preprod.local".parse().unwrap()];
+        let mut matched = request(Some("localhost:8443"), None);
+        let mut unmatched = request(Some("spleis.nais.preprod.local"), None);
+
+        rewrite_host(&mut matched, &rewrites);
+        rewrite_host(&mut unmatched, &rewrites);
+
+        assert_eq!(candidate_hosts(&matched), vec!["speil.nais.preprod.local"]);
+        assert_eq!(candidate_hosts(&unmatched), vec!["spleis.nais.preprod.local"]);
+    }
+
+    #[test]
+    fn builds_upstream_uri_keeping_path_and_query() {
+        let portforward = Portforward { host: "127.0.0.1".to_owned(), port: 1337 };
+        let ipv6 = Portforward { host: "::1".to_owned(), port: 1337 };
+        let build = |portforward: &Portforward, uri: &'static str| build_upstream_uri(portforward, None, &Uri::from_static(uri)).unwrap().to_string();
+
+        assert_eq!(build(&portforward, "/api/person?fnr=1&aktor=2"), "http://127.0.0.1:1337/api/person?fnr=1&aktor=2");
+        assert_eq!(build(&portforward, "/api/person"), "http://127.0.0.1:1337/api/person");
+        assert_eq!(build(&portforward, "https://[fe80::1]:8443/api?q"), "http://127.0.0.1:1337/api?q");
+        assert_eq!(build(&portforward, "http://speil.nais.preprod.local"), "http://127.0.0.1:1337/");
+        assert_eq!(build(&portforward, "speil.nais.preprod.local:443"), "http://127.0.0.1:1337/");
+        assert_eq!(build(&ipv6, "/?q"), "http://[::1]:1337/?q");
+    }
+
+    #[test]
+    fn prepends_upstream_prefix_collapsing_slashes() {
+        let portforward = Portforward { host: "127.0.0.1".to_owned(), port: 1337 };
+        let build = |prefix: &'static str, uri: &'static str| build_upstream_uri(&portforward, Some(prefix), &Uri::from_static(uri)).unwrap().to_string();
+
+        assert_eq!(build("/api", "/person?fnr=1"), "http://127.0.0.1:1337/api/person?fnr=1");
+        assert_eq!(build("/api/", "/person"), "http://127.0.0.1:1337/api/person");
+        assert_eq!(build("/api", "/"), "http://127.0.0.1:1337/api/");
+        assert_eq!(build("/api", "http://speil.nais.preprod.local"), "http://127.0.0.1:1337/api/");
+        assert_eq!(build("/", "/person/"), "http://127.0.0.1:1337/person/");
+        assert_eq!(build("/api", "/person//1"), "http://127.0.0.1:1337/api/person//1");
+    }
+
+    #[test]
+    fn fails_to_build_invalid_upstream_uri() {
+        let portforward = Portforward { host: "127.0.0.1".to_owned(), port: 1337 };
+        let unreachable = Portforward { host: "speil local".to_owned(), port: 1337 };
+
+        assert!(build_upstream_uri(&portforward, Some("/api\u{7}"), &Uri::from_static("/person")).is_err());
+        assert!(build_upstream_uri(&unreachable, None, &Uri::from_static("/person")).is_err());
+    }
+}
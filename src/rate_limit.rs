@@ -0,0 +1,55 @@
+use std::time::{Duration, Instant};
+
+/// A token bucket limiting how often something may happen, like opening port-forwards with --forward-rate. Bursts
+/// beyond the bucket are queued rather than rejected, each waiting for the token it reserved.
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket refilling `rate` tokens a second, holding at most `burst`
+    pub fn new(rate: f64, burst: u32) -> TokenBucket {
+        let burst = f64::from(burst.max(1));
+        TokenBucket { rate, burst, tokens: burst, updated: Instant::now() }
+    }
+
+    /// Takes a token, going into debt when the bucket is empty, and returns how long to wait until it is paid off.
+    /// The wait can happen elsewhere, e.g. without holding a lock on the bucket.
+    pub fn reserve(&mut self) -> Duration {
+        self.reserve_at(Instant::now())
+    }
+
+    fn reserve_at(&mut self, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst) - 1.0;
+        self.updated = now;
+        if self.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queues_reservations_beyond_the_burst() {
+        let mut bucket = TokenBucket::new(2.0, 2);
+        let start = bucket.updated;
+
+        assert_eq!(bucket.reserve_at(start), Duration::from_secs(0));
+        assert_eq!(bucket.reserve_at(start), Duration::from_secs(0));
+        assert_eq!(bucket.reserve_at(start), Duration::from_millis(500));
+        assert_eq!(bucket.reserve_at(start), Duration::from_secs(1));
+        assert_eq!(bucket.reserve_at(start + Duration::from_secs(1)), Duration::from_millis(500));
+        assert_eq!(bucket.reserve_at(start + Duration::from_secs(10)), Duration::from_secs(0));
+        assert_eq!(bucket.reserve_at(start + Duration::from_secs(10)), Duration::from_secs(0));
+        assert_eq!(bucket.reserve_at(start + Duration::from_secs(10)), Duration::from_millis(500));
+    }
+}
@@ -0,0 +1,94 @@
+use std::time::Duration;
+
+use hyper::{Body, Response, StatusCode};
+use hyper::header::{CONTENT_TYPE, HeaderValue, RETRY_AFTER};
+
+use crate::forwarding::ForwardError;
+
+/// Builds a plain text response for errors produced by the proxy itself, as opposed to the upstream
+pub fn error_response(status: StatusCode, message: impl Into<String>) -> Response<Body> {
+    let mut response = Response::new(Body::from(message.into()));
+    *response.status_mut() = status;
+    response.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("text/plain; charset=utf-8"));
+    response
+}
+
+/// Turns a request away while its port-forward reconnects, the client can retry it shortly
+pub fn reconnecting_response(error: &ForwardError) -> Response<Body> {
+    let mut response = error_response(StatusCode::SERVICE_UNAVAILABLE, format!("{}, try again shortly.", error));
+    response.headers_mut().insert(RETRY_AFTER, HeaderValue::from_static("1"));
+    response
+}
+
+/// Turns a request away without trying the upstream, which has been failing
+pub fn circuit_open_response(retry_after: Duration) -> Response<Body> {
+    let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    let mut response = error_response(StatusCode::SERVICE_UNAVAILABLE,
+                                      format!("The upstream keeps failing, try again in {} seconds.", seconds));
+    response.headers_mut().insert(RETRY_AFTER, HeaderValue::from(seconds.max(1)));
+    response
+}
+
+/// Turns a request away when too many are already waiting for the upstream
+pub fn queue_full_response() -> Response<Body> {
+    let mut response = error_response(StatusCode::SERVICE_UNAVAILABLE, "Too many requests are waiting for the upstream, try again.");
+    response.headers_mut().insert(RETRY_AFTER, HeaderValue::from(1));
+    response
+}
+
+/// Answers a request whose port-forward couldn't be opened, naming the route that failed. The underlying error, which
+/// can tell about the machine running autoforward, is only logged
+pub fn forward_error_response(error: &ForwardError) -> Response<Body> {
+    println!("Failed to forward request: {}: {}", error, error.original);
+    if error.is_reconnecting() {
+        return reconnecting_response(error);
+    }
+    error_response(StatusCode::BAD_GATEWAY, error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn error_response_is_plain_text() {
+        let response = error_response(StatusCode::NOT_FOUND, format!("No service found for {}", "speil.nais.preprod.local"));
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers()[CONTENT_TYPE], "text/plain; charset=utf-8");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"No service found for speil.nais.preprod.local");
+    }
+
+    #[tokio::test]
+    async fn forward_error_response_names_the_route() {
+        let error = ForwardError {
+            message: "Could not open port-forward. Are you still connected to navtunnel?".into(),
+            original: std::io::Error::other("kubectl not found"),
+            route: Some("https://speil.nais.preprod.local (speil in dev-fss/default)".to_owned()),
+            reconnecting: false,
+        };
+
+        let response = forward_error_response(&error);
+
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], &b"https://speil.nais.preprod.local (speil in dev-fss/default): Could not open port-forward. \
+                                 Are you still connected to navtunnel?"[..]);
+    }
+
+    #[test]
+    fn only_reconnecting_errors_ask_to_try_again() {
+        let error = ForwardError {
+            message: "Could not open port-forward. Are you still connected to navtunnel?".into(),
+            original: std::io::Error::from(std::io::ErrorKind::NotConnected),
+            route: None,
+            reconnecting: false,
+        };
+
+        assert_eq!(forward_error_response(&error).status(), StatusCode::BAD_GATEWAY);
+        let response = forward_error_response(&ForwardError { reconnecting: true, ..error });
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[RETRY_AFTER], "1");
+    }
+}
@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::atomic_file;
+use crate::forwarding::PortforwardSummary;
+
+/// An open port-forward as listed in the state file, for scripts that need to know which local ports are in use
+#[derive(Serialize)]
+struct Entry<'a> {
+    application: &'a str,
+    ingresses: &'a [String],
+    local: String,
+}
+
+/// The file next to the state file remembering the local port each ingress was last forwarded on, also after the
+/// port-forwards are closed
+fn ports_path(path: &Path) -> PathBuf {
+    let mut ports_path = path.as_os_str().to_owned();
+    ports_path.push(".ports");
+    PathBuf::from(ports_path)
+}
+
+/// The local port each ingress was last forwarded on, nothing if it can't be read
+pub fn recorded_ports(path: &Path) -> HashMap<String, u16> {
+    fs::read(ports_path(path)).ok()
+        .and_then(|json| serde_json::from_slice(&json).ok())
+        .unwrap_or_default()
+}
+
+/// Replaces the state file with the given port-forwards, and the remembered local ports with `ports`. The files are
+/// written next to the targets and renamed into place, so readers never see them half-written.
+pub fn store<'a>(path: &Path, forwards: impl Iterator<Item = PortforwardSummary<'a>>, ports: &HashMap<String, u16>) -> io::Result<()> {
+    let entries = forwards
+        .map(|pf| Entry { application: pf.application, ingresses: pf.ingresses, local: pf.local.authority() })
+        .collect::<Vec<_>>();
+    atomic_file::write(path, &serde_json::to_vec_pretty(&entries)?)?;
+    atomic_file::write(&ports_path(path), &serde_json::to_vec_pretty(ports)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::forwarding::Portforward;
+
+    use super::*;
+
+    #[test]
+    fn lists_local_address_of_every_forward() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("forwards.json");
+        let ingresses = vec!["https://speil.nais.preprod.local".to_owned()];
+        let local = Portforward { host: "127.0.0.1".to_owned(), port: 54500 };
+        let summary = PortforwardSummary { application: "speil", ingresses: &ingresses, local: &local, ttl_seconds: 60, last_selftest: None };
+
+        let ports = vec![("https://speil.nais.preprod.local".to_owned(), 54500)].into_iter().collect();
+        store(&path, vec![summary].into_iter(), &ports).unwrap();
+
+        let written: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(written, serde_json::json!([{
+            "application": "speil",
+            "ingresses": ["https://speil.nais.preprod.local"],
+            "local": "127.0.0.1:54500",
+        }]));
+        assert_eq!(recorded_ports(&path), ports);
+        store(&path, std::iter::empty(), &ports).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "[]");
+        assert_eq!(recorded_ports(&path), ports);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn records_no_ports_without_a_readable_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("forwards.json");
+
+        assert!(recorded_ports(&path).is_empty());
+        fs::write(ports_path(&path), "not json").unwrap();
+        assert!(recorded_ports(&path).is_empty());
+    }
+}
@@ -1,35 +1,127 @@
 use std::fs::File;
 use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
 
 use futures_util::{
     future::TryFutureExt,
     stream::{Stream, StreamExt, TryStreamExt},
 };
 use rustls::internal::pemfile;
-use tokio::net::{TcpListener, TcpStream};
+use rustls::{ProtocolVersion, Session};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
 use tokio_rustls::server::TlsStream;
 use tokio_rustls::TlsAcceptor;
+use x509_parser::extensions::GeneralName;
 use std::sync::Arc;
 use std::pin::Pin;
 use std::task::{Poll, Context};
 
-pub async fn tls_acceptor(tcp: &'_ mut TcpListener) -> Result<HyperAcceptor<'_>, io::Error> {
-    let tls_cfg = {
-        let certs = load_certs(".keys/server.crt")?;
-        let key = load_private_key(".keys/server.key")?;
+/// The oldest TLS version clients are allowed to negotiate
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TlsVersion {
+    Tls12,
+    Tls13,
+}
 
-        let mut cfg = rustls::ServerConfig::new(rustls::NoClientAuth::new());
+impl FromStr for TlsVersion {
+    type Err = String;
 
-        cfg.set_single_cert(certs, key)
-            .map_err(|e| error(format!("{}", e)))?;
-        cfg.set_protocols(&[b"http/1.1".to_vec(), b"h2".to_vec()]);
-        Arc::new(cfg)
-    };
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "1.2" => Ok(TlsVersion::Tls12),
+            "1.3" => Ok(TlsVersion::Tls13),
+            _ => Err(format!("Expected 1.2 or 1.3, got {}", s)),
+        }
+    }
+}
+
+impl TlsVersion {
+    fn versions(self) -> Vec<ProtocolVersion> {
+        match self {
+            TlsVersion::Tls12 => vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2],
+            TlsVersion::Tls13 => vec![ProtocolVersion::TLSv1_3],
+        }
+    }
+}
+
+/// The application protocols offered to clients over ALPN
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Alpn {
+    Http1,
+    H2,
+    Both,
+}
+
+impl FromStr for Alpn {
+    type Err = String;
 
-    let tls_acceptor = TlsAcceptor::from(tls_cfg);
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "http1" => Ok(Alpn::Http1),
+            "h2" => Ok(Alpn::H2),
+            "both" => Ok(Alpn::Both),
+            _ => Err(format!("Expected http1, h2 or both, got {}", s)),
+        }
+    }
+}
 
-    let incoming_tls_stream = tcp
-        .incoming()
+impl Alpn {
+    fn protocols(self) -> Vec<Vec<u8>> {
+        match self {
+            Alpn::Http1 => vec![b"http/1.1".to_vec()],
+            Alpn::H2 => vec![b"h2".to_vec()],
+            Alpn::Both => vec![b"http/1.1".to_vec(), b"h2".to_vec()],
+        }
+    }
+}
+
+/// The server name a client asked for in its TLS handshake, attached to its requests as an extension
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Sni(pub String);
+
+pub fn sni<S>(conn: &TlsStream<S>) -> Option<Sni> {
+    conn.get_ref().1.get_sni_hostname().map(|name| Sni(name.to_owned()))
+}
+
+/// A connection from a client the proxy can serve TLS over
+pub trait ClientStream: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+    /// The address of the client, if it connected over the network
+    fn peer_addr(&self) -> Option<SocketAddr>;
+
+    /// The address the client connected to, if it connected over the network
+    fn local_addr(&self) -> Option<SocketAddr>;
+}
+
+impl ClientStream for TcpStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        TcpStream::peer_addr(self).ok()
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        TcpStream::local_addr(self).ok()
+    }
+}
+
+#[cfg(unix)]
+impl ClientStream for UnixStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+pub async fn tls_acceptor<'a, S: ClientStream>(incoming: impl Stream<Item=io::Result<S>> + Send + 'a, tls_cfg: rustls::ServerConfig) -> Result<HyperAcceptor<'a, S>, io::Error> {
+    let tls_acceptor = TlsAcceptor::from(Arc::new(tls_cfg));
+
+    let incoming_tls_stream = incoming
         .map_err(|e| error(format!("Incoming failed: {:?}", e)))
         .and_then(move |s| {
             tls_acceptor.accept(s).map_err(|e| {
@@ -37,6 +129,7 @@ pub async fn tls_acceptor(tcp: &'_ mut TcpListener) -> Result<HyperAcceptor<'_>,
                 error(format!("TLS Error: {:?}", e))
             })
         })
+        .map_ok(AlpnChecked::new)
         .boxed();
 
     Ok(HyperAcceptor {
@@ -44,46 +137,392 @@ pub async fn tls_acceptor(tcp: &'_ mut TcpListener) -> Result<HyperAcceptor<'_>,
     })
 }
 
-fn load_certs(filename: &str) -> io::Result<Vec<rustls::Certificate>> {
+/// Requires clients to present a certificate signed by `client_ca` when given
+pub fn server_config(cert: &Path, key: &Path, min_version: TlsVersion, alpn: Alpn, client_ca: Option<&Path>) -> io::Result<rustls::ServerConfig> {
+    let certs = load_certs(cert)?;
+    let key = load_private_key(key)?;
+
+    let client_auth = match client_ca {
+        Some(client_ca) => {
+            let mut roots = rustls::RootCertStore::empty();
+            for cert in load_certs(client_ca)? {
+                roots.add(&cert).map_err(|e| error(format!("invalid client CA {}: {:?}", client_ca.display(), e)))?;
+            }
+            rustls::AllowAnyAuthenticatedClient::new(roots)
+        }
+        None => rustls::NoClientAuth::new(),
+    };
+    let mut cfg = rustls::ServerConfig::new(client_auth);
+    cfg.versions = min_version.versions();
+
+    cfg.set_single_cert(certs, key)
+        .map_err(|e| error(format!("{}", e)))?;
+    cfg.set_protocols(&alpn.protocols());
+    Ok(cfg)
+}
+
+/// The parts of a certificate needed to tell whether it is valid for the hosts autoforward routes
+#[derive(Debug, PartialEq, Eq)]
+pub struct CertInfo {
+    pub subject: String,
+    pub subject_alt_names: Vec<String>,
+    pub not_before: String,
+    pub not_after: String,
+}
+
+impl CertInfo {
+    /// Whether one of the DNS names matches the host, where a wildcard matches a single label
+    pub fn covers(&self, host: &str) -> bool {
+        let host = host.to_ascii_lowercase();
+        self.subject_alt_names.iter().map(|name| name.to_ascii_lowercase()).any(|name| {
+            match name.strip_prefix("*.") {
+                Some(suffix) => matches!(host.split_once('.'), Some((label, rest)) if !label.is_empty() && rest == suffix),
+                None => name == host,
+            }
+        })
+    }
+}
+
+/// Reads the first certificate in a PEM file
+pub fn cert_info(filename: &Path) -> io::Result<CertInfo> {
+    let certs = load_certs(filename)?;
+    let cert = certs.first().ok_or_else(|| error(format!("no certificate in {}", filename.display())))?;
+    let (_, cert) = x509_parser::parse_x509_certificate(&cert.0)
+        .map_err(|e| error(format!("invalid certificate in {}: {}", filename.display(), e)))?;
+    let subject_alt_names = match cert.subject_alternative_name() {
+        Ok(Some(extension)) => extension.value.general_names.iter()
+            .filter_map(|name| match name {
+                GeneralName::DNSName(name) => Some((*name).to_owned()),
+                _ => None,
+            })
+            .collect(),
+        Ok(None) => vec![],
+        Err(e) => return Err(error(format!("invalid subject alternative names in {}: {}", filename.display(), e))),
+    };
+    Ok(CertInfo {
+        subject: cert.subject().to_string(),
+        subject_alt_names,
+        not_before: cert.validity().not_before.to_rfc2822(),
+        not_after: cert.validity().not_after.to_rfc2822(),
+    })
+}
+
+/// Accepts any server certificate. Backends are reached through a port-forward on localhost, which no certificate
+/// they present will name, and the port-forward already authenticates the cluster.
+struct AnyServerCert;
+
+impl rustls::ServerCertVerifier for AnyServerCert {
+    fn verify_server_cert(&self, _roots: &rustls::RootCertStore, _presented_certs: &[rustls::Certificate],
+                          _dns_name: tokio_rustls::webpki::DNSNameRef, _ocsp_response: &[u8]) -> Result<rustls::ServerCertVerified, rustls::TLSError> {
+        Ok(rustls::ServerCertVerified::assertion())
+    }
+}
+
+/// The client configuration for probing health checks served over HTTPS
+pub fn backend_client_config() -> rustls::ClientConfig {
+    let mut config = rustls::ClientConfig::new();
+    config.dangerous().set_certificate_verifier(Arc::new(AnyServerCert));
+    config
+}
+
+fn load_certs(filename: &Path) -> io::Result<Vec<rustls::Certificate>> {
     let certfile = File::open(filename)
-        .map_err(|e| error(format!("failed to open {}: {}", filename, e)))?;
+        .map_err(|e| error(format!("failed to open {}: {}", filename.display(), e)))?;
     let mut reader = io::BufReader::new(certfile);
 
     pemfile::certs(&mut reader).map_err(|_| error("failed to load certificate".into()))
 }
 
-fn load_private_key(filename: &str) -> io::Result<rustls::PrivateKey> {
-    let keyfile = File::open(filename)
-        .map_err(|e| error(format!("failed to open {}: {}", filename, e)))?;
-    let mut reader = io::BufReader::new(keyfile);
+/// Reads the single PKCS#8 or RSA private key in a PEM file, ignoring any certificates in it
+fn load_private_key(filename: &Path) -> io::Result<rustls::PrivateKey> {
+    let pem = std::fs::read(filename)
+        .map_err(|e| error(format!("failed to open {}: {}", filename.display(), e)))?;
 
-    let keys = pemfile::pkcs8_private_keys(&mut reader)
+    let mut keys = pemfile::pkcs8_private_keys(&mut pem.as_slice())
         .map_err(|_| error("failed to load private key".into()))?;
+    keys.extend(pemfile::rsa_private_keys(&mut pem.as_slice())
+        .map_err(|_| error("failed to load private key".into()))?);
     if keys.len() != 1 {
-        return Err(error("expected a single private key".into()));
+        return Err(error(format!("expected a single private key in {}, found {}", filename.display(), keys.len())));
     }
-    Ok(keys[0].clone())
+    Ok(keys.remove(0))
 }
 
-pub struct HyperAcceptor<'a> {
-    acceptor: Pin<Box<dyn Stream<Item=Result<TlsStream<TcpStream>, io::Error>> + 'a>>,
+/// The file to read the private key from: `key` if given, or else the certificate file when it holds a key too, or
+/// else the `.key` file next to it
+pub fn key_file(cert: &Path, key: Option<&Path>) -> std::path::PathBuf {
+    if let Some(key) = key {
+        return key.to_owned();
+    }
+    let combined = std::fs::read(cert).is_ok_and(|pem| {
+        pemfile::pkcs8_private_keys(&mut pem.as_slice()).is_ok_and(|keys| !keys.is_empty())
+            || pemfile::rsa_private_keys(&mut pem.as_slice()).is_ok_and(|keys| !keys.is_empty())
+    });
+    if combined {
+        cert.to_owned()
+    } else {
+        cert.with_extension("key")
+    }
 }
 
-impl hyper::server::accept::Accept for HyperAcceptor<'_> {
-    type Conn = TlsStream<TcpStream>;
+pub struct HyperAcceptor<'a, S> {
+    acceptor: Pin<Box<dyn Stream<Item=Result<AlpnChecked<S>, io::Error>> + Send + 'a>>,
+}
+
+impl<S> hyper::server::accept::Accept for HyperAcceptor<'_, S> {
+    type Conn = AlpnChecked<S>;
     type Error = io::Error;
 
     fn poll_accept(
         mut self: Pin<&mut Self>,
         cx: &mut Context,
     ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
-        match Pin::new(&mut self.acceptor).poll_next(cx) {
-            Poll::Ready(Some(Err(_))) => Poll::Pending,
-            record => record,
+        loop {
+            match Pin::new(&mut self.acceptor).poll_next(cx) {
+                // The failure is already logged, one failed handshake mustn't hold up the next clients
+                Poll::Ready(Some(Err(_))) => continue,
+                record => return record,
+            }
+        }
+    }
+}
+
+/// The preface every HTTP/2 connection starts with
+const H2_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// A client connection that logs when the client speaks another protocol than it negotiated over ALPN, which hyper
+/// otherwise fails on without saying why
+pub struct AlpnChecked<S> {
+    inner: TlsStream<S>,
+    checked: bool,
+}
+
+impl<S: ClientStream> AlpnChecked<S> {
+    fn new(inner: TlsStream<S>) -> AlpnChecked<S> {
+        AlpnChecked { inner, checked: false }
+    }
+
+    pub fn get_ref(&self) -> &TlsStream<S> {
+        &self.inner
+    }
+
+    /// Compares the first data from the client with the negotiated protocol
+    fn check_protocol(&mut self, data: &[u8]) {
+        self.checked = true;
+        let speaks_h2 = H2_PREFACE.starts_with(&data[..data.len().min(H2_PREFACE.len())]);
+        let negotiated = self.inner.get_ref().1.get_alpn_protocol();
+        let mismatch = match negotiated {
+            Some(b"h2") => !speaks_h2,
+            Some(_) => speaks_h2,
+            None => false,
+        };
+        if let (true, Some(negotiated)) = (mismatch, negotiated) {
+            let peer = self.inner.get_ref().0.peer_addr().map(|addr| addr.to_string()).unwrap_or_else(|| "a local client".to_owned());
+            println!("{} negotiated {} over ALPN but speaks {}, check the protocols it is configured with and --alpn",
+                     peer, String::from_utf8_lossy(negotiated), if speaks_h2 { "HTTP/2" } else { "HTTP/1" });
+        }
+    }
+}
+
+impl<S: ClientStream> AsyncRead for AlpnChecked<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(read)) = result {
+            if !this.checked && read > 0 {
+                this.check_protocol(&buf[..read]);
+            }
         }
+        result
+    }
+}
+
+impl<S: ClientStream> AsyncWrite for AlpnChecked<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
     }
 }
 
 fn error(err: String) -> io::Error {
-    io::Error::new(io::ErrorKind::Other, err)
+    io::Error::other(err)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsConnector;
+    use tokio_rustls::webpki::DNSNameRef;
+
+    use super::*;
+
+    fn testdata(name: &str) -> std::path::PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata").join(name)
+    }
+
+    /// Performs a handshake against a server requiring client certificates, returning whether the server accepted it
+    async fn handshake(client_cert: Option<(&str, &str)>) -> bool {
+        let server = server_config(&testdata("server.crt"), &testdata("server.key"), TlsVersion::Tls12, Alpn::Both, Some(&testdata("ca.pem"))).unwrap();
+        let mut client = rustls::ClientConfig::new();
+        client.root_store.add(&load_certs(&testdata("ca.pem")).unwrap()[0]).unwrap();
+        if let Some((cert, key)) = client_cert {
+            client.set_single_client_cert(load_certs(&testdata(cert)).unwrap(), load_private_key(&testdata(key)).unwrap()).unwrap();
+        }
+
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            TlsAcceptor::from(Arc::new(server)).accept(stream).await.is_ok()
+        };
+        let connected = async {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let _ = TlsConnector::from(Arc::new(client))
+                .connect(DNSNameRef::try_from_ascii_str("localhost").unwrap(), stream)
+                .await;
+        };
+        futures_util::future::join(accepted, connected).await.0
+    }
+
+    #[tokio::test]
+    async fn loads_certificate_chain_and_key_from_one_file() {
+        let combined = testdata("combined.pem");
+        assert_eq!(key_file(&combined, None), combined);
+        assert_eq!(key_file(&testdata("server.crt"), None), testdata("server.key"));
+        assert_eq!(key_file(&combined, Some(&testdata("server.key"))), testdata("server.key"));
+        assert_eq!(load_certs(&combined).unwrap().len(), 2);
+
+        let server = server_config(&combined, &key_file(&combined, None), TlsVersion::Tls12, Alpn::Both, None).unwrap();
+        let mut client = rustls::ClientConfig::new();
+        client.root_store.add(&load_certs(&testdata("ca.pem")).unwrap()[0]).unwrap();
+
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            TlsAcceptor::from(Arc::new(server)).accept(stream).await.is_ok()
+        };
+        let connected = async {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            TlsConnector::from(Arc::new(client))
+                .connect(DNSNameRef::try_from_ascii_str("localhost").unwrap(), stream)
+                .await
+                .is_ok()
+        };
+        assert_eq!(futures_util::future::join(accepted, connected).await, (true, true));
+    }
+
+    #[tokio::test]
+    async fn captures_sni() {
+        let server = server_config(&testdata("server.crt"), &testdata("server.key"), TlsVersion::Tls12, Alpn::Both, None).unwrap();
+        let mut client = rustls::ClientConfig::new();
+        client.root_store.add(&load_certs(&testdata("ca.pem")).unwrap()[0]).unwrap();
+
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            let conn = TlsAcceptor::from(Arc::new(server)).accept(stream).await.unwrap();
+            sni(&conn)
+        };
+        let connected = async {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            TlsConnector::from(Arc::new(client))
+                .connect(DNSNameRef::try_from_ascii_str("localhost").unwrap(), stream)
+                .await
+                .unwrap()
+        };
+
+        let (sni, _) = futures_util::future::join(accepted, connected).await;
+        assert_eq!(sni, Some(Sni("localhost".to_owned())));
+    }
+
+    #[tokio::test]
+    async fn rejects_clients_without_certificate_when_client_ca_is_set() {
+        assert!(!handshake(None).await);
+    }
+
+    #[tokio::test]
+    async fn accepts_clients_with_certificate_signed_by_client_ca() {
+        assert!(handshake(Some(("client.crt", "client.key"))).await);
+    }
+
+    /// Performs a handshake with a client offering the given protocols, returning the one negotiated
+    async fn negotiated(alpn: Alpn, offered: &[&[u8]]) -> Option<Vec<u8>> {
+        let server = server_config(&testdata("server.crt"), &testdata("server.key"), TlsVersion::Tls12, alpn, None).unwrap();
+        let mut client = rustls::ClientConfig::new();
+        client.root_store.add(&load_certs(&testdata("ca.pem")).unwrap()[0]).unwrap();
+        client.set_protocols(&offered.iter().map(|protocol| protocol.to_vec()).collect::<Vec<_>>());
+
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            let conn = TlsAcceptor::from(Arc::new(server)).accept(stream).await.unwrap();
+            conn.get_ref().1.get_alpn_protocol().map(<[u8]>::to_vec)
+        };
+        let connected = async {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            TlsConnector::from(Arc::new(client))
+                .connect(DNSNameRef::try_from_ascii_str("localhost").unwrap(), stream)
+                .await
+                .unwrap()
+        };
+        futures_util::future::join(accepted, connected).await.0
+    }
+
+    #[tokio::test]
+    async fn offers_only_the_configured_protocols() {
+        assert_eq!(negotiated(Alpn::H2, &[b"http/1.1", b"h2"]).await, Some(b"h2".to_vec()));
+        assert_eq!(negotiated(Alpn::Http1, &[b"h2", b"http/1.1"]).await, Some(b"http/1.1".to_vec()));
+        assert_eq!(negotiated(Alpn::Http1, &[b"h2"]).await, None);
+        assert_eq!(negotiated(Alpn::Both, &[b"h2"]).await, Some(b"h2".to_vec()));
+    }
+
+    #[test]
+    fn parses_alpn() {
+        assert_eq!("http1".parse(), Ok(Alpn::Http1));
+        assert_eq!("both".parse(), Ok(Alpn::Both));
+        assert!("http3".parse::<Alpn>().is_err());
+    }
+
+    #[test]
+    fn parses_min_versions() {
+        assert_eq!("1.2".parse::<TlsVersion>().map(TlsVersion::versions),
+                   Ok(vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2]));
+        assert_eq!("1.3".parse::<TlsVersion>().map(TlsVersion::versions), Ok(vec![ProtocolVersion::TLSv1_3]));
+        assert!("1.1".parse::<TlsVersion>().is_err());
+    }
+
+    #[test]
+    fn reads_cert_info() {
+        let info = cert_info(&testdata("server.crt")).unwrap();
+
+        assert_eq!(info.subject, "CN=server");
+        assert_eq!(info.subject_alt_names, vec!["localhost"]);
+        assert!(info.covers("localhost"));
+        assert!(!info.covers("speil.nais.preprod.local"));
+    }
+
+    #[test]
+    fn wildcard_covers_a_single_label() {
+        let info = CertInfo {
+            subject: "CN=nais.io".to_owned(),
+            subject_alt_names: vec!["*.nais.preprod.local".to_owned()],
+            not_before: String::new(),
+            not_after: String::new(),
+        };
+
+        assert!(info.covers("speil.nais.preprod.local"));
+        assert!(info.covers("Speil.Nais.Preprod.Local"));
+        assert!(!info.covers("nais.preprod.local"));
+        assert!(!info.covers("ny.speil.nais.preprod.local"));
+    }
 }
@@ -0,0 +1,112 @@
+use std::io;
+use std::sync::Arc;
+
+use futures_util::future::try_join;
+use hyper::{Body, Request, Response, StatusCode};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+
+use crate::forwarding::{self, ForwardError, Portforward, State};
+use crate::responses::{error_response, forward_error_response};
+
+/// Opens a raw TCP tunnel for a `CONNECT host:port` request, as long as the host matches a known ingress. The
+/// port of the target is ignored, the tunnel goes to the service port the ingress is routed to.
+pub async fn handle_connect(req: Request<Body>, state: Arc<Mutex<State>>) -> Result<Response<Body>, ForwardError> {
+    let host = match req.uri().host() {
+        Some(host) => host.to_owned(),
+        None => return Ok(error_response(StatusCode::BAD_REQUEST, "CONNECT requires a host:port target.")),
+    };
+    let portforward = match forwarding::fetch_address(&state, &host, "/").await {
+        Ok(Some(portforward)) => portforward,
+        Ok(None) => return Ok(error_response(StatusCode::FORBIDDEN, format!("Tunneling to {} is not allowed", host))),
+        Err(e) => return Ok(forward_error_response(&e)),
+    };
+    println!("Tunneling to {}, forwarding to {}", host, portforward.authority());
+    tokio::spawn(async move {
+        match req.into_body().on_upgrade().await {
+            Ok(upgraded) => if let Err(e) = tunnel(upgraded, &portforward).await {
+                println!("Tunnel to {} failed: {}", host, e);
+            },
+            Err(e) => println!("Could not upgrade CONNECT to {}: {}", host, e),
+        }
+    });
+    Ok(Response::new(Body::empty()))
+}
+
+async fn tunnel<S>(client: S, portforward: &Portforward) -> io::Result<()>
+    where S: AsyncRead + AsyncWrite {
+    let upstream = TcpStream::connect((portforward.host.as_str(), portforward.port as u16)).await?;
+    splice(client, upstream).await
+}
+
+/// Copies data both ways until both sides are done, shutting down each write side when its reader ends
+async fn splice<A, B>(a: A, b: B) -> io::Result<()>
+    where A: AsyncRead + AsyncWrite, B: AsyncRead + AsyncWrite {
+    let (mut a_read, mut a_write) = tokio::io::split(a);
+    let (mut b_read, mut b_write) = tokio::io::split(b);
+    let a_to_b = async {
+        tokio::io::copy(&mut a_read, &mut b_write).await?;
+        b_write.shutdown().await
+    };
+    let b_to_a = async {
+        tokio::io::copy(&mut b_read, &mut a_write).await?;
+        a_write.shutdown().await
+    };
+    try_join(a_to_b, b_to_a).await.map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::Method;
+    use structopt::StructOpt;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    use crate::config::Config;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn rejects_unknown_hosts() {
+        let mut config = Config::from_iter(&["autoforward"]);
+        config.contexts.clear();
+        let state = Arc::new(Mutex::new(State::new(Arc::new(config)).await.unwrap()));
+        let req = Request::builder()
+            .method(Method::CONNECT)
+            .uri("speil.nais.preprod.local:443")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = handle_connect(req, state).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn splices_both_directions() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let echo = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let (mut read, mut write) = stream.split();
+            tokio::io::copy(&mut read, &mut write).await.unwrap();
+        });
+        let mut proxy_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = proxy_listener.local_addr().unwrap();
+        let portforward = Portforward { host: "127.0.0.1".to_owned(), port: addr.port() as usize };
+        tokio::spawn(async move {
+            let (client, _) = proxy_listener.accept().await.unwrap();
+            tunnel(client, &portforward).await.unwrap();
+        });
+
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client.write_all(b"ping").await.unwrap();
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut received = vec![];
+        client.read_to_end(&mut received).await.unwrap();
+
+        assert_eq!(received, b"ping");
+        echo.await.unwrap();
+    }
+}
@@ -0,0 +1,275 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use hyper::{Body, Client, Request, Response, Uri};
+use hyper::client::HttpConnector;
+use hyper::client::connect::{Connected, Connection};
+use hyper::service::Service;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+
+use crate::metrics::Metrics;
+
+pub type UpstreamClient = Client<CountingConnector>;
+
+/// The client shared by all requests to port-forwards, failing fast when a port-forward no longer accepts
+/// connections and counting the connections its pool opens. With `http2` it speaks HTTP/2 without negotiating it
+/// first, which only works for backends that support HTTP/2 over plain text.
+pub fn upstream_client(connect_timeout: Duration, http2: bool, metrics: Arc<Metrics>) -> UpstreamClient {
+    let mut connector = HttpConnector::new();
+    connector.set_connect_timeout(Some(connect_timeout));
+    Client::builder()
+        .http2_only(http2)
+        .build(CountingConnector { inner: connector, metrics })
+}
+
+/// Sends a request upstream, counting it so the metrics can tell new connections from reused ones. When the client
+/// goes away hyper drops this future, which drops the upstream request and closes its connection instead of pooling
+/// it half-way through an exchange.
+pub async fn send(client: &UpstreamClient, req: Request<Body>, metrics: &Metrics) -> Result<Response<Body>, hyper::Error> {
+    metrics.upstream_requests.fetch_add(1, Ordering::Relaxed);
+    metrics.upstream_requests_active.fetch_add(1, Ordering::Relaxed);
+    let _active = ActiveRequest(metrics);
+    client.request(req).await
+}
+
+/// Counts a request as active until it completes or is dropped
+struct ActiveRequest<'a>(&'a Metrics);
+
+impl Drop for ActiveRequest<'_> {
+    fn drop(&mut self) {
+        self.0.upstream_requests_active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Clone)]
+pub struct CountingConnector {
+    inner: HttpConnector,
+    metrics: Arc<Metrics>,
+}
+
+impl Service<Uri> for CountingConnector {
+    type Response = CountedStream;
+    type Error = <HttpConnector as Service<Uri>>::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<CountedStream, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let connecting = self.inner.call(uri);
+        let metrics = self.metrics.clone();
+        Box::pin(async move {
+            let stream = connecting.await?;
+            metrics.upstream_connections_created.fetch_add(1, Ordering::Relaxed);
+            metrics.upstream_connections_open.fetch_add(1, Ordering::Relaxed);
+            Ok(CountedStream { inner: stream, metrics })
+        })
+    }
+}
+
+/// A connection to a port-forward, counted as open until hyper drops it
+pub struct CountedStream {
+    inner: TcpStream,
+    metrics: Arc<Metrics>,
+}
+
+impl Drop for CountedStream {
+    fn drop(&mut self) {
+        self.metrics.upstream_connections_open.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Connection for CountedStream {
+    fn connected(&self) -> Connected {
+        self.inner.connected()
+    }
+}
+
+impl AsyncRead for CountedStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for CountedStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+
+    use futures_util::future::abortable;
+    use hyper::Server;
+    use hyper::body::HttpBody;
+    use hyper::service::{make_service_fn, service_fn};
+
+    use super::*;
+
+    fn backend() -> SocketAddr {
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap())
+            .serve(make_service_fn(|_| async {
+                Ok::<_, Infallible>(service_fn(|_| async { Ok::<_, Infallible>(Response::new(Body::from("ok"))) }))
+            }));
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    /// Starts a backend that sends the first chunk of its body, or nothing at all, and then hangs
+    fn hanging_backend(send_headers: bool) -> SocketAddr {
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap())
+            .serve(make_service_fn(move |_| async move {
+                Ok::<_, Infallible>(service_fn(move |_| async move {
+                    if !send_headers {
+                        tokio::time::delay_for(Duration::from_secs(30)).await;
+                    }
+                    let (mut sender, body) = Body::channel();
+                    tokio::spawn(async move {
+                        sender.send_data("first chunk".into()).await.unwrap();
+                        tokio::time::delay_for(Duration::from_secs(30)).await;
+                    });
+                    Ok::<_, Infallible>(Response::new(body))
+                }))
+            }));
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    async fn wait_for_closed_connections(metrics: &Metrics) {
+        for _ in 0..100 {
+            if metrics.upstream_connections_open.load(Ordering::Relaxed) == 0 {
+                return;
+            }
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+        panic!("Upstream connection was not closed");
+    }
+
+    #[tokio::test]
+    async fn aborted_request_closes_its_connection() {
+        let addr = hanging_backend(false);
+        let metrics = Arc::new(Metrics::default());
+        let client = upstream_client(Duration::from_secs(1), false, metrics.clone());
+        let req = Request::get(format!("http://{}/", addr)).body(Body::empty()).unwrap();
+
+        let (request, abort) = abortable({
+            let metrics = metrics.clone();
+            async move { send(&client, req, &metrics).await }
+        });
+        let request = tokio::spawn(request);
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        assert_eq!(metrics.upstream_requests_active.load(Ordering::Relaxed), 1);
+        abort.abort();
+        assert!(request.await.unwrap().is_err());
+
+        assert_eq!(metrics.upstream_requests_active.load(Ordering::Relaxed), 0);
+        wait_for_closed_connections(&metrics).await;
+    }
+
+    #[tokio::test]
+    async fn half_read_body_is_not_pooled() {
+        let addr = hanging_backend(true);
+        let metrics = Arc::new(Metrics::default());
+        let client = upstream_client(Duration::from_secs(1), false, metrics.clone());
+        let req = Request::get(format!("http://{}/", addr)).body(Body::empty()).unwrap();
+
+        let mut body = send(&client, req, &metrics).await.unwrap().into_body();
+        assert_eq!(&body.data().await.unwrap().unwrap()[..], b"first chunk");
+        drop(body);
+
+        wait_for_closed_connections(&metrics).await;
+    }
+
+    /// A listener whose accept queue is full, so connecting to it hangs like connecting to a pod that is gone. The
+    /// listener and the connections filling the queue have to be kept until the test is done.
+    #[cfg(unix)]
+    async fn unresponsive_backend() -> (std::net::TcpListener, Vec<tokio::net::TcpStream>) {
+        use std::os::unix::io::AsRawFd;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        nix::sys::socket::listen(listener.as_raw_fd(), 0).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut queued = Vec::new();
+        for _ in 0..16 {
+            match tokio::time::timeout(Duration::from_millis(100), tokio::net::TcpStream::connect(addr)).await {
+                Ok(Ok(stream)) => queued.push(stream),
+                _ => break,
+            }
+        }
+        (listener, queued)
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn upstream_connect_times_out() {
+        let (listener, _queued) = unresponsive_backend().await;
+        let client = upstream_client(Duration::from_millis(200), false, Arc::default());
+        let started = std::time::Instant::now();
+
+        let result = client.get(format!("http://{}/", listener.local_addr().unwrap()).parse().unwrap()).await;
+
+        assert!(result.unwrap_err().is_connect());
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn counts_pooled_connections() {
+        let addr = backend();
+        let metrics = Arc::new(Metrics::default());
+        let client = upstream_client(Duration::from_secs(1), false, metrics.clone());
+
+        for _ in 0..3 {
+            let req = Request::get(format!("http://{}/", addr)).body(Body::empty()).unwrap();
+            let response = send(&client, req, &metrics).await.unwrap();
+            hyper::body::to_bytes(response.into_body()).await.unwrap();
+            // Give hyper a moment to return the connection to the pool
+            tokio::time::delay_for(Duration::from_millis(20)).await;
+        }
+
+        assert_eq!(metrics.upstream_requests.load(Ordering::Relaxed), 3);
+        assert_eq!(metrics.upstream_connections_created.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.upstream_connections_open.load(Ordering::Relaxed), 1);
+        assert!(metrics.render().contains("autoforward_upstream_connections_reused_total 2\n"));
+        assert!(metrics.render().contains("autoforward_upstream_connections_idle 1\n"));
+    }
+
+    #[tokio::test]
+    async fn reaches_http2_only_backend() {
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap())
+            .http2_only(true)
+            .serve(make_service_fn(|_| async {
+                Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+                    Ok::<_, Infallible>(Response::new(Body::from(format!("{:?}", req.version()))))
+                }))
+            }));
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        let metrics = Arc::new(Metrics::default());
+        let client = upstream_client(Duration::from_secs(1), true, metrics.clone());
+
+        let req = Request::get(format!("http://{}/", addr)).body(Body::empty()).unwrap();
+        let response = send(&client, req, &metrics).await.unwrap();
+
+        assert_eq!(hyper::body::to_bytes(response.into_body()).await.unwrap(), "HTTP/2.0");
+    }
+}
@@ -0,0 +1,650 @@
+#![cfg(unix)]
+
+use std::convert::Infallible;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_util::future::{BoxFuture, FutureExt};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use hyper::HeaderMap;
+use hyper::body::{Bytes, HttpBody};
+use hyper::server::Builder;
+use hyper::server::conn::AddrIncoming;
+use hyper::header::{AUTHORIZATION, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, HOST, HeaderValue, TRANSFER_ENCODING, WWW_AUTHENTICATE};
+use hyper::service::{make_service_fn, service_fn};
+use structopt::StructOpt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::process::{Child, Command};
+use tokio::sync::Mutex;
+use tokio_rustls::TlsConnector;
+use tokio_rustls::webpki::DNSNameRef;
+
+use autoforward::config::Config;
+use autoforward::forwarding::{ForwardError, State};
+use autoforward::kubernetes::ApplicationResource;
+use autoforward::provider::ResourceProvider;
+use autoforward::{proxy, tls};
+
+/// Serves `speil` from a local backend and `spleis` from a port nothing listens on
+struct FakeProvider {
+    backend_port: u16,
+    dead_port: u16,
+}
+
+impl ResourceProvider for FakeProvider {
+    fn applications(&self, _context: &str, _namespace: Option<&str>, _selector: Option<&str>) -> BoxFuture<'static, Result<Vec<ApplicationResource>, ForwardError>> {
+        let applications = ["speil", "spleis"].iter()
+            .map(|name| serde_json::from_value(serde_json::json!({
+                "metadata": { "name": name },
+                "spec": { "ingresses": [format!("https://{}.nais.preprod.local", name)] },
+            })).unwrap())
+            .collect();
+        async move { Ok(applications) }.boxed()
+    }
+
+    fn port_forward(&self, _context: &str, _namespace: &str, service: &str, _service_port: &str, _local_port: Option<u16>) -> io::Result<Child> {
+        let port = if service == "speil" { self.backend_port } else { self.dead_port };
+        Command::new("sh")
+            .args(["-c", &format!("echo 'Forwarding from 127.0.0.1:{} -> 80'; exec sleep 30", port)])
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+    }
+}
+
+fn testdata(name: &str) -> std::path::PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata").join(name)
+}
+
+/// A response body of a single message followed by the `grpc-status` trailer, like gRPC servers answer
+struct GrpcBody {
+    message: Option<Bytes>,
+    trailers: Option<HeaderMap>,
+}
+
+impl HttpBody for GrpcBody {
+    type Data = Bytes;
+    type Error = Infallible;
+
+    fn poll_data(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, Infallible>>> {
+        Poll::Ready(self.message.take().map(Ok))
+    }
+
+    fn poll_trailers(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<Option<HeaderMap>, Infallible>> {
+        Poll::Ready(Ok(self.trailers.take()))
+    }
+}
+
+/// Starts a backend answering every request with `handler`
+fn serve<H, F, B>(handler: H) -> SocketAddr
+    where H: Fn(Request<Body>) -> F + Clone + Send + Sync + 'static,
+          F: Future<Output = Response<B>> + Send + 'static,
+          B: HttpBody + Send + 'static,
+          B::Data: Send,
+          B::Error: Into<Box<dyn std::error::Error + Send + Sync>> {
+    serve_with(Server::bind(&"127.0.0.1:0".parse().unwrap()), handler)
+}
+
+/// Like `serve`, with a server configured by `builder`
+fn serve_with<H, F, B>(builder: Builder<AddrIncoming>, handler: H) -> SocketAddr
+    where H: Fn(Request<Body>) -> F + Clone + Send + Sync + 'static,
+          F: Future<Output = Response<B>> + Send + 'static,
+          B: HttpBody + Send + 'static,
+          B::Data: Send,
+          B::Error: Into<Box<dyn std::error::Error + Send + Sync>> {
+    let server = builder.serve(make_service_fn(move |_| {
+        let handler = handler.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |req| handler(req).map(Ok::<_, Infallible>))) }
+    }));
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    addr
+}
+
+/// Starts an HTTP/2 only backend echoing gRPC requests
+fn grpc_backend() -> SocketAddr {
+    let builder = Server::bind(&"127.0.0.1:0".parse().unwrap()).http2_only(true);
+    serve_with(builder, |req: Request<Body>| async move {
+        let message = hyper::body::to_bytes(req.into_body()).await.unwrap();
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", HeaderValue::from_static("0"));
+        let body = GrpcBody { message: Some(message), trailers: Some(trailers) };
+        Response::builder().header(CONTENT_TYPE, "application/grpc").body(body).unwrap()
+    })
+}
+
+/// `speil says hello` compressed with gzip
+const GZIPPED: &[u8] = b"\x1f\x8b\x08\x00\x00\x00\x00\x00\x02\x03\x2b\x2e\x48\xcd\xcc\x51\x28\x4e\xac\x2c\x56\xc8\x48\xcd\xc9\xc9\x07\x00\x62\xb6\xf2\xf9\x10\x00\x00\x00";
+
+/// Starts a backend answering with a gzip encoded body, with a Content-Length on `/length` and streamed otherwise
+fn gzip_backend() -> SocketAddr {
+    serve(|req: Request<Body>| async move {
+        let response = Response::builder().header(CONTENT_ENCODING, "gzip");
+        if req.uri().path() == "/length" {
+            response.header(CONTENT_LENGTH, GZIPPED.len()).body(Body::from(GZIPPED)).unwrap()
+        } else {
+            let chunks = GZIPPED.chunks(8).map(|chunk| Ok::<_, Infallible>(chunk.to_vec())).collect::<Vec<_>>();
+            response.body(Body::wrap_stream(futures_util::stream::iter(chunks))).unwrap()
+        }
+    })
+}
+
+fn backend() -> SocketAddr {
+    serve(|req: Request<Body>| async move {
+        Response::new(Body::from(format!("speil says hello to {}", req.uri().path())))
+    })
+}
+
+/// Starts a backend reading the whole request body and answering with its length
+fn body_length_backend() -> SocketAddr {
+    serve(|req: Request<Body>| async move {
+        let body = hyper::body::to_bytes(req.into_body()).await.unwrap_or_default();
+        Response::new(Body::from(format!("received {} bytes", body.len())))
+    })
+}
+
+/// Starts a backend answering 502 to the first `failures` requests and 200 after that, counting the requests
+fn recovering_backend(failures: usize, requests: Arc<AtomicUsize>) -> SocketAddr {
+    serve(move |_| {
+        let status = if requests.fetch_add(1, Ordering::SeqCst) < failures { StatusCode::BAD_GATEWAY } else { StatusCode::OK };
+        async move { Response::builder().status(status).body(Body::empty()).unwrap() }
+    })
+}
+
+async fn unused_port() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    listener.local_addr().unwrap().port()
+}
+
+async fn proxy_state(args: &[&str], backend: SocketAddr) -> (Arc<Config>, Arc<Mutex<State>>, rustls::ServerConfig) {
+    let args = ["autoforward", "--context", "test", "--namespace", "default"].iter().chain(args);
+    let config = Arc::new(Config::from_iter(args));
+    let provider = Arc::new(FakeProvider { backend_port: backend.port(), dead_port: unused_port().await });
+    let state = Arc::new(Mutex::new(State::with_provider(config.clone(), provider).await.unwrap()));
+    let tls_config = tls::server_config(&testdata("server.crt"), &testdata("server.key"), config.tls_min_version, config.alpn, None).unwrap();
+    (config, state, tls_config)
+}
+
+/// Starts the proxy on an ephemeral port, backed by the fake provider
+async fn start_proxy() -> SocketAddr {
+    start_proxy_with(&[], backend()).await
+}
+
+async fn start_proxy_with(args: &[&str], backend: SocketAddr) -> SocketAddr {
+    let (config, state, tls_config) = proxy_state(args, backend).await;
+    let mut tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = tcp.local_addr().unwrap();
+    tokio::spawn(async move {
+        proxy::serve(&mut tcp, tls_config, state, config).await.unwrap();
+    });
+    addr
+}
+
+fn client_config() -> rustls::ClientConfig {
+    let mut client_config = rustls::ClientConfig::new();
+    let ca = rustls::internal::pemfile::certs(&mut io::BufReader::new(std::fs::File::open(testdata("ca.pem")).unwrap())).unwrap();
+    client_config.root_store.add(&ca[0]).unwrap();
+    client_config
+}
+
+/// Sends a request for `speil` over HTTP/1.1, returning the headers and the body exactly as received
+async fn fetch(proxy: SocketAddr, path: &str) -> (HeaderMap, Bytes) {
+    let stream = TlsConnector::from(Arc::new(client_config()))
+        .connect(DNSNameRef::try_from_ascii_str("localhost").unwrap(), TcpStream::connect(proxy).await.unwrap())
+        .await
+        .unwrap();
+    let (mut sender, connection) = hyper::client::conn::handshake(stream).await.unwrap();
+    tokio::spawn(connection);
+
+    let req = Request::get(path).header(HOST, "speil.nais.preprod.local").body(Body::empty()).unwrap();
+    let response = sender.send_request(req).await.unwrap();
+    let headers = response.headers().clone();
+    (headers, hyper::body::to_bytes(response.into_body()).await.unwrap())
+}
+
+/// Posts the body to `speil`, returning the status and body of the response
+async fn post(proxy: SocketAddr, body: Body) -> (StatusCode, String) {
+    let stream = TlsConnector::from(Arc::new(client_config()))
+        .connect(DNSNameRef::try_from_ascii_str("localhost").unwrap(), TcpStream::connect(proxy).await.unwrap())
+        .await
+        .unwrap();
+    let (mut sender, connection) = hyper::client::conn::handshake(stream).await.unwrap();
+    tokio::spawn(connection);
+
+    let req = Request::post("/upload").header(HOST, "speil.nais.preprod.local").body(body).unwrap();
+    let response = sender.send_request(req).await.unwrap();
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    (status, String::from_utf8(body.to_vec()).unwrap())
+}
+
+async fn send(proxy: SocketAddr, host: Option<&str>, path: &str) -> (StatusCode, String) {
+    send_over(TcpStream::connect(proxy).await.unwrap(), host, path).await
+}
+
+async fn send_over<S>(stream: S, host: Option<&str>, path: &str) -> (StatusCode, String)
+    where S: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+    let mut client_config = client_config();
+    // Without a Host header the proxy falls back to the server name, so don't send one either
+    client_config.enable_sni = host.is_some();
+    let stream = TlsConnector::from(Arc::new(client_config))
+        .connect(DNSNameRef::try_from_ascii_str("localhost").unwrap(), stream)
+        .await
+        .unwrap();
+    let (mut sender, connection) = hyper::client::conn::handshake(stream).await.unwrap();
+    tokio::spawn(connection);
+
+    let mut req = Request::builder().uri(path);
+    if let Some(host) = host {
+        req = req.header(HOST, host);
+    }
+    let response = sender.send_request(req.body(Body::empty()).unwrap()).await.unwrap();
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    (status, String::from_utf8(body.to_vec()).unwrap())
+}
+
+#[tokio::test]
+async fn routes_request_to_backend_by_host() {
+    let proxy = start_proxy().await;
+
+    let (status, body) = send(proxy, Some("speil.nais.preprod.local"), "/api/person").await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, "speil says hello to /api/person");
+}
+
+#[tokio::test]
+async fn unknown_host_is_not_found() {
+    let proxy = start_proxy().await;
+
+    let (status, body) = send(proxy, Some("sykepenger.nais.preprod.local"), "/").await;
+
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body, "No service found for sykepenger.nais.preprod.local");
+}
+
+#[tokio::test]
+async fn latency_is_labeled_by_ingress_host() {
+    let proxy = start_proxy_with(&["--admin"], backend()).await;
+
+    send(proxy, Some("speil.nais.preprod.local:443"), "/").await;
+    send(proxy, Some("sykepenger.nais.preprod.local"), "/").await;
+    let (_, metrics) = send(proxy, Some("localhost"), "/_autoforward/metrics").await;
+
+    assert!(metrics.contains("autoforward_forward_seconds_count{host=\"speil.nais.preprod.local\"} 1\n"));
+    assert!(metrics.contains("autoforward_upstream_response_seconds_count{host=\"speil.nais.preprod.local\"} 1\n"));
+    assert!(metrics.contains("autoforward_forward_seconds_count{host=\"(unknown)\"} 1\n"));
+    assert!(!metrics.contains("sykepenger"));
+}
+
+#[tokio::test]
+async fn missing_host_is_bad_request() {
+    let proxy = start_proxy().await;
+
+    let (status, _) = send(proxy, None, "/").await;
+
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn dead_backend_is_bad_gateway() {
+    let proxy = start_proxy().await;
+
+    let (status, _) = send(proxy, Some("spleis.nais.preprod.local"), "/").await;
+
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+}
+
+#[tokio::test]
+async fn serves_over_unix_socket() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("autoforward.sock");
+    let (config, state, tls_config) = proxy_state(&[], backend()).await;
+    let mut listener = UnixListener::bind(&path).unwrap();
+    tokio::spawn(async move {
+        proxy::serve(&mut listener, tls_config, state, config).await.unwrap();
+    });
+
+    let stream = UnixStream::connect(&path).await.unwrap();
+    let (status, body) = send_over(stream, Some("speil.nais.preprod.local"), "/api/person").await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, "speil says hello to /api/person");
+}
+
+#[tokio::test]
+async fn grpc_trailers_survive_the_round_trip() {
+    let proxy = start_proxy_with(&["--upstream-http2"], grpc_backend()).await;
+    let mut client_config = client_config();
+    client_config.set_protocols(&[b"h2".to_vec()]);
+    let stream = TlsConnector::from(Arc::new(client_config))
+        .connect(DNSNameRef::try_from_ascii_str("localhost").unwrap(), TcpStream::connect(proxy).await.unwrap())
+        .await
+        .unwrap();
+    let (mut sender, connection) = hyper::client::conn::Builder::new()
+        .http2_only(true)
+        .handshake(stream)
+        .await
+        .unwrap();
+    tokio::spawn(connection);
+
+    let req = Request::post("https://speil.nais.preprod.local/speil.Echo/Echo")
+        .header(CONTENT_TYPE, "application/grpc")
+        .header("te", "trailers")
+        .body(Body::from("\0\0\0\0\x05hello"))
+        .unwrap();
+    let mut response = sender.send_request(req).await.unwrap();
+    let mut body = vec![];
+    while let Some(chunk) = response.body_mut().data().await {
+        body.extend_from_slice(&chunk.unwrap());
+    }
+    let trailers = response.body_mut().trailers().await.unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(body, b"\0\0\0\0\x05hello");
+    assert_eq!(trailers.unwrap().get("grpc-status").unwrap(), "0");
+}
+
+#[tokio::test]
+async fn passes_compressed_body_through_untouched() {
+    let proxy = start_proxy_with(&[], gzip_backend()).await;
+
+    let (headers, body) = fetch(proxy, "/length").await;
+
+    assert_eq!(&body[..], GZIPPED);
+    assert_eq!(headers.get_all(CONTENT_ENCODING).iter().collect::<Vec<_>>(), vec!["gzip"]);
+    assert_eq!(headers.get_all(CONTENT_LENGTH).iter().collect::<Vec<_>>(), vec![GZIPPED.len().to_string().as_str()]);
+}
+
+#[tokio::test]
+async fn does_not_add_content_length_to_streamed_body() {
+    let proxy = start_proxy_with(&[], gzip_backend()).await;
+
+    let (headers, body) = fetch(proxy, "/stream").await;
+
+    assert_eq!(&body[..], GZIPPED);
+    assert_eq!(headers.get_all(CONTENT_ENCODING).iter().collect::<Vec<_>>(), vec!["gzip"]);
+    assert!(headers.get(CONTENT_LENGTH).is_none());
+    assert_eq!(headers.get(TRANSFER_ENCODING).unwrap(), "chunked");
+}
+
+#[tokio::test]
+async fn access_log_counts_bytes_of_streamed_body() {
+    let dir = tempfile::tempdir().unwrap();
+    let log = dir.path().join("access.log");
+    let proxy = start_proxy_with(&["--access-log", log.to_str().unwrap()], gzip_backend()).await;
+
+    fetch(proxy, "/stream").await;
+    // The entry is written once the body is sent, which may be just after the client has it
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+
+    let entry = std::fs::read_to_string(&log).unwrap();
+    assert!(entry.contains(&format!("\"GET /stream HTTP/1.1\" 200 {} ", GZIPPED.len())), "{}", entry);
+}
+
+#[tokio::test]
+async fn access_log_on_stdout_has_only_log_lines() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    // A kubectl stand-in finding no applications, speil is served by a static route
+    let kubectl = dir.path().join("kubectl");
+    std::fs::write(&kubectl, "#!/bin/sh\necho '{\"items\": []}'\n").unwrap();
+    std::fs::set_permissions(&kubectl, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let socket = dir.path().join("autoforward.sock");
+    let backend = backend();
+    let mut autoforward = Command::new(env!("CARGO_BIN_EXE_autoforward"))
+        .args(["--access-log", "-", "--no-hosts", "--no-preflight", "--context", "test", "--namespace", "default"])
+        .arg("--cert").arg(testdata("server.crt"))
+        .arg("--key").arg(testdata("server.key"))
+        .arg("--unix-socket").arg(&socket)
+        .arg("--static-route").arg(format!("speil.nais.preprod.local=127.0.0.1:{}", backend.port()))
+        .env("PATH", format!("{}:{}", dir.path().display(), std::env::var("PATH").unwrap_or_default()))
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .unwrap();
+    for _ in 0..100 {
+        if socket.exists() {
+            break;
+        }
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+    }
+
+    let (status, _) = send_over(UnixStream::connect(&socket).await.unwrap(), Some("speil.nais.preprod.local"), "/api/person").await;
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+    autoforward.kill().unwrap();
+    let mut stdout = String::new();
+    autoforward.stdout.take().unwrap().read_to_string(&mut stdout).await.unwrap();
+
+    assert_eq!(status, StatusCode::OK);
+    let lines = stdout.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 1, "{}", stdout);
+    assert!(lines[0].contains("\"GET /api/person HTTP/1.1\" 200 "), "{}", stdout);
+}
+
+#[tokio::test]
+async fn routes_by_loopback_address_without_host() {
+    let (config, state, tls_config) = proxy_state(&["--loopback-range", "127.1.0.0/16"], backend()).await;
+    let (address, _) = state.lock().await.host_addresses().into_iter()
+        .find(|(_, host)| host == "speil.nais.preprod.local")
+        .unwrap();
+    let mut tcp = TcpListener::bind((address, 0)).await.unwrap();
+    let proxy = tcp.local_addr().unwrap();
+    tokio::spawn(async move {
+        proxy::serve(&mut tcp, tls_config, state, config).await.unwrap();
+    });
+
+    let (status, body) = send(proxy, None, "/api/person").await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, "speil says hello to /api/person");
+}
+
+#[tokio::test]
+async fn rejects_declared_body_over_max_body_size() {
+    let proxy = start_proxy_with(&["--max-body-size", "16"], body_length_backend()).await;
+
+    assert_eq!(post(proxy, Body::from("speil says hello")).await, (StatusCode::OK, "received 16 bytes".to_owned()));
+    let (status, body) = post(proxy, Body::from("speil says hello!")).await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(body, "The request body is larger than the limit of 16 bytes.");
+}
+
+#[tokio::test]
+async fn cuts_off_chunked_body_over_max_body_size() {
+    let proxy = start_proxy_with(&["--max-body-size", "16"], body_length_backend()).await;
+    let chunks = |count| Body::wrap_stream(futures_util::stream::iter(
+        (0..count).map(|_| Ok::<_, Infallible>(b"speil".to_vec())).collect::<Vec<_>>()));
+
+    assert_eq!(post(proxy, chunks(3)).await, (StatusCode::OK, "received 15 bytes".to_owned()));
+    let (status, _) = post(proxy, chunks(4)).await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+}
+
+#[tokio::test]
+async fn closes_connection_of_client_stalling_after_handshake() {
+    let proxy = start_proxy_with(&["--idle-timeout", "1"], backend()).await;
+    let mut stream = TlsConnector::from(Arc::new(client_config()))
+        .connect(DNSNameRef::try_from_ascii_str("localhost").unwrap(), TcpStream::connect(proxy).await.unwrap())
+        .await
+        .unwrap();
+
+    let started = std::time::Instant::now();
+    let read = tokio::time::timeout(std::time::Duration::from_secs(5), stream.read(&mut [0; 16])).await;
+
+    assert!(matches!(read, Ok(Ok(0)) | Ok(Err(_))), "connection stayed open: {:?}", read);
+    assert!(started.elapsed() >= std::time::Duration::from_millis(900));
+    let (status, _) = send(proxy, Some("speil.nais.preprod.local"), "/").await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn idle_timeout_waits_for_slow_backend() {
+    let backend = serve(|_| async {
+        tokio::time::delay_for(std::time::Duration::from_millis(1500)).await;
+        Response::new(Body::from("slow speil"))
+    });
+    let proxy = start_proxy_with(&["--idle-timeout", "1"], backend).await;
+
+    let (status, body) = send(proxy, Some("speil.nais.preprod.local"), "/").await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, "slow speil");
+}
+
+/// Sends an HTTP/2 request for `uri` over a connection opened for localhost, like a client coalescing connections
+async fn send_coalesced(proxy: SocketAddr, uri: &str) -> StatusCode {
+    let mut client_config = client_config();
+    client_config.set_protocols(&[b"h2".to_vec()]);
+    let stream = TlsConnector::from(Arc::new(client_config))
+        .connect(DNSNameRef::try_from_ascii_str("localhost").unwrap(), TcpStream::connect(proxy).await.unwrap())
+        .await
+        .unwrap();
+    let (mut sender, connection) = hyper::client::conn::Builder::new()
+        .http2_only(true)
+        .handshake(stream)
+        .await
+        .unwrap();
+    tokio::spawn(connection);
+
+    sender.send_request(Request::get(uri).body(Body::empty()).unwrap()).await.unwrap().status()
+}
+
+#[tokio::test]
+async fn strict_sni_rejects_coalesced_request_for_another_host() {
+    let strict = start_proxy_with(&["--strict-sni"], backend()).await;
+    let lenient = start_proxy().await;
+
+    assert_eq!(send_coalesced(strict, "https://speil.nais.preprod.local/").await, StatusCode::MISDIRECTED_REQUEST);
+    assert_eq!(send_coalesced(strict, "https://localhost/").await, StatusCode::NOT_FOUND);
+    assert_eq!(send_coalesced(lenient, "https://speil.nais.preprod.local/").await, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn circuit_breaker_stops_requests_to_failing_backend_until_it_recovers() {
+    let requests = Arc::new(AtomicUsize::new(0));
+    let backend = recovering_backend(2, requests.clone());
+    let proxy = start_proxy_with(&["--circuit-breaker-failures", "2", "--circuit-breaker-cooldown", "1"], backend).await;
+
+    assert_eq!(send(proxy, Some("speil.nais.preprod.local"), "/").await.0, StatusCode::BAD_GATEWAY);
+    assert_eq!(send(proxy, Some("speil.nais.preprod.local"), "/").await.0, StatusCode::BAD_GATEWAY);
+    assert_eq!(send(proxy, Some("speil.nais.preprod.local"), "/").await.0, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+    tokio::time::delay_for(Duration::from_millis(1100)).await;
+    assert_eq!(send(proxy, Some("speil.nais.preprod.local"), "/").await.0, StatusCode::OK);
+    assert_eq!(send(proxy, Some("speil.nais.preprod.local"), "/").await.0, StatusCode::OK);
+    assert_eq!(requests.load(Ordering::SeqCst), 4);
+}
+
+/// Sends an HTTP/1.0 request without keep-alive and reads the response until the proxy closes the connection
+async fn send_http10(proxy: SocketAddr, host: Option<&str>, path: &str) -> String {
+    let mut request = format!("GET {} HTTP/1.0\r\n", path);
+    if let Some(host) = host {
+        request.push_str(&format!("Host: {}\r\n", host));
+    }
+    request.push_str("\r\n");
+    send_raw(proxy, host.is_some(), &request).await
+}
+
+/// Writes the request as is, returning the whole response once the proxy closes the connection
+async fn send_raw(proxy: SocketAddr, sni: bool, request: &str) -> String {
+    let mut client_config = client_config();
+    client_config.enable_sni = sni;
+    let mut stream = TlsConnector::from(Arc::new(client_config))
+        .connect(DNSNameRef::try_from_ascii_str("localhost").unwrap(), TcpStream::connect(proxy).await.unwrap())
+        .await
+        .unwrap();
+    stream.write_all(request.as_bytes()).await.unwrap();
+
+    let mut response = vec![];
+    tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response)).await
+        .expect("the proxy kept the connection open")
+        .unwrap();
+    String::from_utf8(response).unwrap()
+}
+
+#[tokio::test]
+async fn forwards_http10_request_and_closes_the_connection() {
+    let proxy = start_proxy().await;
+
+    let response = send_http10(proxy, Some("speil.nais.preprod.local"), "/api/person").await;
+
+    assert!(response.starts_with("HTTP/1.0 200 OK\r\n"), "{}", response);
+    assert!(response.ends_with("\r\n\r\nspeil says hello to /api/person"), "{}", response);
+}
+
+#[tokio::test]
+async fn http10_request_without_host_is_bad_request() {
+    let proxy = start_proxy().await;
+
+    let response = send_http10(proxy, None, "/").await;
+
+    assert!(response.starts_with("HTTP/1.0 400 Bad Request\r\n"), "{}", response);
+}
+
+#[tokio::test]
+async fn request_without_path_is_bad_request() {
+    let proxy = start_proxy().await;
+
+    let response = send_raw(proxy, true, "OPTIONS * HTTP/1.1\r\nHost: speil.nais.preprod.local\r\nConnection: close\r\n\r\n").await;
+
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", response);
+    assert!(response.ends_with("The request URI * can't be forwarded, it has no path."), "{}", response);
+}
+
+#[tokio::test]
+async fn invalid_upstream_prefix_is_bad_gateway() {
+    let proxy = start_proxy_with(&["--upstream-prefix", "/api\u{7}"], backend()).await;
+
+    let (status, body) = send(proxy, Some("speil.nais.preprod.local"), "/person").await;
+
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+    assert!(body.starts_with("The request URI /person can't be forwarded to "), "{}", body);
+}
+
+/// Sends a request for `speil` with the Authorization header, returning the status and headers of the response
+async fn send_authorized(proxy: SocketAddr, authorization: Option<&str>) -> (StatusCode, HeaderMap) {
+    let stream = TlsConnector::from(Arc::new(client_config()))
+        .connect(DNSNameRef::try_from_ascii_str("localhost").unwrap(), TcpStream::connect(proxy).await.unwrap())
+        .await
+        .unwrap();
+    let (mut sender, connection) = hyper::client::conn::handshake(stream).await.unwrap();
+    tokio::spawn(connection);
+
+    let mut req = Request::get("/").header(HOST, "speil.nais.preprod.local");
+    if let Some(authorization) = authorization {
+        req = req.header(AUTHORIZATION, authorization);
+    }
+    let response = sender.send_request(req.body(Body::empty()).unwrap()).await.unwrap();
+    (response.status(), response.headers().clone())
+}
+
+#[tokio::test]
+async fn basic_auth_requires_the_configured_credentials() {
+    let proxy = start_proxy_with(&["--basic-auth", "speil:hemmelig"], backend()).await;
+
+    let (status, headers) = send_authorized(proxy, None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert!(headers[WWW_AUTHENTICATE].to_str().unwrap().starts_with("Basic "));
+
+    let wrong = format!("Basic {}", base64::encode("speil:gjettet"));
+    let (status, _) = send_authorized(proxy, Some(&wrong)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let correct = format!("Basic {}", base64::encode("speil:hemmelig"));
+    let (status, _) = send_authorized(proxy, Some(&correct)).await;
+    assert_eq!(status, StatusCode::OK);
+}